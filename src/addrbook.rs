@@ -0,0 +1,219 @@
+//! address book of the peers a node heard of
+//!
+//! addresses are gossiped through Addr messages and kept on disk,
+//! so a restarted node finds the network again without its seed peers.
+
+use super::*;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// a node failing this many dials in a row is forgotten
+pub const MAX_FAILURES: u32 = 3;
+/// addresses kept at most, see `AddrBook::add`
+pub const MAX_BOOK_SIZE: usize = 2048;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct PeerEntry {
+    //unix time in milliseconds of the last handshake, 0 if never seen
+    last_seen: u128,
+    //dials failed since last_seen
+    failures: u32,
+}
+
+/// AddrBook maps listening addresses of peers to what we know about them
+#[derive(Debug, Clone)]
+pub struct AddrBook {
+    db: sled::Db,
+    //addresses kept at most
+    capacity: usize,
+}
+
+impl AddrBook {
+    pub fn open(path: &Path) -> Result<AddrBook> {
+        Ok(AddrBook {
+            db: open_db(path)?,
+            capacity: MAX_BOOK_SIZE,
+        })
+    }
+
+    /// adds addr, returns true if it was not known yet.
+    ///
+    /// a full book forgets the node that failed most, then the one seen longest ago,
+    /// addr is not added if every node in the book was seen and never failed since
+    pub fn add(&self, addr: &str) -> Result<bool> {
+        if self.contains(addr)? {
+            return Ok(false);
+        }
+        if self.db.len() >= self.capacity {
+            let worst = self
+                .entries()?
+                .into_iter()
+                .max_by_key(|(_, entry)| (entry.failures, std::cmp::Reverse(entry.last_seen)));
+            match worst {
+                Some((worst, entry)) if entry.failures > 0 || entry.last_seen == 0 => {
+                    debug!("forget node {} to make room for {}", worst, addr);
+                    self.db.remove(worst)?;
+                }
+                _ => return Ok(false),
+            }
+        }
+        self.put(addr, &PeerEntry::default())?;
+        Ok(true)
+    }
+
+    pub fn contains(&self, addr: &str) -> Result<bool> {
+        Ok(self.db.contains_key(addr)?)
+    }
+
+    /// records a successful handshake with the node listening on addr
+    pub fn mark_seen(&self, addr: &str) -> Result<()> {
+        let entry = PeerEntry {
+            last_seen: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis(),
+            failures: 0,
+        };
+        self.put(addr, &entry)
+    }
+
+    /// records a failed dial, the node is forgotten after MAX_FAILURES in a row
+    pub fn mark_failed(&self, addr: &str) -> Result<()> {
+        let mut entry = match self.db.get(addr)? {
+            Some(data) => deserialize::<PeerEntry>(&data)?,
+            None => return Ok(()),
+        };
+        entry.failures += 1;
+        if entry.failures >= MAX_FAILURES {
+            info!("forget node {}", addr);
+            self.db.remove(addr)?;
+            self.db.flush()?;
+            return Ok(());
+        }
+        self.put(addr, &entry)
+    }
+
+    /// known addresses, the most recently seen first
+    pub fn addresses(&self) -> Result<Vec<String>> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_seen));
+        Ok(entries.into_iter().map(|(addr, _)| addr).collect())
+    }
+
+    fn entries(&self) -> Result<Vec<(String, PeerEntry)>> {
+        let mut entries = Vec::new();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            entries.push((String::from_utf8(k.to_vec())?, deserialize::<PeerEntry>(&v)?));
+        }
+        Ok(entries)
+    }
+
+    fn put(&self, addr: &str, entry: &PeerEntry) -> Result<()> {
+        self.db.insert(addr, serialize(entry)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// is_routable() tells whether addr can be reached across the internet,
+/// loopback, private, link-local and other special purpose addresses can not
+pub fn is_routable(addr: &SocketAddr) -> bool {
+    if addr.port() == 0 {
+        return false;
+    }
+    match addr.ip() {
+        IpAddr::V4(ip) => !(ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_private()
+            || ip.is_link_local()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_documentation()),
+        IpAddr::V6(ip) => !(ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_multicast()
+            || ip.is_unique_local()
+            || ip.is_unicast_link_local()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_addrbook_persists() {
+        let path = std::env::temp_dir().join("pok_test_addrbook");
+        std::fs::remove_dir_all(&path).ok();
+
+        let book = AddrBook::open(&path).unwrap();
+        assert!(book.add("localhost:3001").unwrap());
+        assert!(book.add("localhost:3002").unwrap());
+        assert!(!book.add("localhost:3001").unwrap());
+        book.mark_seen("localhost:3002").unwrap();
+        drop(book);
+
+        let book = AddrBook::open(&path).unwrap();
+        assert_eq!(book.addresses().unwrap(), vec!["localhost:3002", "localhost:3001"]);
+
+        //a successful handshake clears earlier failures
+        book.mark_failed("localhost:3002").unwrap();
+        book.mark_seen("localhost:3002").unwrap();
+        for _ in 0..MAX_FAILURES - 1 {
+            book.mark_failed("localhost:3002").unwrap();
+        }
+        assert!(book.contains("localhost:3002").unwrap());
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed("localhost:3001").unwrap();
+        }
+        assert_eq!(book.addresses().unwrap(), vec!["localhost:3002"]);
+
+        drop(book);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_addrbook_capacity() {
+        let path = std::env::temp_dir().join("pok_test_addrbook_capacity");
+        std::fs::remove_dir_all(&path).ok();
+        let mut book = AddrBook::open(&path).unwrap();
+        book.capacity = 3;
+
+        book.add("1.1.1.1:3000").unwrap();
+        book.add("2.2.2.2:3000").unwrap();
+        book.add("3.3.3.3:3000").unwrap();
+        book.mark_seen("1.1.1.1:3000").unwrap();
+        book.mark_seen("2.2.2.2:3000").unwrap();
+        book.mark_seen("3.3.3.3:3000").unwrap();
+        //a book of good nodes stays as it is
+        assert!(!book.add("4.4.4.4:3000").unwrap());
+        assert_eq!(book.addresses().unwrap().len(), 3);
+
+        //a failed node makes room first
+        book.mark_failed("2.2.2.2:3000").unwrap();
+        assert!(book.add("4.4.4.4:3000").unwrap());
+        assert!(!book.contains("2.2.2.2:3000").unwrap());
+        //then a node never seen
+        assert!(book.add("5.5.5.5:3000").unwrap());
+        assert!(!book.contains("4.4.4.4:3000").unwrap());
+        assert_eq!(book.addresses().unwrap().len(), 3);
+
+        drop(book);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_is_routable() {
+        let routable = |addr: &str| is_routable(&addr.parse().unwrap());
+        assert!(routable("1.2.3.4:3000"));
+        assert!(routable("[2001:4860::8888]:3000"));
+        assert!(!routable("1.2.3.4:0"));
+        assert!(!routable("127.0.0.1:3000"));
+        assert!(!routable("10.0.0.7:3000"));
+        assert!(!routable("192.168.1.1:3000"));
+        assert!(!routable("0.0.0.0:3000"));
+        assert!(!routable("[::1]:3000"));
+        assert!(!routable("[fd00::1]:3000"));
+    }
+}
@@ -7,12 +7,34 @@ use ::crypto::digest::Digest;
 use ::crypto::ed25519;
 use ::crypto::ripemd160::Ripemd160;
 use ::crypto::sha2::Sha256;
-use failure::format_err;
+use crate::PokError;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use sled;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use rand::RngCore;
+use crate::config::NodeConfig;
+use crate::hd::{address_path, ExtendedKey};
+use crate::keystore::{KdfParams, Keystore, Sealed, SecretKey};
+use bip39::Mnemonic;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use ::crypto::util::secure_memset;
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+//agent database records besides the addresses
+const AGENT_KEY: &str = "MYAGENT";
+const KEYSTORE_KEY: &str = "KEYSTORE";
+const MNEMONIC_KEY: &str = "MNEMONIC";
+const NEXT_INDEX_KEY: &str = "NEXTINDEX";
+//watch-only entries are stored under their address behind this prefix
+const WATCH_PREFIX: &str = "WATCH:";
+/// words of the mnemonic of a new agent
+pub const MNEMONIC_WORDS: usize = 24;
+/// a restore stops after this many unused addresses in a row
+pub const GAP_LIMIT: u32 = 20;
+/// how long an agent stays unlocked when no timeout is given
+pub const DEFAULT_UNLOCK_TIMEOUT: Duration = Duration::from_secs(300);
 
 /**
  * 
@@ -25,32 +47,61 @@ pub struct Keypair {
     pub public_key: Vec<u8>,
 }
 
+impl Default for Keypair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Keypair {
     /// NewWallet creates and returns a Wallet
-    fn new() -> Self {
+    pub fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
         let mut rand = rand::rngs::OsRng;
         rand.fill_bytes(&mut key);
-        let (secret_key, public_key) = ed25519::keypair(&key);
-        let secret_key = secret_key.to_vec();
-        let public_key = public_key.to_vec();
+        let keypair = Keypair::from_key(&key);
+        secure_memset(&mut key, 0);
+        keypair
+    }
+
+    /// derives keypair number index from the seed of a mnemonic
+    pub fn derive(seed: &[u8], index: u32) -> Self {
+        Keypair::from_key(&ExtendedKey::derive(seed, &address_path(index)).secret_key)
+    }
+
+    fn from_key(key: &[u8; 32]) -> Self {
+        let (secret_key, public_key) = ed25519::keypair(key);
         Keypair {
-            secret_key,
-            public_key,
+            secret_key: secret_key.to_vec(),
+            public_key: public_key.to_vec(),
         }
     }
 
     /// GetAddress derive address from public key
     pub fn address(&self) -> String {
-        let mut pub_hash: Vec<u8> = self.public_key.clone();
-        hash_public_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        address.encode().unwrap()
+        public_key_to_address(&self.public_key)
+    }
+}
+
+/// derives the address of a public key
+pub fn public_key_to_address(public_key: &[u8]) -> String {
+    let mut pub_hash: Vec<u8> = public_key.to_vec();
+    hash_public_key(&mut pub_hash);
+    let address = Address {
+        body: pub_hash,
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    //base58 encoding can not fail
+    address.encode().unwrap_or_default()
+}
+
+/// decodes an address into the public key hash its outputs are locked with
+pub fn address_to_pub_key_hash(address: &str) -> Result<Vec<u8>> {
+    match Address::decode(address) {
+        Ok(address) => Ok(address.body),
+        Err(_) => Err(PokError::InvalidAddress(address.to_owned())),
     }
 }
 
@@ -73,7 +124,7 @@ pub fn hash_public_key(pub_key: &mut Vec<u8>) {
  * 
  */
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Build {
     pub name: String,
     pub class: String,
@@ -119,17 +170,17 @@ impl Build {
         }
     }
     pub fn introduce(&self) {
-        println!("introduce {}:\nthe {} with a(n) {}.", self.name,self.class,self.weapon);
+        debug!("introduce {}: the {} with a(n) {}.", self.name,self.class,self.weapon);
     }
 
     pub fn report_health(&self) {
         match self.health {
-            health if health >= 80 => println!("{} is pretty healthy.",self.name),
-            health if health < 80 && health >= 60 => println!("{} is slightly injured.",self.name),
-            health if health < 60 && health >= 40 => println!("{} is wounded.",self.name),
-            health if health < 40 && health >= 20 => println!("{} is badly hurt.",self.name),
-            health if health < 20 => println!("{} is nearly died.",self.name),
-            _ => println!("{}'s health is uncertain.",self.name),
+            health if health >= 80 => debug!("{} is pretty healthy.",self.name),
+            health if (60..80).contains(&health) => debug!("{} is slightly injured.",self.name),
+            health if (40..60).contains(&health) => debug!("{} is wounded.",self.name),
+            health if (20..40).contains(&health) => debug!("{} is badly hurt.",self.name),
+            health if health < 20 => debug!("{} is nearly died.",self.name),
+            _ => debug!("{}'s health is uncertain.",self.name),
         }
     }
 
@@ -149,21 +200,21 @@ impl Build {
     }
 
     pub fn produce_damage(&mut self, randomness: i32) -> i32 {
-        self.action = self.action + randomness;
+        self.action += randomness;
         if self.action < 0 {
             return 0;
         }
         
         let damage = self.attack + (randomness as f32 * 0.8) as i32;
         //println!("{} deals {} damage to opponent agent.", self.name, damage);
-        println!("deals {} damage to opponent agent.", damage);
+        debug!("deals {} damage to opponent agent.", damage);
         damage
     }
 
     pub fn take_damage(&mut self, damage: i32) {
         //println!("{} took {} damage from opponent agent. Ooouch!", self.name, damage);
-        println!("took {} damage from opponent agent. Ooouch!", damage);
-        self.health = self.health - damage;
+        debug!("took {} damage from opponent agent. Ooouch!", damage);
+        self.health -= damage;
     }
 
 /*
@@ -179,23 +230,76 @@ impl Build {
     }
 */
     fn die(&self) {
-        println!("{} is died, game over.\n", self.name);
+        debug!("{} is died, game over.", self.name);
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Agent {
-    //HashMap<address, keypair>
+    //HashMap<address, keypair>, the secret keys of an encrypted agent are empty while it is locked
     addresses : HashMap<String, Keypair>,
     agent_id : String,
     build : Build,
+    //database the agent is stored in
+    #[serde(skip)]
+    path : PathBuf,
+    //None for an agent stored before keystores, its secret keys are in plaintext
+    #[serde(skip)]
+    keystore : Option<Keystore>,
+    //HashMap<address, secret key sealed under the passphrase>
+    #[serde(skip)]
+    sealed : HashMap<String, Sealed>,
+    //the key of an unlocked agent only ever lives in the memory of the process holding it
+    #[serde(skip)]
+    session : Option<Session>,
+    //cost of a keystore written by this agent
+    #[serde(skip)]
+    kdf_params : KdfParams,
+    //mnemonic sealed under the passphrase, None for an agent created before mnemonics
+    #[serde(skip)]
+    mnemonic : Option<Sealed>,
+    //seed of the mnemonic, empty while the agent is locked
+    #[serde(skip)]
+    seed : Vec<u8>,
+    //index of the next address derived from the seed
+    #[serde(skip)]
+    next_index : u32,
+    //HashMap<address, public key> of the watch-only entries, the agent holds no secret key for them.
+    //the public key is empty if only the address was given
+    #[serde(skip)]
+    watched : HashMap<String, Vec<u8>>,
+}
+
+/// key of an unlocked agent and when it locks again
+#[derive(Debug, Clone)]
+struct Session {
+    key: SecretKey,
+    expires: SystemTime,
+}
+
+/// keypair of an encrypted agent as written to its database
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    public_key: Vec<u8>,
+    secret_key: Sealed,
 }
 
 impl Agent {
-    /// CreateAgent creates Agent and fills it from a file if it exists
-    pub fn new(build:Build, node_id:&str) -> Result<Agent> {
-        let agent_path = "data_".to_owned() + node_id + "/agent";
+    /// CreateAgent creates Agent from a new mnemonic with a keystore encrypted by passphrase,
+    /// replacing the agent stored at the agent path. the new agent is unlocked for DEFAULT_UNLOCK_TIMEOUT
+    pub fn new(build:Build, passphrase:&str, config:&NodeConfig) -> Result<Agent> {
+        let mut entropy = [0; MNEMONIC_WORDS / 3 * 4];
+        rand::rngs::OsRng.fill_bytes(&mut entropy);
+        let mnemonic = Mnemonic::from_entropy(&entropy)
+            .map_err(|e| PokError::InvalidArgument(format!("invalid mnemonic: {}", e)))?;
+        secure_memset(&mut entropy, 0);
+        Agent::restore(build, &mnemonic.to_string(), passphrase, config)
+    }
 
+    /// creates Agent holding the addresses of mnemonic, unlocked like a new one,
+    /// its addresses are derived again by `generate_address` or `recover_addresses`.
+    /// the agent stored before is only replaced once mnemonic turned out valid
+    pub fn restore(build:Build, mnemonic:&str, passphrase:&str, config:&NodeConfig) -> Result<Agent> {
         //agent_id is a 256-bit string
         const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                             abcdefghijklmnopqrstuvwxyz\
@@ -209,39 +313,104 @@ impl Agent {
                     CHARSET[idx] as char
                 })
             .collect();
+        Agent::create(agent_id, build, Some(mnemonic), passphrase, config)
+    }
+
+    //writes a new agent without addresses in place of the stored one, unlocked like a new one
+    fn create(agent_id:String, build:Build, mnemonic:Option<&str>, passphrase:&str, config:&NodeConfig) -> Result<Agent> {
+        let seed = match mnemonic {
+            Some(mnemonic) => Mnemonic::parse_normalized(mnemonic)
+                .map_err(|e| PokError::InvalidArgument(format!("invalid mnemonic: {}", e)))?
+                .to_seed_normalized("")
+                .to_vec(),
+            None => Vec::new(),
+        };
+        let kdf_params = KdfParams { log_n: config.keystore_log_n, ..KdfParams::default() };
+        let (keystore, key) = Keystore::new(passphrase, kdf_params);
+        let sealed_mnemonic = mnemonic.map(|mnemonic| key.seal(mnemonic.as_bytes(), MNEMONIC_KEY.as_bytes()));
         let agent = Agent {
             addresses : HashMap::<String, Keypair>::new(),
-            agent_id : agent_id,
-            build : build
+            agent_id,
+            build,
+            path : config.agent_path(),
+            keystore : Some(keystore),
+            sealed : HashMap::new(),
+            session : Some(Session { key, expires: SystemTime::now() + DEFAULT_UNLOCK_TIMEOUT }),
+            kdf_params,
+            mnemonic : sealed_mnemonic,
+            seed,
+            next_index : 0,
+            watched : HashMap::new(),
         };
-        let db = sled::open(agent_path)?;
+        //the mnemonic is valid and the keystore is ready, nothing is left to fail on the input
+        match std::fs::remove_dir_all(&agent.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let db = open_db(&agent.path)?;
 
         let agent_data = serialize(&agent)?;
-        db.insert("MYAGENT", agent_data)?;
+        db.insert(AGENT_KEY, agent_data)?;
+        db.insert(KEYSTORE_KEY, serialize(&agent.keystore)?)?;
+        db.insert(MNEMONIC_KEY, serialize(&agent.mnemonic)?)?;
+        db.insert(NEXT_INDEX_KEY, serialize(&agent.next_index)?)?;
         drop(db);
         Ok(agent)
     }
 
-    pub fn load() -> Result<Agent> {
-        let node_id =  std::env::var("NODE_ID").unwrap();
-        let agent_path = "data_".to_owned() + &node_id + "/agent";
-        if !Is_agent_exists(&agent_path) {
-            return Err(format_err!("No Existing Agent Found. Create One First."));
+    /// loads the agent, locked if its keys are encrypted
+    pub fn load(config:&NodeConfig) -> Result<Agent> {
+        let agent_path = config.agent_path();
+        if !is_agent_exists(&agent_path) {
+            return Err(PokError::NotFound(String::from("agent")));
         }
 
-        let db = sled::open(agent_path)?;
-        let agent_data = db.get("MYAGENT")?.unwrap();
-        let mut agent: Agent = deserialize(&agent_data.to_vec())?;
+        let db = open_db(&agent_path)?;
+        let agent_data = match db.get(AGENT_KEY)? {
+            Some(data) => data,
+            None => return Err(PokError::NotFound(String::from("agent"))),
+        };
+        let mut agent: Agent = deserialize(&agent_data)?;
+        agent.path = agent_path;
+        //earlier versions kept the key of an unlocked agent in this file
+        std::fs::remove_file(config.session_path()).ok();
+        agent.kdf_params = KdfParams { log_n: config.keystore_log_n, ..KdfParams::default() };
+        agent.keystore = match db.get(KEYSTORE_KEY)? {
+            Some(data) => deserialize(&data)?,
+            None => None,
+        };
+        agent.mnemonic = match db.get(MNEMONIC_KEY)? {
+            Some(data) => deserialize(&data)?,
+            None => None,
+        };
+        agent.next_index = match db.get(NEXT_INDEX_KEY)? {
+            Some(data) => deserialize(&data)?,
+            None => 0,
+        };
 
         //load addresses
         for item in db.into_iter() {
             let i = item?;
-            if i.0.to_vec() == b"MYAGENT" {
+            if [AGENT_KEY, KEYSTORE_KEY, MNEMONIC_KEY, NEXT_INDEX_KEY].iter().any(|key| i.0 == key) {
+                continue;
+            }
+            if let Some(address) = i.0.strip_prefix(WATCH_PREFIX.as_bytes()) {
+                agent.watched.insert(String::from_utf8(address.to_vec())?, deserialize(&i.1)?);
                 continue;
             }
             let address = String::from_utf8(i.0.to_vec())?;
-            let keypair = deserialize(&i.1.to_vec())?;
-            agent.addresses.insert(address, keypair);
+            if agent.keystore.is_some() {
+                let stored: StoredKey = deserialize(&i.1)?;
+                let keypair = Keypair {
+                    secret_key: Vec::new(),
+                    public_key: stored.public_key,
+                };
+                agent.sealed.insert(address.clone(), stored.secret_key);
+                agent.addresses.insert(address, keypair);
+            } else {
+                let keypair = deserialize(&i.1)?;
+                agent.addresses.insert(address, keypair);
+            }
         }
         drop(db);
         Ok(agent)
@@ -255,55 +424,562 @@ impl Agent {
         &self.build
     }
 
-    /// generate an address for agent
-    pub fn generate_address(&mut self) -> String {
-        let keypair = Keypair::new();
+    /// true if the secret keys are stored encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.keystore.is_some()
+    }
+
+    /// true if the secret keys can be used
+    pub fn is_unlocked(&self) -> bool {
+        self.session_key().is_ok()
+    }
+
+    /// tells when the unlocked session ends, None for a locked or unencrypted agent
+    pub fn unlocked_until(&self) -> Option<SystemTime> {
+        match &self.session {
+            Some(session) if self.is_unlocked() => Some(session.expires),
+            _ => None,
+        }
+    }
+
+    /// true if the addresses are derived from a mnemonic
+    pub fn has_mnemonic(&self) -> bool {
+        self.mnemonic.is_some()
+    }
+
+    /// the mnemonic the addresses are derived from, the agent has to be unlocked
+    pub fn mnemonic(&self) -> Result<String> {
+        match &self.mnemonic {
+            Some(sealed) => self.open_mnemonic(sealed, self.session_key()?),
+            None => Err(PokError::NotFound(String::from("mnemonic of the agent"))),
+        }
+    }
+
+    /// generate an address for agent, the next one derived from the mnemonic if there is one,
+    /// an encrypted agent has to be unlocked
+    pub fn generate_address(&mut self) -> Result<String> {
+        let keypair = if self.mnemonic.is_some() {
+            let keypair = self.derive_keypair(self.next_index)?;
+            self.next_index += 1;
+            keypair
+        } else {
+            Keypair::new()
+        };
+        self.add_keypair(keypair)
+    }
+
+    /// derives addresses from the mnemonic until GAP_LIMIT of them in a row are unused,
+    /// an address is used if its public key hash is in used.
+    /// every address up to the last used one is added, at least one address in all,
+    /// returns the added addresses
+    pub fn recover_addresses(&mut self, used: &HashSet<Vec<u8>>) -> Result<Vec<String>> {
+        let mut last_used = None;
+        let mut unused = 0;
+        let mut index = self.next_index;
+        while unused < GAP_LIMIT {
+            let mut pub_key_hash = self.derive_keypair(index)?.public_key;
+            hash_public_key(&mut pub_key_hash);
+            if used.contains(&pub_key_hash) {
+                last_used = Some(index);
+                unused = 0;
+            } else {
+                unused += 1;
+            }
+            index += 1;
+        }
+
+        let mut recovered = Vec::new();
+        if let Some(last) = last_used {
+            while self.next_index <= last {
+                recovered.push(self.generate_address()?);
+            }
+        }
+        if self.addresses.is_empty() {
+            recovered.push(self.generate_address()?);
+        }
+        Ok(recovered)
+    }
+
+    //keypair number index of the mnemonic, the agent has to be unlocked
+    fn derive_keypair(&self, index: u32) -> Result<Keypair> {
+        if self.mnemonic.is_none() {
+            return Err(PokError::NotFound(String::from("mnemonic of the agent")));
+        }
+        if self.seed.is_empty() || self.session_key().is_err() {
+            return Err(PokError::Locked);
+        }
+        Ok(Keypair::derive(&self.seed, index))
+    }
+
+    //adds keypair to the agent, sealed under the session key of an encrypted agent
+    fn add_keypair(&mut self, keypair: Keypair) -> Result<String> {
         let address = keypair.address();
+        if self.keystore.is_some() {
+            let sealed = self.session_key()?.seal(&keypair.secret_key, address.as_bytes());
+            self.sealed.insert(address.clone(), sealed);
+        }
+        //a watched address is no longer watch-only once its secret key is held
+        self.watched.remove(&address);
         self.addresses.insert(address.clone(), keypair);
         info!("create address: {}", address);
-        address
+        Ok(address)
     }
 
     /// GetAddresses returns an array of addresses stored in the wallet file
     pub fn get_all_addresses(&self) -> Vec<String> {
         let mut all_addresses = Vec::<String>::new();
-        for (address, _) in &self.addresses {
+        for address in self.addresses.keys() {
             all_addresses.push(address.clone());
         }
         all_addresses
     }
 
-    /// GetWallet returns a Keypair by its address
-    pub fn get_keypair_by_address(&self, address: &str) -> Option<&Keypair> {
-        self.addresses.get(address)
+    /// watches an address, or the address of a public key given in hex, without its secret key.
+    /// the watch-only entry counts in balances and histories but can not sign, returns its address
+    pub fn watch(&mut self, entry: &str) -> Result<String> {
+        let (address, public_key) = match address_to_pub_key_hash(entry) {
+            Ok(_) => (entry.to_owned(), Vec::new()),
+            Err(_) => match HEXLOWER_PERMISSIVE.decode(entry.as_bytes()) {
+                Ok(public_key) if public_key.len() == 32 => (public_key_to_address(&public_key), public_key),
+                _ => return Err(PokError::InvalidAddress(entry.to_owned())),
+            },
+        };
+        if self.addresses.contains_key(&address) {
+            return Err(PokError::InvalidArgument(format!("address {} is held with its secret key", address)));
+        }
+        //a public key given later is kept, it is not lost to the bare address
+        let held = self.watched.entry(address.clone()).or_default();
+        if !public_key.is_empty() {
+            *held = public_key;
+        }
+        Ok(address)
+    }
+
+    /// stops watching address
+    pub fn unwatch(&mut self, address: &str) -> Result<()> {
+        match self.watched.remove(address) {
+            Some(_) => Ok(()),
+            None => Err(PokError::UnknownAddress(address.to_owned())),
+        }
+    }
+
+    /// addresses of the watch-only entries
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watched.keys().cloned().collect()
+    }
+
+    /// true if address is watched without its secret key
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watched.contains_key(address)
+    }
+
+    /// GetWallet returns a Keypair by its address, an encrypted agent has to be unlocked.
+    /// watch-only addresses have none
+    pub fn get_keypair_by_address(&self, address: &str) -> Result<&Keypair> {
+        if self.is_watch_only(address) {
+            return Err(PokError::WatchOnly(address.to_owned()));
+        }
+        let keypair = match self.addresses.get(address) {
+            Some(keypair) => keypair,
+            None => return Err(PokError::UnknownAddress(address.to_owned())),
+        };
+        if self.keystore.is_some() {
+            self.session_key()?;
+        }
+        Ok(keypair)
+    }
+
+    /// unlocks the secret keys for timeout. the key stays in memory, nothing is written to the disk
+    pub fn unlock(&mut self, passphrase: &str, timeout: Duration) -> Result<()> {
+        let key = match &self.keystore {
+            Some(keystore) => keystore.unlock(passphrase)?,
+            None => return Err(PokError::InvalidArgument(String::from("agent is not encrypted"))),
+        };
+        self.open_keys(&key)?;
+        self.session = Some(Session { key, expires: SystemTime::now() + timeout });
+        Ok(())
+    }
+
+    /// forgets the secret keys, the seed and the session key
+    pub fn lock(&mut self) {
+        if self.keystore.is_some() {
+            for keypair in self.addresses.values_mut() {
+                secure_memset(&mut keypair.secret_key, 0);
+                keypair.secret_key.clear();
+            }
+        }
+        secure_memset(&mut self.seed, 0);
+        self.seed.clear();
+        self.session = None;
+    }
+
+    /// locks an agent whose session ran out, returns true if it did.
+    /// expired keys are refused anyway, this wipes them from memory
+    pub fn lock_if_expired(&mut self) -> bool {
+        match &self.session {
+            Some(session) if session.expires <= SystemTime::now() => {
+                self.lock();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// re-encrypts every secret key under new_passphrase and locks the agent,
+    /// old_passphrase is not asked for by an agent stored in plaintext
+    pub fn change_passphrase(&mut self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        let mnemonic = match &self.keystore {
+            Some(keystore) => {
+                let key = keystore.unlock(old_passphrase)?;
+                self.open_keys(&key)?;
+                match &self.mnemonic {
+                    Some(sealed) => Some(self.open_mnemonic(sealed, &key)?),
+                    None => None,
+                }
+            }
+            None => None,
+        };
+        let (keystore, key) = Keystore::new(new_passphrase, self.kdf_params);
+        let mut sealed = HashMap::new();
+        for (address, keypair) in &self.addresses {
+            sealed.insert(address.clone(), key.seal(&keypair.secret_key, address.as_bytes()));
+        }
+        let sealed_mnemonic = mnemonic.map(|mnemonic| key.seal(mnemonic.as_bytes(), MNEMONIC_KEY.as_bytes()));
+
+        //the keystore and the keys sealed under it are replaced at once
+        let db = open_db(&self.path)?;
+        let mut batch = sled::Batch::default();
+        batch.insert(KEYSTORE_KEY, serialize(&Some(&keystore))?);
+        batch.insert(MNEMONIC_KEY, serialize(&sealed_mnemonic)?);
+        for (address, keypair) in &self.addresses {
+            let stored = StoredKey {
+                public_key: keypair.public_key.clone(),
+                secret_key: sealed[address].clone(),
+            };
+            batch.insert(address.as_bytes(), serialize(&stored)?);
+        }
+        db.apply_batch(batch)?;
+        db.flush()?;
+        drop(db);
+
+        self.keystore = Some(keystore);
+        self.sealed = sealed;
+        self.mnemonic = sealed_mnemonic;
+        self.lock();
+        Ok(())
     }
 
     /// save agent and addresses to the disk
     pub fn save(&self) -> Result<()> {
-        let node_id = std::env::var("NODE_ID").unwrap();
-        let agent_path = "data_".to_owned() + &node_id + "/agent";
-        let db = sled::open(agent_path)?;
+        let db = open_db(&self.path)?;
 
         for (address, keypair) in &self.addresses {
-            let data = serialize(keypair)?;
+            let data = match self.sealed.get(address) {
+                Some(sealed) => serialize(&StoredKey {
+                    public_key: keypair.public_key.clone(),
+                    secret_key: sealed.clone(),
+                })?,
+                None => serialize(keypair)?,
+            };
             db.insert(address, data)?;
         }
+        db.insert(NEXT_INDEX_KEY, serialize(&self.next_index)?)?;
+        for item in db.scan_prefix(WATCH_PREFIX) {
+            db.remove(item?.0)?;
+        }
+        for (address, public_key) in &self.watched {
+            db.insert(format!("{}{}", WATCH_PREFIX, address), serialize(public_key)?)?;
+        }
 
         db.flush()?;
         drop(db);
         Ok(())
     }
+
+    //key of the unlocked session
+    fn session_key(&self) -> Result<&SecretKey> {
+        match &self.session {
+            Some(session) if session.expires > SystemTime::now() => Ok(&session.key),
+            _ => Err(PokError::Locked),
+        }
+    }
+
+    //decrypts the secret keys sealed under key
+    fn open_keys(&mut self, key: &SecretKey) -> Result<()> {
+        for (address, keypair) in self.addresses.iter_mut() {
+            let sealed = match self.sealed.get(address) {
+                Some(sealed) => sealed,
+                None => continue,
+            };
+            keypair.secret_key = match key.open(sealed, address.as_bytes()) {
+                Some(secret_key) => secret_key,
+                None => return Err(PokError::Serialization(format!("secret key of {} is corrupted", address))),
+            };
+        }
+        if let Some(sealed) = &self.mnemonic {
+            let mnemonic = self.open_mnemonic(sealed, key)?;
+            self.seed = Mnemonic::parse_normalized(&mnemonic)
+                .map_err(|e| PokError::Serialization(format!("mnemonic of the agent is corrupted: {}", e)))?
+                .to_seed_normalized("")
+                .to_vec();
+        }
+        Ok(())
+    }
+
+    fn open_mnemonic(&self, sealed: &Sealed, key: &SecretKey) -> Result<String> {
+        match key.open(sealed, MNEMONIC_KEY.as_bytes()) {
+            Some(mnemonic) => Ok(String::from_utf8(mnemonic)?),
+            None => Err(PokError::Serialization(String::from("mnemonic of the agent is corrupted"))),
+        }
+    }
+}
+
+//the secrets of an encrypted agent do not outlive it
+impl Drop for Agent {
+    fn drop(&mut self) {
+        self.lock();
+    }
+}
+
+/// version of the backup files written by `Backup::write`
+pub const BACKUP_VERSION: u32 = 1;
+//first bytes of a backup file
+const BACKUP_MAGIC: [u8; 8] = *b"POKAGENT";
+
+/// Backup is what moves an agent between nodes: its id, build, mnemonic and every keypair.
+/// watch-only entries stay behind
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Backup {
+    agent_id: String,
+    build: Build,
+    mnemonic: Option<String>,
+    next_index: u32,
+    keypairs: Vec<Keypair>,
+}
+
+//leading fields of every version of the backup file
+#[derive(Serialize, Deserialize)]
+struct BackupHeader {
+    magic: [u8; 8],
+    version: u32,
+}
+
+/// layout of a backup file of BACKUP_VERSION
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    magic: [u8; 8],
+    version: u32,
+    //Some if the contents are sealed under a passphrase
+    keystore: Option<Keystore>,
+    contents: BackupContents,
+    //sha256 of the serialized Backup
+    checksum: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum BackupContents {
+    Plain(Vec<u8>),
+    Sealed(Sealed),
+}
+
+impl Backup {
+    pub fn agent_id(&self) -> &str {
+        &self.agent_id
+    }
+
+    pub fn build(&self) -> &Build {
+        &self.build
+    }
+
+    /// addresses of the keypairs in the backup
+    pub fn addresses(&self) -> Vec<String> {
+        self.keypairs.iter().map(Keypair::address).collect()
+    }
+
+    /// writes the backup to path, encrypted if passphrase is given
+    pub fn write(&self, path: &Path, passphrase: Option<&str>, config: &NodeConfig) -> Result<()> {
+        let mut contents = serialize(self)?;
+        let checksum = sha256(&contents);
+        let (keystore, contents) = match passphrase {
+            Some(passphrase) => {
+                let params = KdfParams { log_n: config.keystore_log_n, ..KdfParams::default() };
+                let (keystore, key) = Keystore::new(passphrase, params);
+                let sealed = key.seal(&contents, &BACKUP_MAGIC);
+                secure_memset(&mut contents, 0);
+                (Some(keystore), BackupContents::Sealed(sealed))
+            }
+            None => (None, BackupContents::Plain(contents)),
+        };
+        let file = BackupFile {
+            magic: BACKUP_MAGIC,
+            version: BACKUP_VERSION,
+            keystore,
+            contents,
+            checksum,
+        };
+        write_private(path, &serialize(&file)?)
+    }
+
+    /// true if the backup file at path is encrypted and `read` needs its passphrase
+    pub fn is_encrypted(path: &Path) -> Result<bool> {
+        Ok(Backup::read_file(path)?.keystore.is_some())
+    }
+
+    /// reads the backup file at path, checking that it is whole and its keypairs are sound
+    pub fn read(path: &Path, passphrase: Option<&str>) -> Result<Backup> {
+        let file = Backup::read_file(path)?;
+        let mut contents = match (&file.keystore, file.contents) {
+            (None, BackupContents::Plain(contents)) => contents,
+            (Some(keystore), BackupContents::Sealed(sealed)) => {
+                let passphrase = passphrase.ok_or(PokError::WrongPassphrase)?;
+                match keystore.unlock(passphrase)?.open(&sealed, &BACKUP_MAGIC) {
+                    Some(contents) => contents,
+                    None => return Err(PokError::InvalidBackup(String::from("the encrypted contents are damaged"))),
+                }
+            }
+            _ => return Err(PokError::InvalidBackup(String::from("the contents do not match the encryption"))),
+        };
+        let backup = if sha256(&contents) == file.checksum {
+            deserialize::<Backup>(&contents)
+                .map_err(|e| PokError::InvalidBackup(format!("the contents can not be read: {}", e)))
+        } else {
+            Err(PokError::InvalidBackup(String::from("the checksum does not match")))
+        };
+        secure_memset(&mut contents, 0);
+        let backup = backup?;
+
+        for keypair in &backup.keypairs {
+            let sound = keypair.secret_key.len() == 64 && {
+                let (secret_key, public_key) = ed25519::keypair(&keypair.secret_key[..32]);
+                secret_key[..] == keypair.secret_key[..] && public_key[..] == keypair.public_key[..]
+            };
+            if !sound {
+                return Err(PokError::InvalidBackup(format!("the keypair of {} is broken", keypair.address())));
+            }
+        }
+        if let Some(mnemonic) = &backup.mnemonic {
+            Mnemonic::parse_normalized(mnemonic)
+                .map_err(|e| PokError::InvalidBackup(format!("invalid mnemonic: {}", e)))?;
+        }
+        Ok(backup)
+    }
+
+    //reads the file at path as far as its layout, refusing other files and versions
+    fn read_file(path: &Path) -> Result<BackupFile> {
+        let data = std::fs::read(path)?;
+        let header: BackupHeader = deserialize(&data)
+            .map_err(|_| PokError::InvalidBackup(format!("{} is not a backup of an agent", path.display())))?;
+        if header.magic != BACKUP_MAGIC {
+            return Err(PokError::InvalidBackup(format!("{} is not a backup of an agent", path.display())));
+        }
+        if header.version != BACKUP_VERSION {
+            return Err(PokError::InvalidBackup(format!(
+                "version {} of the backup is not supported, version {} is",
+                header.version, BACKUP_VERSION
+            )));
+        }
+        deserialize(&data).map_err(|e| PokError::InvalidBackup(format!("the file is damaged: {}", e)))
+    }
 }
 
-///Returns true if db_path points at an existing entity.
-pub fn Is_agent_exists(agent_path: &str) -> bool {
-    std::path::Path::new(agent_path).exists()
+impl Agent {
+    /// collects the agent into a Backup, an encrypted agent has to be unlocked
+    pub fn backup(&self) -> Result<Backup> {
+        if self.keystore.is_some() {
+            self.session_key()?;
+        }
+        let mnemonic = match &self.mnemonic {
+            Some(_) => Some(self.mnemonic()?),
+            None => None,
+        };
+        let mut keypairs: Vec<Keypair> = self.addresses.values().cloned().collect();
+        keypairs.sort_by_key(Keypair::address);
+        Ok(Backup {
+            agent_id: self.agent_id.clone(),
+            build: self.build.clone(),
+            mnemonic,
+            next_index: self.next_index,
+            keypairs,
+        })
+    }
+
+    /// writes the agent of backup as the agent of the node, its keys encrypted by passphrase
+    pub fn from_backup(backup: &Backup, passphrase: &str, config: &NodeConfig) -> Result<Agent> {
+        let mut agent = Agent::create(
+            backup.agent_id.clone(),
+            backup.build.clone(),
+            backup.mnemonic.as_deref(),
+            passphrase,
+            config,
+        )?;
+        agent.next_index = backup.next_index;
+        for keypair in &backup.keypairs {
+            agent.add_keypair(keypair.clone())?;
+        }
+        agent.save()?;
+        Ok(agent)
+    }
+
+    /// adds the keypairs of backup the agent does not hold yet, the agent keeps its id and build.
+    /// an encrypted agent has to be unlocked, returns the added addresses
+    pub fn merge_backup(&mut self, backup: &Backup) -> Result<Vec<String>> {
+        if self.keystore.is_some() {
+            self.session_key()?;
+        }
+        let mut added = Vec::new();
+        for keypair in &backup.keypairs {
+            let address = keypair.address();
+            match self.addresses.get(&address) {
+                Some(held) if held == keypair => {}
+                Some(_) => return Err(PokError::InvalidBackup(format!("the keypair of {} differs from the one held", address))),
+                None => added.push(self.add_keypair(keypair.clone())?),
+            }
+        }
+        //addresses derived from a shared mnemonic go on after the ones of the backup
+        if self.mnemonic.is_some() && backup.mnemonic.is_some() && backup.mnemonic == Some(self.mnemonic()?) {
+            self.next_index = self.next_index.max(backup.next_index);
+        }
+        Ok(added)
+    }
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut hash = vec![0; hasher.output_bytes()];
+    hasher.result(&mut hash);
+    hash
+}
+
+//writes a file only its owner can read
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)?;
+    Ok(())
+}
+
+///Returns true if agent_path points at an existing entity.
+pub fn is_agent_exists(agent_path: &Path) -> bool {
+    agent_path.exists()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn test_config(name: &str) -> NodeConfig {
+        let mut config = NodeConfig::new("test");
+        config.data_dir = std::env::temp_dir().join(name);
+        config.keystore_log_n = 4;
+        let _ = std::fs::remove_dir_all(&config.data_dir);
+        config
+    }
 
     #[test]
     fn test_create_keypair_and_hash() {
@@ -326,14 +1002,234 @@ mod test {
             "Warrior".to_owned(),
             "Axe".to_owned(),
         );
-        let mut agent1 = Agent::new(build.clone(),"test").unwrap();
-        let addr1 = agent1.generate_address();
+        let config = test_config("pok_test_agent");
+        let mut agent1 = Agent::new(build,"open sesame",&config).unwrap();
+        let addr1 = agent1.generate_address().unwrap();
         let keypair1 = agent1.get_keypair_by_address(&addr1).unwrap().clone();
         agent1.save().unwrap();
+        drop(agent1);
+
+        //the secret key is not stored in plaintext and a loaded agent is locked
+        let db = open_db(config.agent_path()).unwrap();
+        let stored = db.get(&addr1).unwrap().unwrap();
+        assert!(!stored.windows(keypair1.secret_key.len()).any(|w| w == &keypair1.secret_key[..]));
+        drop(db);
+        let mut agent2 = Agent::load(&config).unwrap();
+        assert_eq!(agent2.get_all_addresses(), vec![addr1.clone()]);
+        assert!(matches!(agent2.get_keypair_by_address(&addr1), Err(PokError::Locked)));
+        assert!(matches!(agent2.unlock("open barley", DEFAULT_UNLOCK_TIMEOUT), Err(PokError::WrongPassphrase)));
+        agent2.unlock("open sesame", DEFAULT_UNLOCK_TIMEOUT).unwrap();
+        assert_eq!(agent2.get_keypair_by_address(&addr1).unwrap(), &keypair1);
+
+        //the session stays in the memory of the agent unlocked, it ends when locked or expired
+        std::fs::write(config.session_path(), b"key of an earlier version").unwrap();
+        let mut agent3 = Agent::load(&config).unwrap();
+        assert!(!config.session_path().exists());
+        assert!(!agent3.is_unlocked());
+        assert!(!agent3.lock_if_expired());
+        agent2.lock();
+        assert!(matches!(agent2.get_keypair_by_address(&addr1), Err(PokError::Locked)));
+        agent2.unlock("open sesame", Duration::from_secs(0)).unwrap();
+        assert!(!agent2.is_unlocked());
+        assert!(matches!(agent2.get_keypair_by_address(&addr1), Err(PokError::Locked)));
+        assert!(agent2.lock_if_expired());
+        assert!(agent2.addresses.values().all(|keypair| keypair.secret_key.is_empty()));
+        assert!(!config.data_dir.read_dir().unwrap().any(|entry| entry.unwrap().path().is_file()));
+
+        //a new passphrase re-encrypts the keys
+        agent3.change_passphrase("open sesame", "open rye").unwrap();
+        let mut agent4 = Agent::load(&config).unwrap();
+        assert!(agent4.unlock("open sesame", DEFAULT_UNLOCK_TIMEOUT).is_err());
+        agent4.unlock("open rye", DEFAULT_UNLOCK_TIMEOUT).unwrap();
+        assert_eq!(agent4.get_keypair_by_address(&addr1).unwrap(), &keypair1);
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        let build:Build = Build::new (
+            "Tim".to_owned(),
+            "Warrior".to_owned(),
+            "Axe".to_owned(),
+        );
+        let config = test_config("pok_test_agent_mnemonic");
+        let mut agent1 = Agent::new(build.clone(),"open sesame",&config).unwrap();
+        let mnemonic = agent1.mnemonic().unwrap();
+        assert_eq!(mnemonic.split_whitespace().count(), MNEMONIC_WORDS);
+        let addresses: Vec<String> = (0..3).map(|_| agent1.generate_address().unwrap()).collect();
+        agent1.save().unwrap();
+        drop(agent1);
+
+        //the next address carries on from the stored index once unlocked
+        let mut agent2 = Agent::load(&config).unwrap();
+        assert!(matches!(agent2.generate_address(), Err(PokError::Locked)));
+        agent2.unlock("open sesame", DEFAULT_UNLOCK_TIMEOUT).unwrap();
+        let seed = Mnemonic::parse_normalized(&mnemonic).unwrap().to_seed_normalized("");
+        assert_eq!(agent2.generate_address().unwrap(), Keypair::derive(&seed, 3).address());
+        agent2.change_passphrase("open sesame", "open rye").unwrap();
+        agent2.unlock("open rye", DEFAULT_UNLOCK_TIMEOUT).unwrap();
+        assert_eq!(agent2.mnemonic().unwrap(), mnemonic);
+
+        //a restore finds used addresses until GAP_LIMIT unused ones in a row
+        let hash = |index| {
+            let mut pub_key_hash = Keypair::derive(&seed, index).public_key;
+            hash_public_key(&mut pub_key_hash);
+            pub_key_hash
+        };
+        let used: HashSet<Vec<u8>> = vec![hash(1), hash(1 + GAP_LIMIT), hash(2 + 2 * GAP_LIMIT)].into_iter().collect();
+        let mut agent3 = Agent::restore(build.clone(),&mnemonic,"open sesame",&config).unwrap();
+        let recovered = agent3.recover_addresses(&used).unwrap();
+        assert_eq!(recovered.len() as u32, 2 + GAP_LIMIT);
+        assert_eq!(recovered[..3], addresses[..]);
+        assert_eq!(agent3.generate_address().unwrap(), Keypair::derive(&seed, 2 + GAP_LIMIT).address());
+
+        let mut agent4 = Agent::restore(build,&mnemonic,"open sesame",&config).unwrap();
+        assert_eq!(agent4.recover_addresses(&HashSet::new()).unwrap(), vec![addresses[0].clone()]);
+        assert!(Agent::restore(Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned()),"not a mnemonic","open sesame",&config).is_err());
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_backup() {
+        let build:Build = Build::new (
+            "Tim".to_owned(),
+            "Warrior".to_owned(),
+            "Axe".to_owned(),
+        );
+        let config = test_config("pok_test_agent_backup");
+        let other = test_config("pok_test_agent_backup_other");
+        std::fs::create_dir_all(&config.data_dir).unwrap();
+        let mut agent1 = Agent::new(build.clone(),"open sesame",&config).unwrap();
+        agent1.generate_address().unwrap();
+        agent1.generate_address().unwrap();
+        agent1.save().unwrap();
+        let backup = agent1.backup().unwrap();
+        assert_eq!(backup.agent_id(), agent1.get_id());
+        let mut addresses = agent1.get_all_addresses();
+        addresses.sort();
+        assert_eq!(backup.addresses(), addresses);
+
+        //a plain file is checked against its checksum
+        let plain = config.data_dir.join("plain.backup");
+        backup.write(&plain, None, &config).unwrap();
+        assert!(!Backup::is_encrypted(&plain).unwrap());
+        assert_eq!(Backup::read(&plain, None).unwrap(), backup);
+        let mut data = std::fs::read(&plain).unwrap();
+        let last = data.len() - 40;
+        data[last] ^= 1;
+        std::fs::write(&plain, &data).unwrap();
+        assert!(matches!(Backup::read(&plain, None), Err(PokError::InvalidBackup(_))));
+        data[8] = 2;
+        std::fs::write(&plain, &data).unwrap();
+        assert!(matches!(Backup::read(&plain, None), Err(PokError::InvalidBackup(_))));
+
+        //an encrypted file needs its passphrase
+        let sealed = config.data_dir.join("sealed.backup");
+        backup.write(&sealed, Some("open rye"), &config).unwrap();
+        assert!(Backup::is_encrypted(&sealed).unwrap());
+        assert!(!std::fs::read(&sealed).unwrap().windows(backup.agent_id().len()).any(|w| w == backup.agent_id().as_bytes()));
+        assert!(matches!(Backup::read(&sealed, None), Err(PokError::WrongPassphrase)));
+        assert!(matches!(Backup::read(&sealed, Some("open sesame")), Err(PokError::WrongPassphrase)));
+        let read = Backup::read(&sealed, Some("open rye")).unwrap();
+        assert_eq!(read, backup);
+
+        //the imported agent carries on deriving where the exported one stopped
+        let mut agent2 = Agent::from_backup(&read, "open barley", &other).unwrap();
+        assert_eq!(agent2.get_id(), agent1.get_id());
+        assert_eq!(agent2.get_build(), agent1.get_build());
+        assert_eq!(agent2.generate_address().unwrap(), agent1.generate_address().unwrap());
+        agent2.save().unwrap();
+        let mut agent2 = Agent::load(&other).unwrap();
+        agent2.unlock("open barley", DEFAULT_UNLOCK_TIMEOUT).unwrap();
+        assert_eq!(agent2.get_all_addresses().len(), 3);
+
+        //merging adds what is missing and refuses another key for a held address
+        let mut agent3 = Agent::new(build,"open sesame",&other).unwrap();
+        let own = agent3.generate_address().unwrap();
+        let mut added = agent3.merge_backup(&backup).unwrap();
+        added.sort();
+        assert_eq!(added, addresses);
+        assert!(agent3.merge_backup(&backup).unwrap().is_empty());
+        assert_eq!(agent3.get_all_addresses().len(), 3);
+        assert_ne!(agent3.generate_address().unwrap(), own);
+        let mut forged = backup.clone();
+        forged.keypairs[0].secret_key = Keypair::new().secret_key;
+        assert!(matches!(agent3.merge_backup(&forged), Err(PokError::InvalidBackup(_))));
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+        std::fs::remove_dir_all(&other.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_watch_only() {
+        let build:Build = Build::new (
+            "Tim".to_owned(),
+            "Warrior".to_owned(),
+            "Axe".to_owned(),
+        );
+        let config = test_config("pok_test_agent_watch");
+        let mut agent = Agent::new(build,"open sesame",&config).unwrap();
+        let own = agent.generate_address().unwrap();
+        let cold1 = Keypair::new();
+        let cold2 = Keypair::new();
+        assert_eq!(agent.watch(&cold1.address()).unwrap(), cold1.address());
+        let hex_key = HEXLOWER_PERMISSIVE.encode(&cold2.public_key);
+        assert_eq!(agent.watch(&hex_key).unwrap(), cold2.address());
+        assert!(matches!(agent.watch("not an address"), Err(PokError::InvalidAddress(_))));
+        assert!(matches!(agent.watch(&own), Err(PokError::InvalidArgument(_))));
+        agent.save().unwrap();
+        drop(agent);
+
+        //watch-only entries persist, never sign and are not among the own addresses
+        let mut agent = Agent::load(&config).unwrap();
+        agent.unlock("open sesame", DEFAULT_UNLOCK_TIMEOUT).unwrap();
+        let mut watched = agent.get_watch_only_addresses();
+        watched.sort();
+        let mut expected = vec![cold1.address(), cold2.address()];
+        expected.sort();
+        assert_eq!(watched, expected);
+        assert_eq!(agent.get_all_addresses(), vec![own]);
+        assert!(agent.is_watch_only(&cold1.address()));
+        assert!(matches!(agent.get_keypair_by_address(&cold1.address()), Err(PokError::WatchOnly(_))));
+        assert!(agent.backup().unwrap().addresses().iter().all(|address| !agent.is_watch_only(address)));
+
+        agent.unwatch(&cold1.address()).unwrap();
+        assert!(matches!(agent.unwatch(&cold1.address()), Err(PokError::UnknownAddress(_))));
+        agent.save().unwrap();
+        drop(agent);
+        assert_eq!(Agent::load(&config).unwrap().get_watch_only_addresses(), vec![cold2.address()]);
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_plaintext_agent() {
+        let build:Build = Build::new (
+            "Tim".to_owned(),
+            "Warrior".to_owned(),
+            "Axe".to_owned(),
+        );
+        let config = test_config("pok_test_agent_plaintext");
+        let keypair = Keypair::new();
+        let address = keypair.address();
+        //an agent stored before keystores
+        let old = serialize(&Agent::new(build,"unused",&config).unwrap()).unwrap();
+        let db = open_db(config.agent_path()).unwrap();
+        db.insert(AGENT_KEY, old).unwrap();
+        db.remove(KEYSTORE_KEY).unwrap();
+        db.insert(&address, serialize(&keypair).unwrap()).unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let mut agent = Agent::load(&config).unwrap();
+        assert!(!agent.is_encrypted());
+        assert_eq!(agent.get_keypair_by_address(&address).unwrap(), &keypair);
+        agent.change_passphrase("", "open sesame").unwrap();
+        assert!(matches!(agent.get_keypair_by_address(&address), Err(PokError::Locked)));
 
-        let agent2=  Agent::new(build,"test").unwrap();
-        let keypair2 = agent2.get_keypair_by_address(&addr1).unwrap();
-        assert_eq!(&keypair1, keypair2);
+        let mut agent = Agent::load(&config).unwrap();
+        assert!(agent.is_encrypted());
+        agent.unlock("open sesame", DEFAULT_UNLOCK_TIMEOUT).unwrap();
+        assert_eq!(agent.get_keypair_by_address(&address).unwrap(), &keypair);
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
     }
 
     #[test]
@@ -345,7 +1241,7 @@ mod test {
             "Axe".to_owned(),
         );
         let k3 = Keypair::new();
-        let agent2 = Agent::new(build,"test").unwrap();
+        let agent2 = Agent::new(build,"open sesame",&test_config("pok_test_agent_not_exist")).unwrap();
         agent2.get_keypair_by_address(&k3.address()).unwrap();
     }
 
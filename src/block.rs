@@ -1,5 +1,6 @@
 use super::*;
 use crate::agent::*;
+use crate::miner::MinerContext;
 use crate::transaction::Transaction;
 use bincode::serialize;
 use ::crypto::digest::Digest;
@@ -7,47 +8,145 @@ use ::crypto::sha2::Sha256;
 use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
 use serde::{Deserialize, Serialize};
+use crate::crypto::generator_from_sha256;
+use crate::fight::*;
+use rand_pcg::Pcg64;
 use std::time::SystemTime;
-use rand::Rng;
 
-const TARGET_HEXS: usize = 4;
-const CHANCE:u32 = 100;
+/// fights a block carries, kills can not exceed it
+pub(crate) const CHANCE:u32 = 100;
+/// leading zero bits required from the hash of the first blocks
+pub const INITIAL_BITS: u32 = 8;
+pub const MIN_BITS: u32 = 1;
+pub const MAX_BITS: u32 = 64;
+//every KILLS_PER_BIT kills of a block waive one bit of its target
+const KILLS_PER_BIT: u32 = 20;
+/// bytes the serialized transactions of a block take at most
+pub const MAX_BLOCK_TX_BYTES: usize = 500_000;
+/// version of the block header layout, bumped whenever the hashed fields change
+pub const BLOCK_VERSION: u32 = 2;
 
-#[derive(Serialize, Deserialize)]
-enum State {
-    UNVERIFIED,
-    VERIFIED,
-    FIGHTABLE,
-    SEALED,
+/// BlockHeader carries every consensus-relevant field of a block.
+///
+/// the block hash is the hash of its header, transactions and fights are
+/// committed to through merkle_root and fights_root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub fights_root: Vec<u8>,
+    pub timestamp: u128,
+    pub height: u128,
+    //difficulty, leading zero bits the hash needs before kills are accounted for
+    pub bits: u32,
+    pub nonce: u64,
+    //chance to have fight with transactions
+    pub chance: u32,
+    pub kills: u32,
+    //current champion of this Block
+    pub agent_id: String,
+    pub agent_build: Build,
 }
 
+impl BlockHeader {
+    /// hashes the header, which gives the hash of its block
+    pub fn hash(&self) -> Result<String> {
+        let data = serialize(self)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        Ok(hasher.result_str())
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+    /// leading zero bits the hash of this header needs, once its kills are accounted for
+    pub fn required_bits(&self) -> u32 {
+        self.bits.saturating_sub(self.kills / KILLS_PER_BIT)
+    }
+
+    /// returns true if hash reaches the kill-weighted target.
+    ///
+    /// kills are only claimed by the header, at most `chance` of them,
+    /// `Block::verify_fights` checks them once the body is known.
+    pub fn meets_target(&self, hash: &str) -> bool {
+        leading_zero_bits(hash) >= self.required_bits()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Block{
+    header: BlockHeader,
+    transactions: Vec<Transaction>,
+    //transcripts of the duels fought for this block
+    fights: Vec<FightResult>,
+    hash: String,
+}
+
+/// LegacyBlock is the layout blocks were stored with before headers were versioned.
+///
+/// it is only read by `Blockchain::migrate`.
+#[derive(Deserialize)]
+pub struct LegacyBlock {
     timestamp: u128,
     transactions: Vec<Transaction>,
+    #[allow(dead_code)]
     hash: String,
     prev_block_hash: String,
     height: u128,
-    //chance to have fight with transactions
+    #[allow(dead_code)]
     chance: u32,
-    kills:u32,
-    //current champion of this Block
+    #[allow(dead_code)]
+    kills: u32,
     agent_id: String,
     agent_build: Build,
 }
 
+impl LegacyBlock {
+    pub fn get_prev_hash(&self) -> String {
+        self.prev_block_hash.clone()
+    }
+
+    /// upgrade() turns a legacy block into a current one on top of prev_block_hash,
+    /// fights are replayed and the block is mined again with the given difficulty.
+    pub fn upgrade(self, prev_block_hash: String, bits: u32) -> Result<Block> {
+        let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash,
+                merkle_root: Vec::new(),
+                fights_root: Vec::new(),
+                timestamp: self.timestamp,
+                height: self.height,
+                bits,
+                nonce: 0,
+                chance: CHANCE,
+                kills: 0,
+                agent_id: self.agent_id,
+                agent_build: self.agent_build,
+            },
+            transactions: self.transactions,
+            fights: Vec::new(),
+            hash: String::new(),
+        };
+        block.dogfight()?;
+        Ok(block)
+    }
+}
+
 impl Block {
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_kills(&self) -> u32 {
-        self.kills
+        self.header.kills
     }
 
     pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+        self.header.prev_block_hash.clone()
     }
 
     pub fn get_transaction(&self) -> &Vec<Transaction> {
@@ -55,57 +154,144 @@ impl Block {
     }
 
     pub fn get_height(&self) -> u128 {
-        self.height
+        self.header.height
     }
 
-    /// NewBlock creates and returns Block
-    pub fn new_block(transactions: Vec<Transaction>, prev_block_hash: String,height: u128) -> Result<Block> {
-        let agent = Agent::load().unwrap();
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
+    }
+
+    /// bytes taken by the serialized transactions, at most MAX_BLOCK_TX_BYTES in a valid block
+    pub fn transactions_size(&self) -> Result<usize> {
+        let mut size = 0;
+        for tx in &self.transactions {
+            size += tx.size()?;
+        }
+        Ok(size)
+    }
+
+    /// NewBlock creates and returns Block, with the agent of ctx as champion
+    pub fn new_block(
+        ctx: &MinerContext,
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: u128,
+        bits: u32,
+    ) -> Result<Block> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
         let mut block = Block {
-            timestamp,
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash,
+                merkle_root: Vec::new(),
+                fights_root: Vec::new(),
+                timestamp,
+                height,
+                bits,
+                nonce: 0,
+                chance: CHANCE,
+                kills: 0,
+                agent_id: ctx.agent_id.clone(),
+                agent_build: ctx.build.clone(),
+            },
             transactions,
-            prev_block_hash,
+            fights: Vec::new(),
             hash: String::new(),
-            height,
-            chance:CHANCE,
-            kills : 0,
-            agent_id: "none".to_owned(),
-            agent_build: agent.get_build().clone(),
         };
         block.dogfight()?;
-        block.agent_id = agent.get_id().to_owned();
-        block.agent_build = agent.get_build().to_owned();
         Ok(block)
     }
 
     /// Run performs a proof-of-work
     fn dogfight(&mut self) -> Result<()> {
-        println!("dogfight to the block");
-        let transactions = self.transactions.clone();
-        for tx in transactions {
-            tx.sender_build.clone().introduce();
-            println!("duel start.\n");
-            while self.chance != 0  {
-                let mut my_build = Agent::load().unwrap().get_build().clone();
-                if Block::is_opponent_killed(&mut my_build,&mut tx.sender_build.clone(),None) {
-                    self.kills += 1;
-                }
-                self.chance -= 1;
-            }
+        debug!("dogfight to the block");
+        self.header.merkle_root = self.hash_transactions()?;
+        self.fights = self.run_fights()?;
+        self.header.fights_root = self.hash_fights()?;
+        self.header.kills = self.fights.iter().filter(|f| f.is_opponent_killed()).count() as u32;
+
+        //kills lower the target the nonce has to reach
+        self.hash = self.calculate_hash()?;
+        while !self.meets_target() {
+            self.header.nonce += 1;
+            self.hash = self.calculate_hash()?;
         }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
         Ok(())
     }
 
+    /// leading zero bits the hash of this block needs, once its kills are accounted for
+    pub fn required_bits(&self) -> u32 {
+        self.header.required_bits()
+    }
+
+    /// returns true if the stored hash reaches the kill-weighted target
+    pub fn meets_target(&self) -> bool {
+        self.header.meets_target(&self.hash)
+    }
+
+    /// calculate_hash() hashes the block header, the stored hash is left untouched.
+    pub fn calculate_hash(&self) -> Result<String> {
+        self.header.hash()
+    }
+
+    /// returns true if the header roots commit to the transactions and fights carried by the block
+    pub fn verify_roots(&self) -> Result<bool> {
+        Ok(self.header.merkle_root == self.hash_transactions()?
+            && self.header.fights_root == self.hash_fights()?)
+    }
+
+    /// replays every duel of the block and returns true if the recorded fights
+    /// and the claimed kills match.
+    ///
+    /// any node holding the block can call this, the outcome depends on block data only.
+    pub fn verify_fights(&self) -> Result<bool> {
+        if self.fights != self.run_fights()? {
+            return Ok(false);
+        }
+        if !self.fights.iter().all(verify_fight) {
+            return Ok(false);
+        }
+        let kills = self.fights.iter().filter(|f| f.is_opponent_killed()).count() as u32;
+        Ok(kills == self.header.kills)
+    }
+
+    /// fights the champion against the sender of each transaction,
+    /// with `chance` rounds shared by the whole block.
+    fn run_fights(&self) -> Result<Vec<FightResult>> {
+        let mut rng = self.duel_rng()?;
+        let mut chance = self.header.chance;
+        let mut fights = Vec::new();
+        for tx in &self.transactions {
+            tx.sender_build.introduce();
+            debug!("duel start.");
+            while chance != 0 {
+                fights.push(versus(&self.header.agent_build, &tx.sender_build, &mut rng));
+                chance -= 1;
+            }
+        }
+        Ok(fights)
+    }
+
+    /// duel randomness is seeded by the parent, the transactions and the champion,
+    /// so the same block always replays the same fights.
+    fn duel_rng(&self) -> Result<Pcg64> {
+        let seed = serialize(&(
+            self.header.prev_block_hash.clone(),
+            self.hash_transactions()?,
+            self.header.agent_id.clone(),
+        ))?;
+        Ok(generator_from_sha256(&seed[..])?)
+    }
+
     /// NewGenesisBlock creates and returns genesis Block
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+    pub fn new_genesis_block(ctx: &MinerContext, coinbase: Transaction) -> Result<Block> {
+        Block::new_block(ctx, vec![coinbase], String::new(), 0, INITIAL_BITS)
     }
 
     /// HashTransactions returns a hash of the transactions in the block
@@ -119,67 +305,28 @@ impl Block {
         Ok(tree.root())
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEXS,
-            self.chance,
-        );
-        let bytes = serialize(&content)?;
-        Ok(bytes)
-    }
-
-    /// is_opponent_killed() return true if killed the opponent.
-    fn is_opponent_killed(myself: &mut Build, opponent: &mut Build,random_seed:Option<Vec<i32>>) -> bool {
-        match random_seed {
-            None => {
-                let mut random_seed : Vec<i32> = vec![];
-                let mut rng = rand::thread_rng();
-
-                while myself.get_health() > 0 && opponent.get_health() > 0 {
-                    //-5 ~ 5 inclusively
-                    let randomness:i32 = rng.gen_range(-5..=5);
-                    random_seed.push(randomness);
-                    //decide the first-mover
-                    if myself.current_action() > opponent.current_action() {
-                        opponent.take_damage(myself.produce_damage(randomness));
-                        myself.take_damage(opponent.produce_damage(randomness));
-                    } else {
-                        myself.take_damage(opponent.produce_damage(randomness));
-                        opponent.take_damage(myself.produce_damage(randomness));
-                    }
-                    //self.agent_build.regenerate();
-                    //opponent.regenerate();
-    
-                    myself.report_health();
-                    opponent.report_health();
-                }
-                return myself.check_death() > 0;
-            },
-            Some(random_seed) => {
-                //unimplemented
-                for randomness in &random_seed{
-                    if myself.current_action() > opponent.current_action() {
-                        opponent.take_damage(myself.produce_damage(*randomness));
-                        myself.take_damage(opponent.produce_damage(*randomness));
-                } else {
-                    myself.take_damage(opponent.produce_damage(*randomness));
-                        opponent.take_damage(myself.produce_damage(*randomness));
-                }
-                //myself.regenerate();
-                //opponent.regenerate();
-    
-                myself.report_health();
-                opponent.report_health();
-                }
-                return myself.check_death() > 0;
-                
-            },
-        }
+    /// hash of the fight transcripts, so they can't be altered without changing the block hash
+    fn hash_fights(&self) -> Result<Vec<u8>> {
+        let data = serialize(&self.fights)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        let mut re: [u8; 32] = [0; 32];
+        hasher.result(&mut re);
+        Ok(re.to_vec())
+    }
+}
 
+/// counts the leading zero bits of a hex encoded hash
+fn leading_zero_bits(hash: &str) -> u32 {
+    let mut bits = 0;
+    for c in hash.chars() {
+        match c.to_digit(16) {
+            Some(0) => bits += 4,
+            Some(d) => return bits + d.leading_zeros() - 28,
+            None => return bits,
+        }
     }
+    bits
 }
 
 struct MergeVu8 {}
@@ -196,3 +343,41 @@ impl Merge for MergeVu8 {
         re.to_vec()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction::INITIAL_SUBSIDY;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn test_miner() -> MinerContext {
+        let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+        MinerContext::new("tester".to_owned(), build, Keypair::new().address(), StdRng::seed_from_u64(7))
+    }
+
+    #[test]
+    fn test_mined_block_replays() {
+        let mut miner = test_miner();
+        let coinbase = miner.coinbase(String::from("test"), INITIAL_SUBSIDY).unwrap();
+        let block = Block::new_genesis_block(&miner, coinbase).unwrap();
+        assert_eq!(block.calculate_hash().unwrap(), block.get_hash());
+        assert!(block.verify_roots().unwrap());
+        assert!(block.verify_fights().unwrap());
+        assert!(block.meets_target());
+
+        let mut forged = block.clone();
+        forged.header.kills += 1;
+        assert_ne!(forged.calculate_hash().unwrap(), forged.get_hash());
+        assert!(!forged.verify_fights().unwrap());
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits("ffff"), 0);
+        assert_eq!(leading_zero_bits("7fff"), 1);
+        assert_eq!(leading_zero_bits("0fff"), 4);
+        assert_eq!(leading_zero_bits("001f"), 11);
+        assert_eq!(leading_zero_bits("0000"), 16);
+    }
+}
@@ -1,13 +1,186 @@
 use super::*;
 use crate::block::*;
+use crate::config::NodeConfig;
+use crate::miner::MinerContext;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
-use failure::format_err;
-use sled;
-use std::collections::HashMap;
+use crate::PokError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 
+//key holding the block layout version of the database
+const DB_VERSION_KEY: &str = "VERSION";
+//tree mapping a block hash to its BlockIndex
+const INDEX_TREE: &str = "index";
+//tree mapping a block hash to the BlockUndo needed to take it off the UTXO set
+const UNDO_TREE: &str = "undo";
+//tree used by older databases to keep cumulative kills only, superseded by INDEX_TREE
+const KILLS_TREE: &str = "kills";
+//tree mapping a block hash to the BlockHeader accepted ahead of its body
+const HEADER_TREE: &str = "header";
+//hashes at the head of a block locator before it starts skipping blocks
+const LOCATOR_DENSE_LEN: usize = 10;
 const GENESIS_COINBASE_DATA: &str = "18:29, August 3rd, 2021, Tokyo. The sunset is beautiful.";
+//blocks between two difficulty adjustments
+const RETARGET_INTERVAL: u128 = 10;
+//expected time between two blocks, in milliseconds
+const TARGET_BLOCK_TIME: u128 = 60 * 1000;
+//how far a block timestamp may run ahead of the local clock, in milliseconds
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// BlockError explains why validate_block refused a block
+#[derive(Debug)]
+pub enum BlockError {
+    BadHash(String),
+    UnknownParent(String),
+    BadHeight(u128, u128),
+    BadTimestamp(u128),
+    InvalidTransaction(String),
+    CoinbaseCount(usize),
+    BadCoinbaseValue(i64, i64),
+    DoubleSpend(String, i32),
+    //an output the parent chain never created or already spent
+    MissingOutput(String, i32),
+    BadFights,
+    UnsupportedVersion(u32),
+    BadRoots,
+    BadDifficulty(u32, u32),
+    //chance other than CHANCE
+    BadChance(u32),
+    //kills claimed beyond the fights the block can carry
+    TooManyKills(u32, u32),
+    TargetNotMet(String),
+    //a second genesis, building on it would share no ancestor with our chain
+    ForeignGenesis(String),
+    //bytes of transactions
+    TooLarge(usize),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::BadHash(hash) => write!(f, "block hash {} does not match its content", hash),
+            BlockError::UnknownParent(hash) => write!(f, "previous block {} is unknown", hash),
+            BlockError::BadHeight(height, parent) => {
+                write!(f, "block height {} does not follow parent height {}", height, parent)
+            }
+            BlockError::BadTimestamp(timestamp) => write!(f, "block timestamp {} is out of range", timestamp),
+            BlockError::InvalidTransaction(id) => write!(f, "transaction {} is invalid", id),
+            BlockError::CoinbaseCount(count) => {
+                write!(f, "block should contain exactly one coinbase, found {}", count)
+            }
+            BlockError::BadCoinbaseValue(value, expected) => {
+                write!(f, "coinbase pays {} instead of {}", value, expected)
+            }
+            BlockError::DoubleSpend(txid, vout) => write!(f, "output {}:{} is spent more than once", txid, vout),
+            BlockError::MissingOutput(txid, vout) => {
+                write!(f, "output {}:{} is missing or already spent", txid, vout)
+            }
+            BlockError::BadFights => write!(f, "fights do not match the claimed kills"),
+            BlockError::UnsupportedVersion(version) => write!(f, "block version {} is not supported", version),
+            BlockError::BadRoots => write!(f, "block header does not commit to its transactions and fights"),
+            BlockError::BadDifficulty(bits, expected) => {
+                write!(f, "block difficulty is {} bits instead of {}", bits, expected)
+            }
+            BlockError::BadChance(chance) => write!(f, "block chance is {} instead of {}", chance, CHANCE),
+            BlockError::TooManyKills(kills, chance) => {
+                write!(f, "block claims {} kills out of {} fights", kills, chance)
+            }
+            BlockError::TargetNotMet(hash) => write!(f, "block hash {} does not reach its target", hash),
+            BlockError::ForeignGenesis(hash) => write!(f, "genesis block {} is not the genesis of this chain", hash),
+            BlockError::TooLarge(size) => {
+                write!(f, "block carries {} bytes of transactions, at most {} are allowed", size, MAX_BLOCK_TX_BYTES)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// BlockIndex locates a block in the block tree, its body may not be stored yet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockIndex {
+    pub prev_hash: String,
+    pub height: u128,
+    //kills accumulated from genesis up to this block
+    pub cumulative_kills: u128,
+}
+
+impl BlockIndex {
+    /// the chain with more cumulative kills wins, height breaks ties
+    pub fn is_heavier_than(&self, other: &BlockIndex) -> bool {
+        (self.cumulative_kills, self.height) > (other.cumulative_kills, other.height)
+    }
+}
+
+/// SpentOutput is an output consumed by a block, kept so the block can be reverted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpentOutput {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TXOutput,
+}
+
+/// BlockUndo holds the outputs spent by each transaction of a block, in block order
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BlockUndo {
+    pub spent: Vec<Vec<SpentOutput>>,
+}
+
+/// HistoryEntry is a transaction of the main chain paying to or spending from a set of pub key hashes
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub height: u128,
+    pub block_hash: String,
+    pub txid: String,
+    //paid to the set by the outputs of the transaction
+    pub received: i64,
+    //taken from the set by the inputs of the transaction
+    pub sent: i64,
+}
+
+/// ChainUpdate tells what add_block did with a block
+#[derive(Debug)]
+pub enum ChainUpdate {
+    //the block was already stored
+    Known,
+    //the block was stored on a fork lighter than the current chain
+    SideChain,
+    //the block was stored on top of the tip
+    Extended,
+    //a heavier fork became the main chain.
+    //disconnected goes from the old tip backwards, connected from the fork point forwards
+    Reorganized {
+        disconnected: Vec<Block>,
+        connected: Vec<Block>,
+    },
+}
+
+impl ChainUpdate {
+    /// transactions of disconnected blocks that did not make it into the new main chain
+    pub fn orphaned_transactions(&self) -> Vec<Transaction> {
+        match self {
+            ChainUpdate::Reorganized { disconnected, connected } => {
+                let confirmed: HashSet<&String> = connected
+                    .iter()
+                    .flat_map(|b| b.get_transaction())
+                    .map(|tx| &tx.id)
+                    .collect();
+                disconnected
+                    .iter()
+                    .flat_map(|b| b.get_transaction())
+                    .filter(|tx| !tx.is_coinbase() && !confirmed.contains(&tx.id))
+                    .cloned()
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
 
 /// Blockchain implements interactions with a DB
 #[derive(Debug)]
@@ -15,6 +188,27 @@ pub struct Blockchain {
     pub tip: String,
     pub db: sled::Db,
     pub kills: u128,
+    //heaviest known header, ahead of tip while block bodies are downloaded
+    pub best_header: String,
+    //directory of the node, the UTXO set is kept next to the chain
+    pub data_dir: PathBuf,
+}
+
+/// BlockTemplate is a block waiting to be mined on top of prev_hash
+pub struct BlockTemplate {
+    //the coinbase comes last
+    pub transactions: Vec<Transaction>,
+    pub prev_hash: String,
+    pub height: u128,
+    pub bits: u32,
+}
+
+impl BlockTemplate {
+    /// mine() runs the fights and the proof of work, ctx has to be the one the template was built for
+    pub fn mine(self, ctx: &mut MinerContext) -> Result<Block> {
+        //this will start dogfight() to each of transaction with own agent.
+        Block::new_block(ctx, self.transactions, self.prev_hash, self.height, self.bits)
+    }
 }
 
 /// BlockchainIterator is used to iterate over blockchain blocks
@@ -25,43 +219,52 @@ pub struct BlockchainIterator<'a> {
 
 impl Blockchain {
 
-    /// init() creates a new blockchain with DB initialized.
-    pub fn init(address: String, node_id:&str) -> Result<Blockchain> {
+    /// init() creates a new blockchain with DB initialized,
+    /// the genesis block is mined by ctx and pays its payout address.
+    pub fn init(ctx: &mut MinerContext, config: &NodeConfig) -> Result<Blockchain> {
         info!("Initialize New Blockchain");
         //e.g., data_3000/chain
-        let db_path = "data_".to_owned() + node_id + "/chain";
-        if Is_db_exists(&db_path) {
-            return Err(format_err!("ERROR: Blockchain Already Exists."));
+        let db_path = config.chain_path();
+        if is_db_exists(&db_path) {
+            return Err(PokError::Chain(String::from("blockchain already exists")));
         }
 
         //std::fs::remove_dir_all(&db_path).ok();
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
+        let cbtx = ctx.coinbase(String::from(GENESIS_COINBASE_DATA), subsidy(0))?;
+        let genesis: Block = Block::new_genesis_block(ctx, cbtx)?;
 
-        let db = sled::open(db_path)?;
+        let db = open_db(db_path)?;
         debug!("Configuring A New Blockchain Database...");
 
         db.insert(genesis.get_hash(), serialize(&genesis)?)?;
         db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = Blockchain {
+        db.insert(DB_VERSION_KEY, serialize(&BLOCK_VERSION)?)?;
+        let mut bc = Blockchain {
             tip: genesis.get_hash(),
             db,
             kills:0,
+            best_header: genesis.get_hash(),
+            data_dir: config.data_dir.clone(),
         };
+        bc.kills = bc.store_block_index(&genesis)?.cumulative_kills;
+        bc.store_block_undo(&genesis)?;
         bc.db.flush()?;
         Ok(bc)
     }
 
     /// load() loads an existed Blockchain on the disk and .
-    pub fn load(node_id:&str) -> Result<Blockchain> {
-        //e.g., data/chain_3000
-        let db_path = "data_".to_owned() + node_id + "/chain";
-        if !Is_db_exists(&db_path) {
-            return Err(format_err!("blockchain database is not initialized.\nuse command `initdb` to initialize one."));
+    pub fn load(config: &NodeConfig) -> Result<Blockchain> {
+        //e.g., data_3000/chain
+        let db_path = config.chain_path();
+        if !is_db_exists(&db_path) {
+            return Err(PokError::Chain(String::from("blockchain database is not initialized.\nuse command `initdb` to initialize one.")));
         }
 
         info!("Blockchain Database is Found. Loading...");
-        let db = sled::open(db_path)?;
+        let db = open_db(db_path)?;
+        if Blockchain::db_version(&db)? != BLOCK_VERSION {
+            return Err(PokError::Chain(String::from("blockchain database uses an old block format.\nuse command `migrate` to upgrade it.")));
+        }
         let hash = match db.get("LAST")? {
             Some(last) => last.to_vec(),
             None => Vec::new(),
@@ -72,63 +275,757 @@ impl Blockchain {
         } else {
             String::from_utf8(hash.to_vec())?
         };
+        let mut bc = Blockchain {
+            best_header: lasthash.clone(),
+            tip: lasthash,
+            db,
+            kills: 0,
+            data_dir: config.data_dir.clone(),
+        };
+        bc.kills = match bc.get_block_index(&bc.tip)? {
+            Some(index) => index.cumulative_kills,
+            None if bc.tip.is_empty() => 0,
+            None => {
+                warn!("block index is missing, rebuilding it from stored blocks");
+                bc.repair_kills()?
+            }
+        };
         info!("Loaded.");
-        //**********TODO: kills is not 0***************
-        Ok(Blockchain { tip: lasthash, db, kills:0 })
+        Ok(bc)
     }
 
-     /// Saves the block into the blockchain
-     pub fn add_block(&mut self, block: Block) -> Result<()> {
+    /// migrate() rewrites a database written with an older block layout.
+    ///
+    /// the main chain is walked from LAST back to genesis, then every block is
+    /// upgraded from genesis forward so that each one links to the new hash of its parent.
+    /// upgrading mines every block again, so every block hash changes.
+    /// blocks that are not on the main chain are dropped.
+    ///
+    /// the upgraded chain and its index are written to a new database first, which then
+    /// takes the place of the old one. the old database is kept next to it as `chain.legacy`,
+    /// a failure on the way leaves it untouched.
+    /// returns the number of migrated blocks.
+    pub fn migrate(config: &NodeConfig) -> Result<usize> {
+        let db_path = config.chain_path();
+        if !is_db_exists(&db_path) {
+            return Err(PokError::Chain(String::from("blockchain database is not initialized.\nuse command `initdb` to initialize one.")));
+        }
+        let db = open_db(&db_path)?;
+        if Blockchain::db_version(&db)? == BLOCK_VERSION {
+            return Ok(0);
+        }
+        let legacy_path = db_path.with_extension("legacy");
+        if is_db_exists(&legacy_path) {
+            return Err(PokError::Chain(format!(
+                "{} is in the way of the old database, move it elsewhere first",
+                legacy_path.display()
+            )));
+        }
+
+        let mut legacy_chain = Vec::new();
+        let mut current_hash = match db.get("LAST")? {
+            Some(last) => String::from_utf8(last.to_vec())?,
+            None => String::new(),
+        };
+        while !current_hash.is_empty() {
+            let data = match db.get(&current_hash)? {
+                Some(data) => data,
+                None => return Err(PokError::NotFound(format!("block {}", current_hash))),
+            };
+            let legacy: LegacyBlock = deserialize(&data)?;
+            current_hash = legacy.get_prev_hash();
+            legacy_chain.push(legacy);
+        }
+        drop(db);
+
+        info!("Migrating {} blocks to block version {}", legacy_chain.len(), BLOCK_VERSION);
+        //a migration that failed before left its unfinished database behind
+        let new_path = db_path.with_extension("migrating");
+        std::fs::remove_dir_all(&new_path).ok();
+        let count = legacy_chain.len();
+        let mut bc = Blockchain {
+            tip: String::new(),
+            db: open_db(&new_path)?,
+            kills: 0,
+            best_header: String::new(),
+            data_dir: config.data_dir.clone(),
+        };
+        for legacy in legacy_chain.into_iter().rev() {
+            let bits = bc.next_bits(&bc.tip)?;
+            let block = legacy.upgrade(bc.tip.clone(), bits)?;
+            bc.db.insert(block.get_hash(), serialize(&block)?)?;
+            bc.store_block_index(&block)?;
+            bc.store_block_undo(&block)?;
+            bc.tip = block.get_hash();
+        }
+        bc.db.insert("LAST", bc.tip.as_bytes())?;
+        bc.db.insert(DB_VERSION_KEY, serialize(&BLOCK_VERSION)?)?;
+        bc.db.flush()?;
+        drop(bc);
+
+        std::fs::rename(&db_path, &legacy_path)?;
+        std::fs::rename(&new_path, &db_path)?;
+        info!("the old database is kept in {}", legacy_path.display());
+        Ok(count)
+    }
+
+    /// block layout version of db, databases written before versioning report 1
+    fn db_version(db: &sled::Db) -> Result<u32> {
+        match db.get(DB_VERSION_KEY)? {
+            Some(version) => Ok(deserialize(&version)?),
+            None => Ok(1),
+        }
+    }
+
+    /// Saves the block into the blockchain
+    ///
+    /// the tip moves to the block if its chain carries more cumulative kills,
+    /// switching to another fork when the block does not extend the current tip.
+    pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate> {
         let data = serialize(&block)?;
         //if this block is already exists, discard it.
-        if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
+        if self.db.get(block.get_hash())?.is_some() {
+            return Ok(ChainUpdate::Known);
         }
+        self.validate_block(&block)?;
         self.db.insert(block.get_hash(), data)?;
+        let index = self.store_block_index(&block)?;
+        self.store_block_undo(&block)?;
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.tip = block.get_hash();
-            self.kills = self.kills + block.get_kills() as u128;
-            self.db.flush()?;
+        if let Some(tip_index) = self.get_block_index(&self.tip)? {
+            if !index.is_heavier_than(&tip_index) {
+                self.db.flush()?;
+                return Ok(ChainUpdate::SideChain);
+            }
+        }
+
+        let update = if block.get_prev_hash() == self.tip {
+            ChainUpdate::Extended
+        } else {
+            let (disconnected, connected) = self.find_fork(&self.tip, &block.get_hash())?;
+            info!(
+                "reorganize: disconnect {} blocks, connect {} blocks",
+                disconnected.len(),
+                connected.len()
+            );
+            ChainUpdate::Reorganized { disconnected, connected }
+        };
+        self.set_tip(&block.get_hash(), index.cumulative_kills)?;
+        Ok(update)
+    }
+
+    /// walks both chains back to their common ancestor.
+    ///
+    /// returns the blocks only on the old chain, from old_tip backwards,
+    /// and the blocks only on the new chain, from the fork point forwards.
+    fn find_fork(&self, old_tip: &str, new_tip: &str) -> Result<(Vec<Block>, Vec<Block>)> {
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        let mut old_hash = old_tip.to_owned();
+        let mut new_hash = new_tip.to_owned();
+        while old_hash != new_hash {
+            let old_index = self.get_block_index(&old_hash)?;
+            let new_index = self.get_block_index(&new_hash)?;
+            match (old_index, new_index) {
+                (Some(old_index), Some(new_index)) => {
+                    if old_index.height >= new_index.height {
+                        disconnected.push(self.get_block(&old_hash)?);
+                        old_hash = old_index.prev_hash;
+                    } else {
+                        connected.push(self.get_block(&new_hash)?);
+                        new_hash = new_index.prev_hash;
+                    }
+                }
+                _ => return Err(PokError::Chain(format!("blocks {} and {} share no ancestor", old_tip, new_tip))),
+            }
+        }
+        connected.reverse();
+        Ok((disconnected, connected))
+    }
+
+    fn set_tip(&mut self, block_hash: &str, cumulative_kills: u128) -> Result<()> {
+        self.db.insert("LAST", block_hash.as_bytes())?;
+        self.db.flush()?;
+        self.tip = block_hash.to_owned();
+        self.kills = cumulative_kills;
+        if let Some(index) = self.get_block_index(block_hash)? {
+            self.update_best_header(block_hash, &index)?;
         }
         Ok(())
     }
 
+    /// moves best_header to block_hash if its chain ranks above the current best one.
+    ///
+    /// the kills of a header are claims until the fights of its body are replayed,
+    /// so unless both ends have their body the longer chain wins, whatever kills it claims.
+    fn update_best_header(&mut self, block_hash: &str, index: &BlockIndex) -> Result<()> {
+        let better = match self.get_block_index(&self.best_header)? {
+            Some(best) if self.has_block(block_hash)? && self.has_block(&self.best_header)? => {
+                index.is_heavier_than(&best)
+            }
+            Some(best) => index.height > best.height,
+            None => true,
+        };
+        if better {
+            self.best_header = block_hash.to_owned();
+        }
+        Ok(())
+    }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        
-        info!("mine a new block");
+    /// forgets the header chain ahead of tip, e.g. once one of its bodies turned out invalid
+    pub fn reset_best_header(&mut self) {
+        self.best_header = self.tip.clone();
+    }
+
+    /// returns true if the body of the block is stored
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.db.contains_key(block_hash)?)
+    }
+
+    /// returns the header of a block whose body is stored or whose header was accepted
+    pub fn get_header(&self, block_hash: &str) -> Result<BlockHeader> {
+        if let Some(header) = self.db.open_tree(HEADER_TREE)?.get(block_hash)? {
+            return Ok(deserialize(&header)?);
+        }
+        Ok(self.get_block(block_hash)?.get_header().clone())
+    }
+
+    /// accept_header() validates a header received ahead of its body, indexes it and returns its hash.
+    ///
+    /// the header has to extend a known header, its transactions and fights are checked
+    /// by validate_block once the body is downloaded.
+    pub fn accept_header(&mut self, header: &BlockHeader) -> Result<String> {
+        let block_hash = header.hash()?;
+        if self.get_block_index(&block_hash)?.is_some() {
+            return Ok(block_hash);
+        }
+        self.validate_header(&block_hash, header)?;
+        self.db
+            .open_tree(HEADER_TREE)?
+            .insert(block_hash.as_str(), serialize(header)?)?;
+        let index = self.store_index(&block_hash, header)?;
+        self.update_best_header(&block_hash, &index)?;
+        Ok(block_hash)
+    }
+
+    /// hashes of the main chain, from genesis to tip
+    fn main_chain(&self) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        let mut hash = self.tip.clone();
+        while !hash.is_empty() {
+            let index = match self.get_block_index(&hash)? {
+                Some(index) => index,
+                None => return Err(PokError::NotFound(format!("index of block {}", hash))),
+            };
+            hashes.push(hash);
+            hash = index.prev_hash;
+        }
+        hashes.reverse();
+        Ok(hashes)
+    }
+
+    /// locator() describes the chain ending at block_hash to a peer.
+    ///
+    /// it lists the last LOCATOR_DENSE_LEN blocks, then steps back twice as far each time,
+    /// and always ends with genesis, so the peer finds the fork point with few hashes.
+    pub fn locator(&self, block_hash: &str) -> Result<Vec<String>> {
+        let mut locator = Vec::new();
+        let mut hash = block_hash.to_owned();
+        let mut step = 1;
+        while !hash.is_empty() {
+            locator.push(hash.clone());
+            if locator.len() >= LOCATOR_DENSE_LEN {
+                step *= 2;
+            }
+            let mut next = hash.clone();
+            for _ in 0..step {
+                match self.get_block_index(&next)? {
+                    Some(index) if !index.prev_hash.is_empty() => next = index.prev_hash,
+                    _ => break,
+                }
+            }
+            if next == hash {
+                break;
+            }
+            hash = next;
+        }
+        Ok(locator)
+    }
+
+    /// headers_after() returns up to max headers of the main chain following the
+    /// first locator hash found on it, stopping after stop.
+    ///
+    /// a locator sharing no block with the main chain gets headers from genesis.
+    pub fn headers_after(&self, locator: &[String], stop: &str, max: usize) -> Result<Vec<BlockHeader>> {
+        let main_chain = self.main_chain()?;
+        let positions: HashMap<&String, usize> = main_chain.iter().enumerate().map(|(i, h)| (h, i)).collect();
+        let start = locator
+            .iter()
+            .find_map(|hash| positions.get(hash))
+            .map_or(0, |pos| pos + 1);
+        let mut headers = Vec::new();
+        for hash in main_chain.iter().skip(start).take(max) {
+            headers.push(self.get_header(hash)?);
+            if hash == stop {
+                break;
+            }
+        }
+        Ok(headers)
+    }
+
+    /// hashes and heights of the blocks between tip and the best header
+    /// whose bodies are missing, parents first
+    pub fn missing_blocks(&self) -> Result<Vec<(String, u128)>> {
+        let mut missing = Vec::new();
+        let mut hash = self.best_header.clone();
+        while !hash.is_empty() && !self.has_block(&hash)? {
+            let index = match self.get_block_index(&hash)? {
+                Some(index) => index,
+                None => return Err(PokError::NotFound(format!("index of block {}", hash))),
+            };
+            missing.push((hash, index.height));
+            hash = index.prev_hash;
+        }
+        missing.reverse();
+        Ok(missing)
+    }
+
+    /// returns where a stored block sits in the block tree, None if it was never indexed
+    pub fn get_block_index(&self, block_hash: &str) -> Result<Option<BlockIndex>> {
+        match self.db.open_tree(INDEX_TREE)?.get(block_hash)? {
+            Some(index) => Ok(Some(deserialize(&index)?)),
+            None => Ok(None),
+        }
+    }
 
+    /// indexes a block whose parent is already indexed
+    fn store_block_index(&self, block: &Block) -> Result<BlockIndex> {
+        self.store_index(&block.get_hash(), block.get_header())
+    }
+
+    fn store_index(&self, block_hash: &str, header: &BlockHeader) -> Result<BlockIndex> {
+        let parent_kills = if header.prev_block_hash.is_empty() {
+            0
+        } else {
+            match self.get_block_index(&header.prev_block_hash)? {
+                Some(parent) => parent.cumulative_kills,
+                None => return Err(PokError::NotFound(format!("index of block {}", header.prev_block_hash))),
+            }
+        };
+        let index = BlockIndex {
+            prev_hash: header.prev_block_hash.clone(),
+            height: header.height,
+            cumulative_kills: parent_kills + header.kills as u128,
+        };
+        self.db
+            .open_tree(INDEX_TREE)?
+            .insert(block_hash, serialize(&index)?)?;
+        Ok(index)
+    }
+
+    /// records the outputs spent by a block, looking them up in the block itself
+    /// and in the chain leading to it
+    fn store_block_undo(&self, block: &Block) -> Result<()> {
+        let mut undo = BlockUndo::default();
+        for (tx_index, tx) in block.get_transaction().iter().enumerate() {
+            let mut spent = Vec::new();
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let prev_tx = match block.get_transaction()[..tx_index].iter().find(|t| t.id == vin.txid) {
+                        Some(t) => t.clone(),
+                        None => self.find_transacton_from(&block.get_prev_hash(), &vin.txid)?,
+                    };
+                    let output = match prev_tx.vout.get(vin.vout as usize) {
+                        Some(output) => output.clone(),
+                        None => return Err(PokError::NotFound(format!("output {}:{}", vin.txid, vin.vout))),
+                    };
+                    spent.push(SpentOutput {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                        output,
+                    });
+                }
+            }
+            undo.spent.push(spent);
+        }
+        self.db
+            .open_tree(UNDO_TREE)?
+            .insert(block.get_hash(), serialize(&undo)?)?;
+        Ok(())
+    }
+
+    /// returns the outputs spent by a stored block
+    pub fn get_block_undo(&self, block_hash: &str) -> Result<BlockUndo> {
+        match self.db.open_tree(UNDO_TREE)?.get(block_hash)? {
+            Some(undo) => Ok(deserialize(&undo)?),
+            None => Err(PokError::NotFound(format!("undo data of block {}", block_hash))),
+        }
+    }
+
+    /// disconnect_tip() moves the tip back to its parent and returns the disconnected block.
+    ///
+    /// the block stays stored, the UTXO set has to be reverted by the caller.
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        let block = self.get_block(&self.tip)?;
+        if block.get_prev_hash().is_empty() {
+            return Err(PokError::Chain(String::from("genesis block can not be disconnected")));
+        }
+        let parent_kills = match self.get_block_index(&block.get_prev_hash())? {
+            Some(index) => index.cumulative_kills,
+            None => return Err(PokError::NotFound(format!("index of block {}", block.get_prev_hash()))),
+        };
+        self.set_tip(&block.get_prev_hash(), parent_kills)?;
+        Ok(block)
+    }
+
+    /// repair_kills() rebuilds the index entries of every stored block, cumulative kills included,
+    /// and returns the kills of the current tip.
+    ///
+    /// headers accepted ahead of their body keep their entries, indexed again on top of the repaired blocks.
+    pub fn repair_kills(&mut self) -> Result<u128> {
+        let mut headers = Vec::new();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            if k == "LAST" || k == DB_VERSION_KEY {
+                continue;
+            }
+            match deserialize::<Block>(&v) {
+                Ok(block) if block.get_hash().as_bytes() == &*k => {
+                    headers.push((block.get_hash(), block.get_header().clone()))
+                }
+                _ => warn!("skip entry {}, it is not a block", String::from_utf8_lossy(&k)),
+            }
+        }
+        for kv in self.db.open_tree(HEADER_TREE)?.iter() {
+            let (k, v) = kv?;
+            let block_hash = String::from_utf8(k.to_vec())?;
+            if !self.has_block(&block_hash)? {
+                headers.push((block_hash, deserialize::<BlockHeader>(&v)?));
+            }
+        }
+        //parents always sit one height below their children
+        headers.sort_by_key(|(_, header)| header.height);
+
+        self.db.drop_tree(KILLS_TREE)?;
+        for (block_hash, header) in &headers {
+            if let Err(e) = self.store_index(block_hash, header) {
+                warn!("skip block {}: {}", block_hash, e);
+            }
+        }
+        self.db.flush()?;
+
+        self.kills = match self.get_block_index(&self.tip)? {
+            Some(index) => index.cumulative_kills,
+            None => 0,
+        };
+        Ok(self.kills)
+    }
+
+
+    /// validate_header() checks the fields of a header against its parent header
+    fn validate_header(&self, block_hash: &str, header: &BlockHeader) -> Result<()> {
+        if header.version != BLOCK_VERSION {
+            return Err(BlockError::UnsupportedVersion(header.version).into());
+        }
+        //kills lower the target, so they are bounded before the target is checked
+        if header.chance != CHANCE {
+            return Err(BlockError::BadChance(header.chance).into());
+        }
+        if header.kills > header.chance {
+            return Err(BlockError::TooManyKills(header.kills, header.chance).into());
+        }
+        if !header.meets_target(block_hash) {
+            return Err(BlockError::TargetNotMet(block_hash.to_owned()).into());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::BadTimestamp(header.timestamp).into());
+        }
+
+        //genesis has no parent to check against
+        if !header.prev_block_hash.is_empty() || header.height != 0 {
+            if self.get_block_index(&header.prev_block_hash)?.is_none() {
+                return Err(BlockError::UnknownParent(header.prev_block_hash.clone()).into());
+            }
+            let parent = self.get_header(&header.prev_block_hash)?;
+            if header.height != parent.height + 1 {
+                return Err(BlockError::BadHeight(header.height, parent.height).into());
+            }
+            if header.timestamp < parent.timestamp {
+                return Err(BlockError::BadTimestamp(header.timestamp).into());
+            }
+        } else if !self.tip.is_empty() {
+            return Err(BlockError::ForeignGenesis(block_hash.to_owned()).into());
+        }
+
+        let expected_bits = self.next_bits(&header.prev_block_hash)?;
+        if header.bits != expected_bits {
+            return Err(BlockError::BadDifficulty(header.bits, expected_bits).into());
+        }
+        Ok(())
+    }
+
+    /// validate_block() checks a block received from a peer before it gets stored.
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        if block.calculate_hash()? != block.get_hash() {
+            return Err(BlockError::BadHash(block.get_hash()).into());
+        }
+        self.validate_header(&block.get_hash(), block.get_header())?;
+        //the outputs spent by the block are looked up in the body of its parent chain
+        if !block.get_prev_hash().is_empty() && !self.has_block(&block.get_prev_hash())? {
+            return Err(BlockError::UnknownParent(block.get_prev_hash()).into());
+        }
+        if !block.verify_roots()? {
+            return Err(BlockError::BadRoots.into());
+        }
+        let size = block.transactions_size()?;
+        if size > MAX_BLOCK_TX_BYTES {
+            return Err(BlockError::TooLarge(size).into());
+        }
+
+        let mut coinbase_value: i64 = 0;
+        let mut coinbase_count = 0;
+        let mut fees: i64 = 0;
+        let inputs = self.resolve_inputs(block)?;
+        for tx in block.get_transaction() {
+            if tx.is_coinbase() {
+                coinbase_count += 1;
+                coinbase_value = tx.vout.iter().map(|out| i64::from(out.value)).sum();
+                continue;
+            }
+            match verify_spend(tx, &inputs, block.get_height()) {
+                Ok(true) => {}
+                _ => return Err(BlockError::InvalidTransaction(tx.id.clone()).into()),
+            }
+            let prev_txs: HashMap<String, Transaction> = tx
+                .vin
+                .iter()
+                .filter_map(|vin| inputs.get(&vin.txid))
+                .map(|(prev_tx, _)| (prev_tx.id.clone(), prev_tx.clone()))
+                .collect();
+            match tx.fee(&prev_txs) {
+                Ok(fee) => fees += i64::from(fee),
+                Err(_) => return Err(BlockError::InvalidTransaction(tx.id.clone()).into()),
+            }
+        }
+        if coinbase_count != 1 {
+            return Err(BlockError::CoinbaseCount(coinbase_count).into());
+        }
+        //the coinbase collects the fees of the block on top of the subsidy of its height
+        let reward = i64::from(subsidy(block.get_height())) + fees;
+        if coinbase_value != reward {
+            return Err(BlockError::BadCoinbaseValue(coinbase_value, reward).into());
+        }
+
+        if !block.verify_fights()? {
+            return Err(BlockError::BadFights.into());
+        }
+        Ok(())
+    }
+
+    /// resolve_inputs() looks up the transactions holding the outputs spent by block,
+    /// in the chain ending at its parent or earlier in the block itself.
+    ///
+    /// returns them along with the height of their block. an output spent twice by the block,
+    /// or one the parent chain never created or already spent, is an error.
+    fn resolve_inputs(&self, block: &Block) -> Result<HashMap<String, (Transaction, u128)>> {
+        let mut inputs = HashMap::new();
+        //outputs still to be found in the parent chain, by the id of their transaction
+        let mut wanted: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut spent = HashSet::new();
+        let transactions = block.get_transaction();
+        for (tx_index, tx) in transactions.iter().enumerate() {
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if !spent.insert((vin.txid.clone(), vin.vout)) {
+                    return Err(BlockError::DoubleSpend(vin.txid.clone(), vin.vout).into());
+                }
+                match transactions[..tx_index].iter().find(|t| t.id == vin.txid) {
+                    Some(prev_tx) => {
+                        inputs.insert(prev_tx.id.clone(), (prev_tx.clone(), block.get_height()));
+                    }
+                    None => wanted.entry(vin.txid.clone()).or_default().push(vin.vout),
+                }
+            }
+        }
+
+        //walking back from the parent, an output is spent if a block above its own spent it
+        let mut hash = block.get_prev_hash();
+        while !wanted.is_empty() && !hash.is_empty() {
+            let ancestor = self.get_block(&hash)?;
+            for spent in self.get_block_undo(&hash)?.spent.iter().flatten() {
+                if wanted.get(&spent.txid).is_some_and(|vouts| vouts.contains(&spent.vout)) {
+                    return Err(BlockError::MissingOutput(spent.txid.clone(), spent.vout).into());
+                }
+            }
+            for tx in ancestor.get_transaction() {
+                if wanted.remove(&tx.id).is_some() {
+                    inputs.insert(tx.id.clone(), (tx.clone(), ancestor.get_height()));
+                }
+            }
+            hash = ancestor.get_prev_hash();
+        }
+        if let Some((txid, vouts)) = wanted.into_iter().next() {
+            return Err(BlockError::MissingOutput(txid, vouts[0]).into());
+        }
+        Ok(inputs)
+    }
+
+    /// next_bits() returns the difficulty a block on top of parent_hash has to carry.
+    ///
+    /// every RETARGET_INTERVAL blocks the time taken by the previous interval is compared
+    /// to TARGET_BLOCK_TIME, one bit is added if blocks came more than twice as fast
+    /// and one is removed if they came more than twice as slow.
+    pub fn next_bits(&self, parent_hash: &str) -> Result<u32> {
+        if parent_hash.is_empty() {
+            return Ok(INITIAL_BITS);
+        }
+        let parent = self.get_header(parent_hash)?;
+        let height = parent.height + 1;
+        if height % RETARGET_INTERVAL != 0 {
+            return Ok(parent.bits);
+        }
+
+        let mut first = parent.clone();
+        while first.height > height - RETARGET_INTERVAL {
+            first = self.get_header(&first.prev_block_hash)?;
+        }
+        let actual = parent.timestamp - first.timestamp;
+        let expected = TARGET_BLOCK_TIME * (parent.height - first.height);
+        let bits = if actual < expected / 2 {
+            parent.bits + 1
+        } else if actual > expected * 2 {
+            parent.bits.saturating_sub(1)
+        } else {
+            parent.bits
+        };
+        Ok(bits.clamp(MIN_BITS, MAX_BITS))
+    }
+
+    /// block_template() checks transactions and puts them into a block on top of the tip,
+    /// with the coinbase that rewards ctx. mining it needs no access to the chain.
+    pub fn block_template(&self, ctx: &mut MinerContext, mut transactions: Vec<Transaction>) -> Result<BlockTemplate> {
+        let mut fees = 0;
+        let mut size = 0;
         for tx in &transactions {
             if !self.verify_transacton(tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
+                return Err(PokError::InvalidTransaction(tx.id.clone()));
             }
+            fees += self.transaction_fee(tx)?;
+            size += tx.size()?;
+        }
+        if size > MAX_BLOCK_TX_BYTES {
+            return Err(BlockError::TooLarge(size).into());
         }
 
-        
-        let last_hash = self.db.get("LAST")?.unwrap();
+        let last_hash = match self.db.get("LAST")? {
+            Some(last_hash) => last_hash,
+            None => return Err(PokError::Chain(String::from("blockchain has no tip to mine on"))),
+        };
 
-        //this will start dogfight() to each of transaction with own agent.
-        let newblock = Block::new_block(
+        let last_hash = String::from_utf8(last_hash.to_vec())?;
+        let bits = self.next_bits(&last_hash)?;
+        let height = self.get_best_height()? + 1;
+        transactions.push(ctx.coinbase(String::new(), subsidy(height) + fees)?);
+        Ok(BlockTemplate {
             transactions,
-            String::from_utf8(last_hash.to_vec())?,
-            self.get_best_height()? + 1,
-        )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
+            prev_hash: last_hash,
+            height,
+            bits,
+        })
+    }
 
-        self.tip = newblock.get_hash();
+    /// mine_block() mines transactions into a new block on top of the tip,
+    /// adding the coinbase that rewards ctx.
+    pub fn mine_block(&mut self, ctx: &mut MinerContext, transactions: Vec<Transaction>) -> Result<Block> {
+        
+        info!("mine a new block");
+
+        let newblock = self.block_template(ctx, transactions)?.mine(ctx)?;
+        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
+        let index = self.store_block_index(&newblock)?;
+        self.store_block_undo(&newblock)?;
+        self.set_tip(&newblock.get_hash(), index.cumulative_kills)?;
         Ok(newblock)
     }
 
+    /// returns the coins a block of the main chain created:
+    /// what its transactions pay out beyond the outputs they spend
+    pub fn block_emission(&self, block: &Block) -> Result<i64> {
+        let undo = self.get_block_undo(&block.get_hash())?;
+        let paid: i64 = block
+            .get_transaction()
+            .iter()
+            .flat_map(|tx| tx.vout.iter())
+            .map(|out| i64::from(out.value))
+            .sum();
+        let spent: i64 = undo
+            .spent
+            .iter()
+            .flatten()
+            .map(|spent| i64::from(spent.output.value))
+            .sum();
+        Ok(paid - spent)
+    }
+
+    /// returns the coins created by the main chain, from its tip down to genesis
+    pub fn emitted_supply(&self) -> Result<i64> {
+        let mut supply = 0;
+        for block in self.iter() {
+            supply += self.block_emission(&block)?;
+        }
+        Ok(supply)
+    }
+
+    /// returns the transactions of the main chain touching pub_key_hashes, newest first.
+    /// with since, only those of blocks above that height are returned
+    pub fn history(&self, pub_key_hashes: &HashSet<Vec<u8>>, since: Option<u128>) -> Result<Vec<HistoryEntry>> {
+        let mut history = Vec::new();
+        for block in self.iter() {
+            if since.is_some_and(|since| block.get_height() <= since) {
+                break;
+            }
+            let block_hash = block.get_hash();
+            let undo = self.get_block_undo(&block_hash)?;
+            for (i, tx) in block.get_transaction().iter().enumerate().rev() {
+                let received: i64 = tx
+                    .vout
+                    .iter()
+                    .filter(|out| pub_key_hashes.contains(&out.pub_key_hash))
+                    .map(|out| i64::from(out.value))
+                    .sum();
+                let sent: i64 = undo
+                    .spent
+                    .get(i)
+                    .into_iter()
+                    .flatten()
+                    .filter(|spent| pub_key_hashes.contains(&spent.output.pub_key_hash))
+                    .map(|spent| i64::from(spent.output.value))
+                    .sum();
+                if received != 0 || sent != 0 {
+                    history.push(HistoryEntry {
+                        height: block.get_height(),
+                        block_hash: block_hash.clone(),
+                        txid: tx.id.clone(),
+                        received,
+                        sent,
+                    });
+                }
+            }
+        }
+        Ok(history)
+    }
+
     /// Iterator returns a BlockchainIterat
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
             current_hash: self.tip.clone(),
-            blockchain: &self,
+            blockchain: self,
         }
     }
 
@@ -146,31 +1043,16 @@ impl Blockchain {
                         }
                     }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
-                    }
+                    utxos
+                        .entry(tx.id.clone())
+                        .or_insert_with(|| TXOutputs::of(tx, block.get_height()))
+                        .outputs
+                        .insert(index as i32, tx.vout[index].clone());
                 }
 
                 if !tx.is_coinbase() {
                     for i in &tx.vin {
-                        match spend_txos.get_mut(&i.txid) {
-                            Some(v) => {
-                                v.push(i.vout);
-                            }
-                            None => {
-                                spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                            }
-                        }
+                        spend_txos.entry(i.txid.clone()).or_default().push(i.vout);
                     }
                 }
             }
@@ -179,18 +1061,47 @@ impl Blockchain {
         utxos
     }
 
+    /// returns the public key hashes paid by any output of the main chain, spent or not
+    pub fn used_pub_key_hashes(&self) -> HashSet<Vec<u8>> {
+        let mut used = HashSet::new();
+        for block in self.iter() {
+            for tx in block.get_transaction() {
+                for out in &tx.vout {
+                    used.insert(out.pub_key_hash.clone());
+                }
+            }
+        }
+        used
+    }
+
     /// FindTransaction finds a transaction by its ID
     pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
-        for b in self.iter() {
+        self.find_transacton_from(&self.tip, id)
+    }
+
+    /// finds a transaction in the chain ending at block_hash
+    fn find_transacton_from(&self, block_hash: &str, id: &str) -> Result<Transaction> {
+        Ok(self.locate_transaction(block_hash, id)?.0)
+    }
+
+    /// finds a transaction in the chain ending at block_hash,
+    /// returns it with the height of the block holding it
+    pub fn locate_transaction(&self, block_hash: &str, id: &str) -> Result<(Transaction, u128)> {
+        let iter = BlockchainIterator {
+            current_hash: block_hash.to_owned(),
+            blockchain: self,
+        };
+        for b in iter {
             for tx in b.get_transaction() {
                 if tx.id == id {
-                    return Ok(tx.clone());
+                    return Ok((tx.clone(), b.get_height()));
                 }
             }
         }
-        Err(format_err!("Transaction is not found"))
+        Err(PokError::NotFound(format!("transaction {}", id)))
     }
 
+    /// transactions of the main chain tx spends from, blocks are checked with resolve_inputs instead
     fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_txs = HashMap::new();
         for vin in &tx.vin {
@@ -207,19 +1118,36 @@ impl Blockchain {
         Ok(())
     }
 
-    /// VerifyTransaction verifies transaction input signatures
+    /// returns the fee tx pays on top of the tip, the outputs it spends are looked up in the main chain
+    pub fn transaction_fee(&self, tx: &Transaction) -> Result<i32> {
+        if tx.is_coinbase() {
+            return Ok(0);
+        }
+        tx.fee(&self.get_prev_txs(tx)?)
+    }
+
+    /// VerifyTransaction verifies transaction input signatures,
+    /// and that the coinbase outputs spent can go in the block on top of the tip
     pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool> {
         if tx.is_coinbase() {
             return Ok(true);
         }
-        let prev_txs = self.get_prev_txs(tx)?;
-        tx.verify(prev_txs)
+        let spend_height = self.get_best_height()?.wrapping_add(1);
+        let mut inputs = HashMap::new();
+        for vin in &tx.vin {
+            let (prev_tx, height) = self.locate_transaction(&self.tip, &vin.txid)?;
+            inputs.insert(prev_tx.id.clone(), (prev_tx, height));
+        }
+        verify_spend(tx, &inputs, spend_height)
     }
 
     /// GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
+        let data = match self.db.get(block_hash)? {
+            Some(data) => data,
+            None => return Err(PokError::NotFound(format!("block {}", block_hash))),
+        };
+        let block = deserialize(&data)?;
         Ok(block)
     }
 
@@ -231,23 +1159,13 @@ impl Blockchain {
         } else {
             return Ok(u128::MAX);
         };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
+        let last_block = self.get_block(&String::from_utf8(lasthash.to_vec())?)?;
         Ok(last_block.get_height())
     }
 
     pub fn get_kills(&self) -> u128 {
         self.kills
     }
-
-    /// GetBlockHashes returns a list of hashes of all the blocks in the chain
-    pub fn get_block_hashs(&self) -> Vec<String> {
-        let mut list = Vec::new();
-        for b in self.iter() {
-            list.push(b.get_hash());
-        }
-        list
-    }
 }
 
 impl<'a> Iterator for BlockchainIterator<'a> {
@@ -271,7 +1189,24 @@ impl<'a> Iterator for BlockchainIterator<'a> {
     }
 }
 
+/// verifies the signatures of tx against the transactions it spends from, found in inputs
+/// with their heights. a coinbase output spent before it matured makes the transaction invalid
+fn verify_spend(tx: &Transaction, inputs: &HashMap<String, (Transaction, u128)>, spend_height: u128) -> Result<bool> {
+    let mut prev_txs = HashMap::new();
+    for vin in &tx.vin {
+        let (prev_tx, height) = match inputs.get(&vin.txid) {
+            Some(input) => input,
+            None => return Err(PokError::NotFound(format!("transaction {}", vin.txid))),
+        };
+        if !TXOutputs::of(prev_tx, *height).is_mature(spend_height) {
+            return Ok(false);
+        }
+        prev_txs.insert(prev_tx.id.clone(), prev_tx.clone());
+    }
+    tx.verify(prev_txs)
+}
+
 ///Returns true if db_path points at an existing entity.
-pub fn Is_db_exists(db_path: &str) -> bool {
-    std::path::Path::new(db_path).exists()
+pub fn is_db_exists(db_path: &Path) -> bool {
+    db_path.exists()
 }
\ No newline at end of file
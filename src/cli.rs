@@ -1,17 +1,22 @@
 //! cli process
 
 use super::*;
-use crate::blockchain::*;
-use crate::server::*;
-use crate::transaction::*;
-use crate::utxoset::*;
-use crate::agent::*;
-use bitcoincash_addr::Address;
+use proof_of_kill::blockchain::*;
+use proof_of_kill::config::*;
+use proof_of_kill::miner::MinerContext;
+use proof_of_kill::protocol::{Message, Unlockmsg};
+use proof_of_kill::server::*;
+use proof_of_kill::transaction::*;
+use proof_of_kill::utxoset::*;
+use proof_of_kill::agent::*;
+use proof_of_kill::PokError;
 use clap::{App, Arg};
 use std::process::exit;
+use std::collections::HashSet;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{thread, time};
-use std::env;
 
 pub struct Cli {}
 
@@ -22,42 +27,101 @@ impl Cli {
 
     pub fn run(&mut self) -> Result<()> {
         info!("run app");
-        let node_id = match env::var("NODE_ID") {
-            Ok(id) => id,
-            Err(err) => {
-                println!("environment variable 'NODE_ID' should be set properly.\nuse 'export NODE_ID=xxxx' to set\n{:?}",err);
-                panic!() 
-            },
-        };
-        
         let matches = App::new("proof-of-kill-demo-version")
             .version("0.0.1")
             .author("ntswamp <nterheoid@gmail.com>")
             .about("a demonstration of PoK(Proof-of-Kill) consensus model")
+            .arg(Arg::from_usage("--config [file] 'config file to read, pok.toml by default'").global(true))
+            .arg(Arg::from_usage("--node-id [id] 'id of the node, also its default port'").global(true))
+            .arg(Arg::from_usage("--data-dir [dir] 'directory holding the node data'").global(true))
+            .arg(Arg::from_usage("--listen [addr] 'address the node server listens on'").global(true))
+            .arg(
+                Arg::from_usage("--seed [addr]... 'peer to connect to on start'")
+                    .number_of_values(1)
+                    .global(true),
+            )
+            .arg(
+                Arg::from_usage("--network [name] 'network to join, mainnet by default'")
+                    .possible_values(&["mainnet", "testnet", "regtest"])
+                    .global(true),
+            )
             .subcommand(App::new("chain").about("print out current state of blockchain"))
             .subcommand(App::new("newagent").about("(re)create an agent to start collecting coins!"))
-            .subcommand(App::new("agent").about("show agent stats"))
+            .subcommand(
+                App::new("agent")
+                    .about("show agent stats")
+                    .subcommand(
+                        App::new("export")
+                            .about("write your agent to a backup file")
+                            .arg(Arg::from_usage("<file> 'backup file to write'"))
+                            .arg(Arg::from_usage("-e --encrypt 'encrypt the backup file with a passphrase'")),
+                    )
+                    .subcommand(
+                        App::new("import")
+                            .about("make the agent in a backup file the agent of this node")
+                            .arg(Arg::from_usage("<file> 'backup file to read'"))
+                            .arg(Arg::from_usage("--merge 'add the keys of the backup to the agent of this node'"))
+                            .arg(
+                                Arg::from_usage("--replace 'replace the agent of this node'")
+                                    .conflicts_with("merge"),
+                            ),
+                    ),
+            )
+            .subcommand(App::new("restore").about("(re)create your agent from its mnemonic, finding the addresses it used"))
             .subcommand(App::new("newaddr").about("create an address for your agent"))
             .subcommand(App::new("addr").about("list all addresses held by your agent"))
+            .subcommand(
+                App::new("watch")
+                    .about("watch an address without its secret key, counting it in balances and history")
+                    .arg(Arg::from_usage("<entry> 'address, or public key in hex, to watch'")),
+            )
+            .subcommand(
+                App::new("unwatch")
+                    .about("stop watching an address")
+                    .arg(Arg::from_usage("<address> 'watch-only address to drop'")),
+            )
+            .subcommand(
+                App::new("unlock")
+                    .about("unlock the keys of the agent of the running node for a while")
+                    .arg(Arg::from_usage("--timeout [secs] 'seconds the agent stays unlocked, 300 by default'")),
+            )
+            .subcommand(App::new("lock").about("lock the keys of the agent of the running node"))
+            .subcommand(App::new("passwd").about("change the passphrase encrypting the keys of your agent"))
             .subcommand(App::new("reindex").about("reindex UTXO"))
+            .subcommand(App::new("supply").about("compare the coins emitted by the blockchain to the subsidy schedule"))
+            .subcommand(
+                App::new("rollback")
+                    .about("disconnect blocks from the tip of the blockchain")
+                    .arg(Arg::from_usage("<n> 'number of blocks to disconnect'")),
+            )
+            .subcommand(App::new("repairkills").about("recompute cumulative kills of the blockchain"))
+            .subcommand(App::new("migrate").about("upgrade blockchain database to the current block format"))
             .subcommand(
                 App::new("startnode")
                     .about("start the node server")
-                    .arg(Arg::from_usage("<port> 'the port server bind to locally'")),
+                    .arg(Arg::from_usage("[port] 'the port server bind to locally, unless a listen address is configured'")),
             )
             .subcommand(
                 App::new("startminer")
                     .about("start the minner server")
-                    .arg(Arg::from_usage("<port> 'the port server bind to locally'"))
+                    .arg(Arg::from_usage("<port> 'the port server bind to locally, unless a listen address is configured'"))
                     .arg(Arg::from_usage("<address> 'wallet address'")),
             )
             .subcommand(
                 App::new("bal")
                     .about("get balance in the blockchain")
                     .arg(Arg::from_usage(
-                        "<address> 'The address to get balance for'",
+                        "[address] 'The address to get balance for, every address of your agent by default'",
                     )),
             )
+            .subcommand(
+                App::new("history")
+                    .about("list the transactions paying to or spending from addresses")
+                    .arg(Arg::from_usage(
+                        "[address] 'The address to list transactions for, every address of your agent by default'",
+                    ))
+                    .arg(Arg::from_usage("--since [height] 'only list transactions of blocks above height'")),
+            )
             .subcommand(App::new("initdb").about("initialize blockchain database").arg(
                 Arg::from_usage("<address> 'The address to send genesis block reward to'"),
             ))
@@ -69,33 +133,205 @@ impl Cli {
                     .arg(Arg::from_usage("<amount> 'Amount To Send'"))
                     .arg(Arg::from_usage(
                         "-m --mine 'Let The From Address Mine Immediately'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--fee-rate [rate] 'fee in coins per 1000 bytes of the transaction, 1 by default'",
                     )),
             )
             .get_matches();
 
-        if let Some(ref matches) = matches.subcommand_matches("bal") {
+        let overrides = ConfigOverrides {
+            node_id: matches.value_of("node-id").map(String::from),
+            data_dir: matches.value_of("data-dir").map(PathBuf::from),
+            listen_addr: matches.value_of("listen").map(String::from),
+            seed_peers: matches.values_of("seed").map(|peers| peers.map(String::from).collect()),
+            network: matches.value_of("network").map(str::parse).transpose()?,
+            keystore_log_n: None,
+        };
+        //the port of startnode and startminer only counts when no flag, variable or file sets a listen address
+        let port = matches
+            .subcommand_matches("startnode")
+            .or_else(|| matches.subcommand_matches("startminer"))
+            .and_then(|matches| matches.value_of("port"));
+        let fallback = ConfigOverrides {
+            listen_addr: port.map(|port| String::from("localhost:") + port),
+            ..Default::default()
+        };
+        let config = NodeConfig::load(overrides, matches.value_of("config").map(Path::new), fallback)?;
+
+        if let Some(matches) = matches.subcommand_matches("bal") {
             if let Some(address) = matches.value_of("address") {
-                let balance = cmd_bal(address)?;
-                println!("Balance: {}\n", balance);
+                let balance = cmd_bal(&config, address)?;
+                println!("Balance: {}", balance.spendable);
+                println!("Immature: {}\n", balance.immature);
+            } else {
+                let mut total = Balance::default();
+                let mut watched = Balance::default();
+                for (address, watch_only, balance) in cmd_agent_bal(&config)? {
+                    println!(
+                        "{}: {} (immature {}){}",
+                        address,
+                        balance.spendable,
+                        balance.immature,
+                        if watch_only { " watch-only" } else { "" }
+                    );
+                    if watch_only {
+                        watched.spendable += balance.spendable;
+                        watched.immature += balance.immature;
+                    }
+                    total.spendable += balance.spendable;
+                    total.immature += balance.immature;
+                }
+                println!("Balance: {}", total.spendable);
+                println!("Immature: {}", total.immature);
+                println!("of which watch-only: {} (immature {})\n", watched.spendable, watched.immature);
             }
-        } else if let Some(_) = matches.subcommand_matches("newagent") {
-            println!("address: {}", cmd_newagent()?);
-        } else if let Some(_) = matches.subcommand_matches("agent") {
-            cmd_agent()?;
-        } else if let Some(_) = matches.subcommand_matches("newaddr") {
-            cmd_newaddr()?;
-        } else if let Some(_) = matches.subcommand_matches("addr") {
-            cmd_addr()?;
-        } else if let Some(_) = matches.subcommand_matches("chain") {
-            cmd_chain()?;
-        } else if let Some(_) = matches.subcommand_matches("reindex") {
-            let count = cmd_reindex()?;
+        } else if let Some(matches) = matches.subcommand_matches("history") {
+            let since = match matches.value_of("since") {
+                Some(height) => Some(
+                    height
+                        .parse()
+                        .map_err(|_| PokError::InvalidArgument(format!("{} is not a height", height)))?,
+                ),
+                None => None,
+            };
+            for entry in cmd_history(&config, matches.value_of("address"), since)? {
+                println!(
+                    "height {} tx {}: received {}, sent {}",
+                    entry.height, entry.txid, entry.received, entry.sent
+                );
+            }
+        } else if matches.subcommand_matches("newagent").is_some() {
+            println!("address: {}", cmd_newagent(&config)?);
+        } else if let Some(matches) = matches.subcommand_matches("agent") {
+            if let Some(matches) = matches.subcommand_matches("export") {
+                let path = Path::new(matches.value_of("file").unwrap_or_default());
+                let passphrase = read_agent_passphrase(&config)?;
+                let backup_passphrase = if matches.is_present("encrypt") {
+                    Some(read_new_passphrase()?)
+                } else {
+                    None
+                };
+                let count = cmd_export(&config, path, backup_passphrase.as_deref(), &passphrase)?;
+                println!("{} addresses written to {}.", count, path.display());
+            } else if let Some(matches) = matches.subcommand_matches("import") {
+                let path = Path::new(matches.value_of("file").unwrap_or_default());
+                let mode = if matches.is_present("merge") {
+                    ImportMode::Merge
+                } else if matches.is_present("replace") {
+                    ImportMode::Replace
+                } else {
+                    ImportMode::New
+                };
+                let exists = is_agent_exists(&config.agent_path());
+                if exists && mode == ImportMode::New {
+                    return Err(PokError::AgentExists);
+                }
+                let backup_passphrase = if Backup::is_encrypted(path)? {
+                    Some(read_passphrase("passphrase of the backup:")?)
+                } else {
+                    None
+                };
+                //a merged backup takes the passphrase of the agent it joins
+                let passphrase = if exists && mode == ImportMode::Merge {
+                    read_agent_passphrase(&config)?
+                } else {
+                    read_new_passphrase()?
+                };
+                let addresses = cmd_import(&config, path, backup_passphrase.as_deref(), &passphrase, mode)?;
+                println!("{} addresses imported:", addresses.len());
+                for address in addresses {
+                    println!("{}", address);
+                }
+            } else {
+                cmd_agent(&config)?;
+            }
+        } else if matches.subcommand_matches("restore").is_some() {
+            println!("this operation will remove current agent. continue?(y/n)");
+            let mut yesno = String::new();
+            io::stdin().read_line(&mut yesno)?;
+            if yesno.trim() != "n" {
+                let mnemonic = read_passphrase("mnemonic of your agent:")?;
+                let build = ask_build()?;
+                let passphrase = read_new_passphrase()?;
+                let addresses = cmd_restore(&config, build, mnemonic.trim(), &passphrase)?;
+                println!("{} addresses restored:", addresses.len());
+                for address in addresses {
+                    println!("{}", address);
+                }
+            }
+        } else if matches.subcommand_matches("newaddr").is_some() {
+            let passphrase = read_agent_passphrase(&config)?;
+            println!("new address generated:\n{}", cmd_newaddr(&config, &passphrase)?);
+        } else if matches.subcommand_matches("addr").is_some() {
+            cmd_addr(&config)?;
+        } else if let Some(matches) = matches.subcommand_matches("watch") {
+            let address = cmd_watch(&config, matches.value_of("entry").unwrap_or_default())?;
+            println!("watching address {}, it can not be spent from.", address);
+        } else if let Some(matches) = matches.subcommand_matches("unwatch") {
+            let address = matches.value_of("address").unwrap_or_default();
+            cmd_unwatch(&config, address)?;
+            println!("no longer watching address {}.", address);
+        } else if let Some(matches) = matches.subcommand_matches("unlock") {
+            let timeout = match matches.value_of("timeout") {
+                Some(secs) => Duration::from_secs(
+                    secs.parse()
+                        .map_err(|_| PokError::InvalidArgument(format!("{} is not a number of seconds", secs)))?,
+                ),
+                None => DEFAULT_UNLOCK_TIMEOUT,
+            };
+            let passphrase = read_passphrase("passphrase of your agent:")?;
+            let unlocked = cmd_unlock(&config, &passphrase, timeout)?;
+            println!("agent of the node at {} unlocked for {} seconds.", config.listen_addr, unlocked.as_secs());
+        } else if matches.subcommand_matches("lock").is_some() {
+            cmd_lock(&config)?;
+            println!("agent of the node at {} locked.", config.listen_addr);
+        } else if matches.subcommand_matches("passwd").is_some() {
+            let old_passphrase = read_agent_passphrase(&config)?;
+            let new_passphrase = read_new_passphrase()?;
+            cmd_passwd(&config, &old_passphrase, &new_passphrase)?;
+            println!("passphrase changed, the agent is locked.");
+        } else if matches.subcommand_matches("chain").is_some() {
+            cmd_chain(&config)?;
+        } else if let Some(matches) = matches.subcommand_matches("rollback") {
+            if let Some(n) = matches.value_of("n") {
+                let count = n
+                    .parse()
+                    .map_err(|_| PokError::InvalidArgument(format!("{} is not a number of blocks", n)))?;
+                let height = cmd_rollback(&config, count)?;
+                println!("Done! The blockchain is back at height {}.", height);
+            }
+        } else if matches.subcommand_matches("repairkills").is_some() {
+            let kills = cmd_repair_kills(&config)?;
+            println!("Done! The blockchain holds {} kills.", kills);
+        } else if matches.subcommand_matches("migrate").is_some() {
+            let count = cmd_migrate(&config)?;
+            if count > 0 {
+                println!("Done! {} blocks migrated, they were mined again under new hashes.", count);
+                println!("the old database is kept in {}.", config.chain_path().with_extension("legacy").display());
+            } else {
+                println!("the blockchain database is up to date.");
+            }
+        } else if matches.subcommand_matches("supply").is_some() {
+            let supply = cmd_supply(&config)?;
+            println!("height: {}", supply.height);
+            println!("emitted: {}", supply.emitted);
+            println!("scheduled: {}", supply.scheduled);
+            println!("unspent: {}", supply.unspent);
+            println!("max supply: {}", MAX_SUPPLY);
+            if supply.emitted == supply.scheduled {
+                println!("the blockchain follows the subsidy schedule");
+            } else {
+                println!("the blockchain is off the subsidy schedule by {}", supply.emitted - supply.scheduled);
+            }
+        } else if matches.subcommand_matches("reindex").is_some() {
+            let count = cmd_reindex(&config)?;
             println!("Done! There are {} transactions in the UTXO set.", count);
-        } else if let Some(ref matches) = matches.subcommand_matches("initdb") {
+        } else if let Some(matches) = matches.subcommand_matches("initdb") {
             if let Some(address) = matches.value_of("address") {
-                cmd_init_db(address)?;
+                cmd_init_db(&config, address)?;
             }
-        } else if let Some(ref matches) = matches.subcommand_matches("send") {
+        } else if let Some(matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.value_of("from") {
                 address
             } else {
@@ -109,42 +345,53 @@ impl Cli {
                 exit(1)
             };
             let amount: i32 = if let Some(amount) = matches.value_of("amount") {
-                amount.parse()?
+                amount
+                    .parse()
+                    .map_err(|_| PokError::InvalidArgument(format!("{} is not an amount", amount)))?
             } else {
                 println!("amount in send not supply!: usage\n{}", matches.usage());
                 exit(1)
             };
+            let fee_rate: i32 = match matches.value_of("fee-rate") {
+                Some(rate) => rate
+                    .parse()
+                    .ok()
+                    .filter(|rate| *rate >= 0)
+                    .ok_or_else(|| PokError::InvalidArgument(format!("{} is not a fee rate", rate)))?,
+                None => DEFAULT_FEE_RATE,
+            };
+            let passphrase = read_agent_passphrase(&config)?;
             if matches.is_present("mine") {
-                cmd_send(from, to, amount, true)?;
+                cmd_send(&config, from, to, amount, fee_rate, true, &passphrase)?;
             } else {
-                cmd_send(from, to, amount, false)?;
+                cmd_send(&config, from, to, amount, fee_rate, false, &passphrase)?;
             }
-        } else if let Some(ref matches) = matches.subcommand_matches("startnode") {
-            if let Some(port) = matches.value_of("port") {
-                println!("Start node...");
-                let bc = Blockchain::load(&node_id)?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
-                //will start a server with node_id = localhost:port, known_nodes = localhost:3333
-                server.start()?;
-            }
-        } else if let Some(ref matches) = matches.subcommand_matches("startminer") {
+        } else if matches.subcommand_matches("startnode").is_some() {
+            println!("Start node...");
+            let bc = Blockchain::load(&config)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            //a node without agent refuses to unlock
+            let agent = match Agent::load(&config) {
+                Ok(agent) => Some(agent),
+                Err(PokError::NotFound(_)) => None,
+                Err(e) => return Err(e),
+            };
+            let server = Server::new(&config, None, agent, utxo_set)?;
+            //will start a server listening on listen_addr, dialing the seed peers and the address book
+            server.start()?;
+        } else if let Some(matches) = matches.subcommand_matches("startminer") {
             let address = if let Some(address) = matches.value_of("address") {
                 address
             } else {
                 println!("address not supply!: usage\n{}", matches.usage());
                 exit(1)
             };
-            let port = if let Some(port) = matches.value_of("port") {
-                port
-            } else {
-                println!("port not supply!: usage\n{}", matches.usage());
-                exit(1)
-            };
             println!("Start miner node...");
-            let bc = Blockchain::load(&node_id)?;
+            let bc = Blockchain::load(&config)?;
             let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            let agent = Agent::load(&config)?;
+            let miner = MinerContext::from_agent(&agent, address);
+            let server = Server::new(&config, Some(miner), Some(agent), utxo_set)?;
             server.start()?;
         }
 
@@ -152,53 +399,77 @@ impl Cli {
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let node_id = env::var("NODE_ID").unwrap();
-    let bc = Blockchain::load(&node_id)?;
+fn cmd_send(
+    config: &NodeConfig,
+    from: &str,
+    to: &str,
+    amount: i32,
+    fee_rate: i32,
+    mine_now: bool,
+    passphrase: &str,
+) -> Result<()> {
+    let bc = Blockchain::load(config)?;
     let mut utxo_set = UTXOSet { blockchain: bc };
-    let agent = Agent::load().unwrap();
-    let from_keypair = agent.get_keypair_by_address(from).unwrap();
-    let tx = Transaction::send(from_keypair, to, amount, &utxo_set,agent.get_build().clone())?;
+    let agent = load_unlocked(config, passphrase)?;
+    let from_keypair = agent.get_keypair_by_address(from)?;
+    let tx = Transaction::send(from_keypair, to, amount, fee_rate, &utxo_set, agent.get_build().clone())?;
     if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+        let mut miner = MinerContext::from_agent(&agent, from);
+        let new_block = utxo_set.blockchain.mine_block(&mut miner, vec![tx])?;
 
         utxo_set.update(&new_block)?;
     } else {
-        let server = Server::new("7000", "", utxo_set)?;
-        server.send_tx(CENTRAL_NODE, &tx)?;
+        let server = Server::new(config, None, None, utxo_set)?;
+        server.submit_tx(&tx)?;
     }
 
     println!("success!");
     Ok(())
 }
 
-fn cmd_newagent() -> Result<String> {
-    let node_id = env::var("NODE_ID").unwrap();
-    let agent_path = "data_".to_owned() + &node_id + "/agent";
-
+fn cmd_newagent(config: &NodeConfig) -> Result<String> {
     println!("this operation will remove current agent. continue?(y/n)");
     let mut yesno = String::new();
-    io::stdin()
-    .read_line(&mut yesno)
-    .expect("please enter y or n");
+    io::stdin().read_line(&mut yesno)?;
 
     if yesno.trim() == "n" {
         return Ok("Creation Canceled".to_owned());
     }
-    
-    
-    std::fs::remove_dir_all(&agent_path).ok();
 
+    let build = ask_build()?;
+    let passphrase = read_new_passphrase()?;
+    let (address, mnemonic) = create_agent(config, build, &passphrase)?;
+
+    println!("Congratulation, you made a wise choice.");
+    println!("Write down the words below, they bring back every address of your agent with command `restore`:");
+    println!("{}", mnemonic);
+    println!("Use Command `agent` to greet your agent.");
+
+    Ok(address)
+}
+
+fn cmd_restore(config: &NodeConfig, build: Build, mnemonic: &str, passphrase: &str) -> Result<Vec<String>> {
+    //the chain is read before the stored agent is replaced, only a chain never initialized is empty
+    let used = if is_db_exists(&config.chain_path()) {
+        Blockchain::load(config)?.used_pub_key_hashes()
+    } else {
+        HashSet::new()
+    };
+    let mut agent = Agent::restore(build, mnemonic, passphrase, config)?;
+    let addresses = agent.recover_addresses(&used)?;
+    agent.save()?;
+    Ok(addresses)
+}
+
+/// asks for the name, class and weapon of an agent
+fn ask_build() -> Result<Build> {
     loop {
         let mut name = String::new();
         let mut class = String::new();
         let mut weapon = String::new();
 
         println!("\nPlease name your agent:");
-        io::stdin()
-            .read_line(&mut name)
-            .expect("failed to read name");
+        io::stdin().read_line(&mut name)?;
         name = name.trim().to_owned();
 
         println!();
@@ -211,9 +482,7 @@ fn cmd_newagent() -> Result<String> {
         println!("#2 Mage");
         println!("#3 Archer");
 
-        io::stdin()
-            .read_line(&mut class)
-            .expect("failed to read class");
+        io::stdin().read_line(&mut class)?;
 
         class = match class.trim().parse() {
             Ok(num) => {
@@ -221,10 +490,10 @@ fn cmd_newagent() -> Result<String> {
                     1 => "Warrior".to_owned(),
                     2 => "Mage".to_owned(),
                     3 => "Archer".to_owned(),
-                    _ => panic!()
+                    _ => return Err(PokError::InvalidArgument(String::from("class should be 1, 2 or 3"))),
                 }
             },
-            Err(_) => panic!(),
+            Err(_) => return Err(PokError::InvalidArgument(String::from("class should be a number"))),
         };
 
         println!();
@@ -238,52 +507,46 @@ fn cmd_newagent() -> Result<String> {
             "Warrior" => {
                 println!("#1 Axe");
                 println!("#2 Warhammer");
-                io::stdin()
-                .read_line(&mut weapon)
-                .expect("failed to read weapon");
+                io::stdin().read_line(&mut weapon)?;
                 weapon = match weapon.trim().parse() {
                     Ok(num) => {
                         match num {
                             1 => "Axe".to_owned(),
                             2 => "Warhammer".to_owned(),
-                            _ => panic!(),
+                            _ => return Err(PokError::InvalidArgument(String::from("weapon should be 1 or 2"))),
                         }
                     },
-                    Err(_) => panic!(),
+                    Err(_) => return Err(PokError::InvalidArgument(String::from("weapon should be a number"))),
                 };
             },
             "Mage" => {
                 println!("#1 Wand");
                 println!("#2 Sword");
-                io::stdin()
-                .read_line(&mut weapon)
-                .expect("failed to read weapon");
+                io::stdin().read_line(&mut weapon)?;
                 weapon = match weapon.trim().parse() {
                     Ok(num) => {
                         match num {
                             1 => "Wand".to_owned(),
                             2 => "Sword".to_owned(),
-                            _ => panic!(),
+                            _ => return Err(PokError::InvalidArgument(String::from("weapon should be 1 or 2"))),
                         }
                     },
-                    Err(_) => panic!(),
+                    Err(_) => return Err(PokError::InvalidArgument(String::from("weapon should be a number"))),
                 };
             },
             "Archer" => {
                 println!("#1 Longbow");
                 println!("#2 Crossbow");
-                io::stdin()
-                .read_line(&mut weapon)
-                .expect("failed to read weapon");
+                io::stdin().read_line(&mut weapon)?;
                 weapon = match weapon.trim().parse() {
                     Ok(num) => {
                         match num {
                             1 => "Longbow".to_owned(),
                             2 => "Crossbow".to_owned(),
-                            _ => panic!(),
+                            _ => return Err(PokError::InvalidArgument(String::from("weapon should be 1 or 2"))),
                         }
                     },
-                    Err(_) => panic!(),
+                    Err(_) => return Err(PokError::InvalidArgument(String::from("weapon should be a number"))),
                 };
 
             },
@@ -306,9 +569,7 @@ fn cmd_newagent() -> Result<String> {
         println!("Are you satisfied with this agent?: (y/n)");
 
         let mut yesno = String::new();
-        io::stdin()
-        .read_line(&mut yesno)
-        .expect("please enter y or n");
+        io::stdin().read_line(&mut yesno)?;
 
         if yesno.trim() == "n" {
             continue;
@@ -317,52 +578,202 @@ fn cmd_newagent() -> Result<String> {
         println!();
         println!();
         println!();
-        
-        let build = Build::new(name,class,weapon);
 
-        let mut agent = Agent::new(build,&node_id).unwrap();
-        let address = agent.generate_address();
-        agent.save()?;
+        return Ok(Build::new(name,class,weapon))
+    }
+}
 
-        println!("Congratulation, you made a wise choice.");
-        println!("Use Command `agent` to greet your agent.");
-        
-        return Ok(address)
+/// replaces the agent of the node with a new one fighting as build,
+/// returns the first address of the new agent and the mnemonic it is derived from
+fn create_agent(config: &NodeConfig, build: Build, passphrase: &str) -> Result<(String, String)> {
+    let mut agent = Agent::new(build, passphrase, config)?;
+    let address = agent.generate_address()?;
+    agent.save()?;
+    Ok((address, agent.mnemonic()?))
+}
+
+/// reads a passphrase from the standard input
+fn read_passphrase(prompt: &str) -> Result<String> {
+    println!("{}", prompt);
+    let mut passphrase = String::new();
+    io::stdin().read_line(&mut passphrase)?;
+    Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// asks for the passphrase of the agent, unless its keys are stored in plaintext
+fn read_agent_passphrase(config: &NodeConfig) -> Result<String> {
+    if Agent::load(config)?.is_encrypted() {
+        read_passphrase("passphrase of your agent:")
+    } else {
+        Ok(String::new())
+    }
+}
+
+/// loads the agent, unlocking its keys with passphrase for the rest of the command
+fn load_unlocked(config: &NodeConfig, passphrase: &str) -> Result<Agent> {
+    let mut agent = Agent::load(config)?;
+    if agent.is_encrypted() {
+        agent.unlock(passphrase, DEFAULT_UNLOCK_TIMEOUT)?;
+    }
+    Ok(agent)
+}
+
+/// reads a new passphrase twice, they have to match
+fn read_new_passphrase() -> Result<String> {
+    let passphrase = read_passphrase("new passphrase encrypting the keys of your agent:")?;
+    if passphrase.is_empty() {
+        return Err(PokError::InvalidArgument(String::from("the passphrase is empty")));
     }
+    if read_passphrase("repeat the passphrase:")? != passphrase {
+        return Err(PokError::InvalidArgument(String::from("the passphrases do not match")));
+    }
+    Ok(passphrase)
+}
+
+/// ImportMode tells `cmd_import` what to do with the agent the node already has
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImportMode {
+    //refuse to touch it
+    New,
+    //add the keys of the backup to it, it keeps its id and build
+    Merge,
+    //remove it
+    Replace,
+}
+
+/// writes the agent to a backup file, encrypted by backup_passphrase if there is one,
+/// returns the number of addresses in it
+fn cmd_export(config: &NodeConfig, path: &Path, backup_passphrase: Option<&str>, passphrase: &str) -> Result<usize> {
+    let agent = load_unlocked(config, passphrase)?;
+    let backup = agent.backup()?;
+    backup.write(path, backup_passphrase, config)?;
+    Ok(backup.addresses().len())
 }
 
-fn cmd_agent()-> Result<()> {
-    match Agent::load() {
+/// imports the agent of a backup file, passphrase encrypts the keys of an agent it creates
+/// or unlocks the agent it merges into, returns the imported addresses
+fn cmd_import(
+    config: &NodeConfig,
+    path: &Path,
+    backup_passphrase: Option<&str>,
+    passphrase: &str,
+    mode: ImportMode,
+) -> Result<Vec<String>> {
+    let backup = Backup::read(path, backup_passphrase)?;
+    let exists = is_agent_exists(&config.agent_path());
+    match mode {
+        ImportMode::New if exists => Err(PokError::AgentExists),
+        ImportMode::Merge if exists => {
+            let mut agent = load_unlocked(config, passphrase)?;
+            let added = agent.merge_backup(&backup)?;
+            agent.save()?;
+            Ok(added)
+        }
+        _ => {
+            Agent::from_backup(&backup, passphrase, config)?;
+            Ok(backup.addresses())
+        }
+    }
+}
+
+fn cmd_agent(config: &NodeConfig)-> Result<()> {
+    match Agent::load(config) {
         Ok(agent) => {
             println!("agent name: {:?}", agent.get_build().name);
             println!("agent class: {:?}", agent.get_build().class);
             println!("agent's weapon: {:?}", agent.get_build().weapon);
-            return Ok(());
+            if agent.is_encrypted() {
+                println!("keys: encrypted, commands using them ask for the passphrase");
+            } else {
+                println!("keys: not encrypted, use command `passwd` to encrypt them");
+            }
+            Ok(())
         },
-        Err(err) => return Err(err),
+        Err(err) => Err(err),
     }
 }
 
-fn cmd_newaddr() ->Result<()> {
-    let mut agent = Agent::load().unwrap();
-    let address = agent.generate_address();
+fn cmd_newaddr(config: &NodeConfig, passphrase: &str) -> Result<String> {
+    let mut agent = load_unlocked(config, passphrase)?;
+    let address = agent.generate_address()?;
+    agent.save()?;
+    Ok(address)
+}
+
+fn cmd_watch(config: &NodeConfig, entry: &str) -> Result<String> {
+    let mut agent = Agent::load(config)?;
+    let address = agent.watch(entry)?;
     agent.save()?;
-    println!("new address generated:\n{}",&address);
+    Ok(address)
+}
+
+fn cmd_unwatch(config: &NodeConfig, address: &str) -> Result<()> {
+    let mut agent = Agent::load(config)?;
+    agent.unwatch(address)?;
+    agent.save()
+}
+
+/// asks the node listening on the address of config to unlock its agent,
+/// returns how long it stays unlocked
+fn cmd_unlock(config: &NodeConfig, passphrase: &str, timeout: Duration) -> Result<Duration> {
+    let request = Message::Unlock(Unlockmsg {
+        passphrase: passphrase.to_owned(),
+        timeout_secs: timeout.as_secs(),
+    });
+    let session = request_session(&config.listen_addr, config.network, &request)?;
+    Ok(Duration::from_secs(session.unlocked_secs))
+}
+
+/// asks the node listening on the address of config to lock its agent
+fn cmd_lock(config: &NodeConfig) -> Result<()> {
+    request_session(&config.listen_addr, config.network, &Message::Lock)?;
     Ok(())
 }
 
-fn cmd_reindex() -> Result<i32> {
-    let node_id = env::var("NODE_ID").unwrap();
-    let bc = Blockchain::load(&node_id)?;
+fn cmd_passwd(config: &NodeConfig, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+    let mut agent = Agent::load(config)?;
+    agent.change_passphrase(old_passphrase, new_passphrase)
+}
+
+fn cmd_reindex(config: &NodeConfig) -> Result<i32> {
+    let bc = Blockchain::load(config)?;
     let utxo_set = UTXOSet { blockchain: bc };
     utxo_set.reindex()?;
     utxo_set.count_transactions()
 }
 
-fn cmd_init_db(address: &str) -> Result<()> {
-    let node_id = env::var("NODE_ID").unwrap();
-    let address = String::from(address);
-    let bc = Blockchain::init(address,&node_id)?;
+fn cmd_supply(config: &NodeConfig) -> Result<Supply> {
+    let bc = Blockchain::load(config)?;
+    let utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.supply()
+}
+
+fn cmd_rollback(config: &NodeConfig, count: usize) -> Result<u128> {
+    let bc = Blockchain::load(config)?;
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.rollback(count)?;
+    utxo_set.blockchain.get_best_height()
+}
+
+fn cmd_repair_kills(config: &NodeConfig) -> Result<u128> {
+    let mut bc = Blockchain::load(config)?;
+    bc.repair_kills()
+}
+
+fn cmd_migrate(config: &NodeConfig) -> Result<usize> {
+    let count = Blockchain::migrate(config)?;
+    if count > 0 {
+        let bc = Blockchain::load(config)?;
+        let utxo_set = UTXOSet { blockchain: bc };
+        utxo_set.reindex()?;
+    }
+    Ok(count)
+}
+
+fn cmd_init_db(config: &NodeConfig, address: &str) -> Result<()> {
+    let agent = Agent::load(config)?;
+    let mut miner = MinerContext::from_agent(&agent, address);
+    let bc = Blockchain::init(&mut miner,config)?;
 
     let utxo_set = UTXOSet { blockchain: bc };
     utxo_set.reindex()?;
@@ -370,42 +781,81 @@ fn cmd_init_db(address: &str) -> Result<()> {
     Ok(())
 }
 
-fn cmd_bal(address: &str) -> Result<i32> {
-    let node_id = env::var("NODE_ID").unwrap();
-    let pub_key_hash = Address::decode(address).unwrap().body;
-    let bc = match Blockchain::load(&node_id) {
+fn cmd_bal(config: &NodeConfig, address: &str) -> Result<Balance> {
+    let pub_key_hash = address_to_pub_key_hash(address)?;
+    let bc = match Blockchain::load(config) {
         Ok(bc) => bc,
         Err(_) => {
-            return Ok(0);
+            return Ok(Balance::default());
         }
     };
   
     let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_utxo(&pub_key_hash)?;
+    utxo_set.get_balance(&pub_key_hash)
+}
 
-    let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
+/// balances of the addresses of the agent, the watch-only ones last and flagged
+fn cmd_agent_bal(config: &NodeConfig) -> Result<Vec<(String, bool, Balance)>> {
+    let agent = Agent::load(config)?;
+    let mut own = agent.get_all_addresses();
+    let mut watched = agent.get_watch_only_addresses();
+    own.sort();
+    watched.sort();
+    let utxo_set = Blockchain::load(config).ok().map(|bc| UTXOSet { blockchain: bc });
+
+    let mut balances = Vec::new();
+    for (address, watch_only) in own.into_iter().map(|a| (a, false)).chain(watched.into_iter().map(|a| (a, true))) {
+        let balance = match &utxo_set {
+            Some(utxo_set) => utxo_set.get_balance(&address_to_pub_key_hash(&address)?)?,
+            None => Balance::default(),
+        };
+        balances.push((address, watch_only, balance));
     }
-    Ok(balance)
+    Ok(balances)
 }
 
-fn cmd_chain() -> Result<()> {
-    let node_id = env::var("NODE_ID").unwrap();
-    let bc = Blockchain::load(&node_id)?;
+/// transactions touching address, or every address of the agent watch-only ones included
+fn cmd_history(config: &NodeConfig, address: Option<&str>, since: Option<u128>) -> Result<Vec<HistoryEntry>> {
+    let addresses = match address {
+        Some(address) => vec![address.to_owned()],
+        None => {
+            let agent = Agent::load(config)?;
+            let mut addresses = agent.get_all_addresses();
+            addresses.extend(agent.get_watch_only_addresses());
+            addresses
+        }
+    };
+    let mut pub_key_hashes = HashSet::new();
+    for address in &addresses {
+        pub_key_hashes.insert(address_to_pub_key_hash(address)?);
+    }
+    let bc = Blockchain::load(config)?;
+    bc.history(&pub_key_hashes, since)
+}
+
+fn cmd_chain(config: &NodeConfig) -> Result<()> {
+    let bc = Blockchain::load(config)?;
     for b in bc.iter() {
         println!("{:#?}", b);
     }
     Ok(())
 }
 
-fn cmd_addr() -> Result<()> {
-    let agent = Agent::load().unwrap();
+fn cmd_addr(config: &NodeConfig) -> Result<()> {
+    let agent = Agent::load(config)?;
     let addresses = agent.get_all_addresses();
     println!("addresses: ");
     for ad in addresses {
         println!("{}", ad);
     }
+    let mut watched = agent.get_watch_only_addresses();
+    if !watched.is_empty() {
+        watched.sort();
+        println!("watch-only addresses: ");
+        for ad in watched {
+            println!("{}", ad);
+        }
+    }
     Ok(())
 }
 
@@ -415,26 +865,141 @@ mod test {
 
     #[test]
     fn test_locally() {
-        let addr1 = cmd_newagent().unwrap();
-        let addr2 = cmd_newagent().unwrap();
-        cmd_init_db(&addr1).unwrap();
-
-        let b1 = cmd_bal(&addr1).unwrap();
-        let b2 = cmd_bal(&addr2).unwrap();
-        assert_eq!(b1, 10);
-        assert_eq!(b2, 0);
-
-        cmd_send(&addr1, &addr2, 5, true).unwrap();
-
-        let b1 = cmd_bal(&addr1).unwrap();
-        let b2 = cmd_bal(&addr2).unwrap();
-        assert_eq!(b1, 15);
-        assert_eq!(b2, 5);
-
-        cmd_send(&addr2, &addr1, 15, true).unwrap_err();
-        let b1 = cmd_bal(&addr1).unwrap();
-        let b2 = cmd_bal(&addr2).unwrap();
-        assert_eq!(b1, 15);
-        assert_eq!(b2, 5);
+        let mut config = NodeConfig::new("test");
+        config.data_dir = std::env::temp_dir().join("pok_test_cli");
+        config.keystore_log_n = 4;
+        std::fs::remove_dir_all(&config.data_dir).ok();
+
+        //the keys of the agent are sealed, every command using them takes the passphrase
+        let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+        let (addr1, mnemonic) = create_agent(&config, build.clone(), "open sesame").unwrap();
+        assert!(matches!(cmd_newaddr(&config, "open barley"), Err(PokError::WrongPassphrase)));
+        let addr2 = cmd_newaddr(&config, "open sesame").unwrap();
+        assert!(!config.session_path().exists());
+        cmd_init_db(&config, &addr1).unwrap();
+
+        //the genesis reward matures while others mine
+        let b1 = cmd_bal(&config, &addr1).unwrap();
+        assert_eq!(b1, Balance { spendable: 0, immature: 10 });
+        cmd_send(&config, &addr1, &addr2, 5, DEFAULT_FEE_RATE, true, "open sesame").unwrap_err();
+        {
+            let mut bc = Blockchain::load(&config).unwrap();
+            let agent = Agent::load(&config).unwrap();
+            let mut miner = MinerContext::from_agent(&agent, &Keypair::new().address());
+            for _ in 1..COINBASE_MATURITY {
+                bc.mine_block(&mut miner, Vec::new()).unwrap();
+            }
+        }
+        cmd_reindex(&config).unwrap();
+
+        let b1 = cmd_bal(&config, &addr1).unwrap();
+        let b2 = cmd_bal(&config, &addr2).unwrap();
+        assert_eq!(b1, Balance { spendable: 10, immature: 0 });
+        assert_eq!(b2, Balance::default());
+
+        //the reward of the block mined by the sender collects the fee and waits in turn
+        cmd_send(&config, &addr1, &addr2, 5, DEFAULT_FEE_RATE, true, "open sesame").unwrap();
+
+        let b1 = cmd_bal(&config, &addr1).unwrap();
+        let b2 = cmd_bal(&config, &addr2).unwrap();
+        assert_eq!(b1.spendable + b1.immature, 15);
+        assert!(b1.immature > 10);
+        assert_eq!(b2, Balance { spendable: 5, immature: 0 });
+
+        cmd_send(&config, &addr2, &addr1, 15, DEFAULT_FEE_RATE, true, "open sesame").unwrap_err();
+        assert_eq!(cmd_bal(&config, &addr1).unwrap(), b1);
+        assert_eq!(cmd_bal(&config, &addr2).unwrap(), b2);
+
+        //fees move coins around, only subsidies create them
+        let supply = cmd_supply(&config).unwrap();
+        assert_eq!(supply.height, COINBASE_MATURITY);
+        assert_eq!(supply.emitted, 10 * (COINBASE_MATURITY as i64 + 1));
+        assert_eq!(supply.scheduled, supply.emitted);
+        assert_eq!(supply.unspent, supply.emitted);
+
+        //a cold address is watched: it shows in balances and history but never signs
+        let cold = Keypair::new();
+        cmd_send(&config, &addr2, &cold.address(), 2, DEFAULT_FEE_RATE, true, "open sesame").unwrap();
+        assert_eq!(cmd_watch(&config, &cold.address()).unwrap(), cold.address());
+        let balances = cmd_agent_bal(&config).unwrap();
+        assert_eq!(balances.last().unwrap(), &(cold.address(), true, Balance { spendable: 2, immature: 0 }));
+        assert_eq!(balances.iter().filter(|(_, watch_only, _)| !watch_only).count(), 2);
+        assert!(matches!(
+            cmd_send(&config, &cold.address(), &addr1, 1, DEFAULT_FEE_RATE, true, "open sesame"),
+            Err(PokError::WatchOnly(_))
+        ));
+        let height = Blockchain::load(&config).unwrap().get_best_height().unwrap();
+        let history = cmd_history(&config, None, Some(height - 1)).unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history.iter().all(|entry| entry.height == height));
+        //the send pays the cold address and the change back, short of the fee
+        assert!(history.iter().any(|entry| entry.sent == 5 && entry.received > 2 && entry.received < 5));
+        let history = cmd_history(&config, Some(&cold.address()), None).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].received, history[0].sent), (2, 0));
+        let all = cmd_history(&config, None, None).unwrap();
+        assert!(all.windows(2).all(|pair| pair[0].height >= pair[1].height));
+        assert_eq!((all.last().unwrap().height, all.last().unwrap().received), (0, 10));
+        cmd_unwatch(&config, &cold.address()).unwrap();
+        assert_eq!(cmd_agent_bal(&config).unwrap().len(), 2);
+
+        //after a new passphrase the old one opens nothing
+        assert!(matches!(cmd_passwd(&config, "open barley", "open rye"), Err(PokError::WrongPassphrase)));
+        cmd_passwd(&config, "open sesame", "open rye").unwrap();
+        assert!(!Agent::load(&config).unwrap().is_unlocked());
+        assert!(matches!(
+            cmd_send(&config, &addr2, &addr1, 1, DEFAULT_FEE_RATE, true, "open sesame"),
+            Err(PokError::WrongPassphrase)
+        ));
+        cmd_send(&config, &addr2, &addr1, 1, DEFAULT_FEE_RATE, true, "open rye").unwrap();
+
+        //the mnemonic brings back the addresses the chain knows
+        let b1 = cmd_bal(&config, &addr1).unwrap();
+        //a mistyped mnemonic leaves the agent as it was
+        let before = Agent::load(&config).unwrap();
+        assert!(cmd_restore(&config, build.clone(), "not a mnemonic", "open rye").is_err());
+        let after = load_unlocked(&config, "open rye").unwrap();
+        assert_eq!(after.get_id(), before.get_id());
+        let (mut addresses, mut kept) = (before.get_all_addresses(), after.get_all_addresses());
+        addresses.sort();
+        kept.sort();
+        assert_eq!(kept, addresses);
+        assert_eq!(after.mnemonic().unwrap(), mnemonic);
+        //so does a chain that can not be read
+        let moved = config.data_dir.join("chain.moved");
+        std::fs::rename(config.chain_path(), &moved).unwrap();
+        std::fs::write(config.chain_path(), b"not a chain").unwrap();
+        assert!(cmd_restore(&config, build, &mnemonic, "open rye").is_err());
+        assert_eq!(load_unlocked(&config, "open rye").unwrap().get_id(), before.get_id());
+        std::fs::remove_file(config.chain_path()).unwrap();
+        std::fs::rename(&moved, config.chain_path()).unwrap();
+        let restored = cmd_restore(&config, Build::new("Tom".to_owned(), "Mage".to_owned(), "Wand".to_owned()), &mnemonic, "open rye").unwrap();
+        assert_eq!(restored, vec![addr1.clone(), addr2.clone()]);
+        assert_eq!(cmd_bal(&config, &addr1).unwrap(), b1);
+        assert_ne!(cmd_newaddr(&config, "open rye").unwrap(), addr1);
+
+        //a backup moves the agent, an existing agent is only touched when asked to
+        let file = config.data_dir.join("agent.backup");
+        assert_eq!(cmd_export(&config, &file, Some("open oat"), "open rye").unwrap(), 3);
+        let agent = Agent::load(&config).unwrap();
+        assert!(matches!(
+            cmd_import(&config, &file, Some("open oat"), "open rye", ImportMode::New),
+            Err(PokError::AgentExists)
+        ));
+        assert!(cmd_import(&config, &file, Some("open oat"), "open rye", ImportMode::Merge).unwrap().is_empty());
+        create_agent(&config, Build::new("Tom".to_owned(), "Mage".to_owned(), "Wand".to_owned()), "open rye").unwrap();
+        assert_eq!(cmd_import(&config, &file, Some("open oat"), "open rye", ImportMode::Merge).unwrap().len(), 3);
+        assert_eq!(Agent::load(&config).unwrap().get_all_addresses().len(), 4);
+        let mut imported = cmd_import(&config, &file, Some("open oat"), "open rye", ImportMode::Replace).unwrap();
+        let mut exported = agent.get_all_addresses();
+        imported.sort();
+        exported.sort();
+        assert_eq!(imported, exported);
+        assert_eq!(Agent::load(&config).unwrap().get_id(), agent.get_id());
+        assert!(matches!(
+            cmd_import(&config, &file, Some("open rye"), "open rye", ImportMode::Replace),
+            Err(PokError::WrongPassphrase)
+        ));
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
     }
 }
@@ -0,0 +1,206 @@
+//! node configuration
+//!
+//! settings are merged from several sources, the first one that sets a field wins:
+//! command line flags, environment variables, the TOML config file, then defaults.
+
+use super::*;
+use crate::protocol::Network;
+use crate::PokError;
+use serde::Deserialize;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// config file read when neither `--config` nor POK_CONFIG names one
+pub const DEFAULT_CONFIG_FILE: &str = "pok.toml";
+pub const DEFAULT_NODE_ID: &str = "3000";
+/// peers dialed on start when no source names any, they bootstrap the address book.
+/// there are none, a node joins a network through the seeds its config file, POK_SEED_PEERS or `--seed` name
+pub const DEFAULT_SEED_PEERS: [&str; 0] = [];
+/// scrypt of a new keystore runs 2^DEFAULT_KEYSTORE_LOG_N rounds
+pub const DEFAULT_KEYSTORE_LOG_N: u8 = 15;
+
+/// NodeConfig tells a node where its data lives and how it reaches the network
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeConfig {
+    pub node_id: String,
+    //holds the chain, utxo, agent and peer databases
+    pub data_dir: PathBuf,
+    pub listen_addr: String,
+    pub seed_peers: Vec<String>,
+    //peers of other networks are refused
+    pub network: Network,
+    //cost of the passphrase of a new agent keystore, existing ones keep theirs
+    pub keystore_log_n: u8,
+}
+
+/// ConfigOverrides is what a single source sets, None falls through to the next source
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigOverrides {
+    pub node_id: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub listen_addr: Option<String>,
+    pub seed_peers: Option<Vec<String>>,
+    pub network: Option<Network>,
+    pub keystore_log_n: Option<u8>,
+}
+
+impl ConfigOverrides {
+    /// reads a TOML config file
+    pub fn from_file(path: &Path) -> Result<ConfigOverrides> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| PokError::Config(format!("invalid config file {}: {}", path.display(), e)))
+    }
+
+    /// reads NODE_ID, POK_DATA_DIR, POK_LISTEN_ADDR, POK_SEED_PEERS (comma separated),
+    /// POK_NETWORK and POK_KEYSTORE_LOG_N
+    pub fn from_env() -> Result<ConfigOverrides> {
+        let network = match env::var("POK_NETWORK") {
+            Ok(network) => Some(network.parse()?),
+            Err(_) => None,
+        };
+        let keystore_log_n = match env::var("POK_KEYSTORE_LOG_N") {
+            Ok(log_n) => Some(
+                log_n
+                    .parse()
+                    .map_err(|_| PokError::Config(format!("POK_KEYSTORE_LOG_N {} is not a number", log_n)))?,
+            ),
+            Err(_) => None,
+        };
+        Ok(ConfigOverrides {
+            node_id: env::var("NODE_ID").ok(),
+            data_dir: env::var("POK_DATA_DIR").ok().map(PathBuf::from),
+            listen_addr: env::var("POK_LISTEN_ADDR").ok(),
+            seed_peers: env::var("POK_SEED_PEERS").ok().map(|peers| {
+                peers
+                    .split(',')
+                    .map(|peer| peer.trim().to_owned())
+                    .filter(|peer| !peer.is_empty())
+                    .collect()
+            }),
+            network,
+            keystore_log_n,
+        })
+    }
+
+    /// fields unset here are taken from lower
+    pub fn or(self, lower: ConfigOverrides) -> ConfigOverrides {
+        ConfigOverrides {
+            node_id: self.node_id.or(lower.node_id),
+            data_dir: self.data_dir.or(lower.data_dir),
+            listen_addr: self.listen_addr.or(lower.listen_addr),
+            seed_peers: self.seed_peers.or(lower.seed_peers),
+            network: self.network.or(lower.network),
+            keystore_log_n: self.keystore_log_n.or(lower.keystore_log_n),
+        }
+    }
+}
+
+impl NodeConfig {
+    /// new() returns the default configuration of a node:
+    /// data in `data_<node_id>`, listening on `localhost:<node_id>`
+    pub fn new(node_id: &str) -> NodeConfig {
+        NodeConfig {
+            node_id: node_id.to_owned(),
+            data_dir: PathBuf::from("data_".to_owned() + node_id),
+            listen_addr: "localhost:".to_owned() + node_id,
+            seed_peers: DEFAULT_SEED_PEERS.iter().map(|peer| String::from(*peer)).collect(),
+            network: Network::default(),
+            keystore_log_n: DEFAULT_KEYSTORE_LOG_N,
+        }
+    }
+
+    /// load() merges command line flags with the environment and the config file,
+    /// fallback fills what none of them sets before the defaults do.
+    ///
+    /// the config file is config_file if given, otherwise POK_CONFIG,
+    /// otherwise DEFAULT_CONFIG_FILE when it exists.
+    pub fn load(cli: ConfigOverrides, config_file: Option<&Path>, fallback: ConfigOverrides) -> Result<NodeConfig> {
+        let config_file = match config_file {
+            Some(path) => Some(path.to_path_buf()),
+            None => match env::var("POK_CONFIG") {
+                Ok(path) => Some(PathBuf::from(path)),
+                Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => Some(PathBuf::from(DEFAULT_CONFIG_FILE)),
+                Err(_) => None,
+            },
+        };
+        let file = match config_file {
+            Some(path) => ConfigOverrides::from_file(&path)?,
+            None => ConfigOverrides::default(),
+        };
+        Ok(NodeConfig::merge(cli.or(ConfigOverrides::from_env()?).or(file).or(fallback)))
+    }
+
+    /// fills the fields left unset by every source with the defaults of its node id
+    pub fn merge(overrides: ConfigOverrides) -> NodeConfig {
+        let node_id = overrides.node_id.unwrap_or_else(|| String::from(DEFAULT_NODE_ID));
+        let defaults = NodeConfig::new(&node_id);
+        NodeConfig {
+            node_id,
+            data_dir: overrides.data_dir.unwrap_or(defaults.data_dir),
+            listen_addr: overrides.listen_addr.unwrap_or(defaults.listen_addr),
+            seed_peers: overrides.seed_peers.unwrap_or(defaults.seed_peers),
+            network: overrides.network.unwrap_or(defaults.network),
+            keystore_log_n: overrides.keystore_log_n.unwrap_or(defaults.keystore_log_n),
+        }
+    }
+
+    pub fn chain_path(&self) -> PathBuf {
+        self.data_dir.join("chain")
+    }
+
+    pub fn agent_path(&self) -> PathBuf {
+        self.data_dir.join("agent")
+    }
+
+    /// file earlier versions kept the key of an unlocked agent in, it is removed when the agent loads
+    pub fn session_path(&self) -> PathBuf {
+        self.data_dir.join("agent.session")
+    }
+
+    pub fn peers_path(&self) -> PathBuf {
+        self.data_dir.join("peers")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        let file: ConfigOverrides = toml::from_str(
+            r#"
+            node_id = "3001"
+            listen_addr = "0.0.0.0:4001"
+            seed_peers = ["seed1:3333", "seed2:3333"]
+            network = "testnet"
+            keystore_log_n = 10
+            "#,
+        )
+        .unwrap();
+        let cli = ConfigOverrides {
+            listen_addr: Some(String::from("localhost:5001")),
+            ..Default::default()
+        };
+
+        let port = ConfigOverrides {
+            listen_addr: Some(String::from("localhost:6001")),
+            ..Default::default()
+        };
+        assert_eq!(NodeConfig::merge(file.clone().or(port.clone())).listen_addr, "0.0.0.0:4001");
+        assert_eq!(NodeConfig::merge(ConfigOverrides::default().or(port)).listen_addr, "localhost:6001");
+
+        let config = NodeConfig::merge(cli.or(file));
+        assert_eq!(config.node_id, "3001");
+        assert_eq!(config.data_dir, PathBuf::from("data_3001"));
+        assert_eq!(config.listen_addr, "localhost:5001");
+        assert_eq!(config.seed_peers, vec!["seed1:3333", "seed2:3333"]);
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(config.keystore_log_n, 10);
+
+        let config = NodeConfig::merge(ConfigOverrides::default());
+        assert_eq!(config, NodeConfig::new(DEFAULT_NODE_ID));
+        assert!(config.seed_peers.is_empty());
+    }
+}
@@ -1,11 +1,10 @@
-/*
 use ring::digest::{Context, SHA256};
 use rand_seeder::{Seeder};
 use rand_pcg::Pcg64;
 use std::io::{Read};
 
-//produce a random number generator from SHA256 hashes
-fn generator_from_sha256<R: Read>(mut reader: R) -> Result<Pcg64,std::io::Error> {
+/// produce a random number generator from SHA256 hashes
+pub fn generator_from_sha256<R: Read>(mut reader: R) -> Result<Pcg64,std::io::Error> {
     let mut context = Context::new(&SHA256);
     let mut buffer = [0; 1024];
 
@@ -27,20 +26,19 @@ fn generator_from_sha256<R: Read>(mut reader: R) -> Result<Pcg64,std::io::Error>
 mod tests {
     use super::*;
     use rand::Rng;
-    use std::fs::File;
+
     #[test]
     fn generator_from_sha256_test()-> Result<(),std::io::Error> {
-        
-        
-        let path = "target/debug/bin.d";
-    
-        let input = File::open(path)?;
-        let reader = BufReader::new(input);
-        let mut rng = generator_from_sha256(reader)?;
-    
-        println!("random number from sha256 is {}",rng.gen_range(-5..=5));
-    
+        let mut rng1 = generator_from_sha256(&b"proof of kill"[..])?;
+        let mut rng2 = generator_from_sha256(&b"proof of kill"[..])?;
+        let mut rng3 = generator_from_sha256(&b"proof of work"[..])?;
+
+        let seq1: Vec<i32> = (0..32).map(|_| rng1.gen_range(-5..=5)).collect();
+        let seq2: Vec<i32> = (0..32).map(|_| rng2.gen_range(-5..=5)).collect();
+        let seq3: Vec<i32> = (0..32).map(|_| rng3.gen_range(-5..=5)).collect();
+        assert_eq!(seq1, seq2);
+        assert_ne!(seq1, seq3);
+
         Ok(())
     }
 }
-*/
\ No newline at end of file
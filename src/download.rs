@@ -0,0 +1,268 @@
+//! scheduler of block body downloads
+//!
+//! every requested block is tracked with the peer asked for it and a deadline.
+//! a request running past its deadline is handed to another peer, a peer letting
+//! too many requests expire is reported as stalled so the server can drop it.
+//! blocks arriving before the body of their parent wait in the orphan pool.
+
+use crate::block::Block;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// time a peer gets to deliver a requested block
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// blocks requested from a single peer at a time
+pub const MAX_BLOCKS_PER_PEER: usize = 16;
+/// a block is given up after this many expired requests, until new headers come in
+pub const MAX_ATTEMPTS: u32 = 3;
+/// expired requests after which a peer is considered stalled
+pub const MAX_STALLS: u32 = 3;
+/// blocks kept while waiting for their parent, the oldest is evicted first
+pub const MAX_ORPHANS: usize = 256;
+
+#[derive(Debug, Clone)]
+struct Request {
+    peer: String,
+    deadline: Instant,
+}
+
+/// Downloader decides which peer is asked for which block
+#[derive(Debug, Default)]
+pub struct Downloader {
+    //block hash to the request in flight
+    requests: HashMap<String, Request>,
+    //expired requests per block
+    attempts: HashMap<String, u32>,
+    //peers that let a request of the block expire, tried last
+    failed_peers: HashMap<String, HashSet<String>>,
+    //best height announced per peer address
+    peer_heights: HashMap<String, u128>,
+    //expired requests per peer address
+    stalls: HashMap<String, u32>,
+    orphans: HashMap<String, Block>,
+    //orphan hashes, oldest first
+    orphan_order: VecDeque<String>,
+}
+
+impl Downloader {
+    pub fn new() -> Downloader {
+        Downloader::default()
+    }
+
+    /// records the height of the best chain a peer announced, lower heights are ignored
+    pub fn update_peer_height(&mut self, peer: &str, height: u128) {
+        let known = self.peer_heights.entry(peer.to_owned()).or_insert(height);
+        *known = (*known).max(height);
+    }
+
+    /// returns true if the block is requested or waits in the orphan pool
+    pub fn is_pending(&self, block_hash: &str) -> bool {
+        self.requests.contains_key(block_hash) || self.orphans.contains_key(block_hash)
+    }
+
+    /// returns true if the block expired MAX_ATTEMPTS times and is not requested until new headers come in
+    pub fn is_given_up(&self, block_hash: &str) -> bool {
+        self.attempts.get(block_hash).copied().unwrap_or(0) >= MAX_ATTEMPTS
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.requests.len()
+    }
+
+    fn load(&self, peer: &str) -> usize {
+        self.requests.values().filter(|r| r.peer == peer).count()
+    }
+
+    /// schedule() assigns missing blocks, given as (hash, height) parents first, to peers.
+    ///
+    /// a block goes to the least busy peer whose chain reaches it, peers that
+    /// already failed to deliver it are only picked when no other one can.
+    /// returns the (peer, block hash) requests to send.
+    pub fn schedule(&mut self, missing: &[(String, u128)], peers: &[String], now: Instant) -> Vec<(String, String)> {
+        let mut assigned = Vec::new();
+        for (block_hash, height) in missing {
+            if self.is_pending(block_hash) {
+                continue;
+            }
+            if self.is_given_up(block_hash) {
+                continue;
+            }
+            let failed = self.failed_peers.get(block_hash);
+            let peer = peers
+                .iter()
+                .filter(|peer| self.peer_heights.get(*peer).is_some_and(|h| h >= height))
+                .map(|peer| (failed.is_some_and(|f| f.contains(peer)), self.load(peer), peer))
+                .filter(|(_, load, _)| *load < MAX_BLOCKS_PER_PEER)
+                .min_by_key(|(failed, load, _)| (*failed, *load))
+                .map(|(_, _, peer)| peer.clone());
+            let peer = match peer {
+                Some(peer) => peer,
+                //every peer able to serve the block is busy
+                None => continue,
+            };
+            self.requests.insert(
+                block_hash.clone(),
+                Request {
+                    peer: peer.clone(),
+                    deadline: now + BLOCK_TIMEOUT,
+                },
+            );
+            assigned.push((peer, block_hash.clone()));
+        }
+        assigned
+    }
+
+    /// marks a block as delivered, returns the peer it was requested from
+    pub fn received(&mut self, block_hash: &str) -> Option<String> {
+        self.attempts.remove(block_hash);
+        self.failed_peers.remove(block_hash);
+        let request = self.requests.remove(block_hash)?;
+        self.stalls.remove(&request.peer);
+        Some(request.peer)
+    }
+
+    /// expire() drops the requests past their deadline so they get scheduled again,
+    /// returns the peers that stalled MAX_STALLS times
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<(String, String)> = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.deadline <= now)
+            .map(|(hash, request)| (hash.clone(), request.peer.clone()))
+            .collect();
+        let mut stalled = Vec::new();
+        for (block_hash, peer) in expired {
+            warn!("request of block {} to {} timed out", block_hash, peer);
+            self.requests.remove(&block_hash);
+            *self.attempts.entry(block_hash.clone()).or_insert(0) += 1;
+            self.failed_peers.entry(block_hash).or_default().insert(peer.clone());
+            let stalls = self.stalls.entry(peer.clone()).or_insert(0);
+            *stalls += 1;
+            if *stalls >= MAX_STALLS && !stalled.contains(&peer) {
+                stalled.push(peer);
+            }
+        }
+        stalled
+    }
+
+    /// forgets a disconnected peer, its requests get scheduled again
+    pub fn peer_gone(&mut self, peer: &str) {
+        self.requests.retain(|_, request| request.peer != peer);
+        self.peer_heights.remove(peer);
+        self.stalls.remove(peer);
+    }
+
+    /// lets blocks given up after MAX_ATTEMPTS be requested again, e.g. once new headers came in
+    pub fn clear_attempts(&mut self) {
+        self.attempts.clear();
+    }
+
+    /// keeps a block until the body of its parent arrives
+    pub fn add_orphan(&mut self, block: Block) {
+        let block_hash = block.get_hash();
+        if self.orphans.contains_key(&block_hash) {
+            return;
+        }
+        while self.orphans.len() >= MAX_ORPHANS {
+            match self.orphan_order.pop_front() {
+                Some(oldest) => {
+                    self.orphans.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.orphans.insert(block_hash.clone(), block);
+        self.orphan_order.push_back(block_hash);
+    }
+
+    /// takes the orphans whose parent is parent_hash out of the pool
+    pub fn take_children(&mut self, parent_hash: &str) -> Vec<Block> {
+        let children: Vec<String> = self
+            .orphans
+            .values()
+            .filter(|block| block.get_prev_hash() == parent_hash)
+            .map(|block| block.get_hash())
+            .collect();
+        self.orphan_order.retain(|hash| !children.contains(hash));
+        children
+            .iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .collect()
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn missing(count: u128) -> Vec<(String, u128)> {
+        (1..=count).map(|height| (format!("block{}", height), height)).collect()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut downloader = Downloader::new();
+        let peers = vec![String::from("a"), String::from("b"), String::from("c")];
+        downloader.update_peer_height("a", 40);
+        downloader.update_peer_height("b", 40);
+        //c only has part of the chain
+        downloader.update_peer_height("c", 2);
+
+        let now = Instant::now();
+        let assigned = downloader.schedule(&missing(40), &peers, now);
+        assert_eq!(assigned.len(), 2 * MAX_BLOCKS_PER_PEER);
+        assert!(assigned.iter().all(|(peer, _)| peer != "c"));
+        //nothing is asked twice
+        assert!(downloader.schedule(&missing(40), &peers, now).is_empty());
+
+        let peer = downloader.received("block1").unwrap();
+        assert!(!downloader.is_pending("block1"));
+        let assigned = downloader.schedule(&missing(40)[1..], &peers, now);
+        assert_eq!(assigned, vec![(peer, format!("block{}", 2 * MAX_BLOCKS_PER_PEER + 1))]);
+
+        //a peer letting its requests expire is reported
+        assert_eq!(downloader.expire(now), Vec::<String>::new());
+        let mut stalled = downloader.expire(now + BLOCK_TIMEOUT);
+        stalled.sort();
+        assert_eq!(stalled, vec!["a", "b"]);
+        assert_eq!(downloader.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut downloader = Downloader::new();
+        let peers = vec![String::from("a"), String::from("b")];
+        downloader.update_peer_height("a", 1);
+        downloader.update_peer_height("b", 1);
+        let mut now = Instant::now();
+
+        //an expired request goes to the other peer first
+        assert_eq!(downloader.schedule(&missing(1), &peers, now)[0].0, "a");
+        now += BLOCK_TIMEOUT;
+        assert!(downloader.expire(now).is_empty());
+        assert_eq!(downloader.schedule(&missing(1), &peers, now)[0].0, "b");
+        for _ in 1..MAX_ATTEMPTS {
+            now += BLOCK_TIMEOUT;
+            downloader.expire(now);
+            downloader.schedule(&missing(1), &peers, now);
+        }
+        //given up until new headers come in
+        assert_eq!(downloader.in_flight(), 0);
+        assert!(downloader.is_given_up("block1"));
+        downloader.clear_attempts();
+        assert!(!downloader.is_given_up("block1"));
+        assert_eq!(downloader.schedule(&missing(1), &peers, now).len(), 1);
+
+        //requests of a disconnected peer are scheduled again
+        let peer = downloader.schedule(&missing(1), &peers, now);
+        assert!(peer.is_empty());
+        downloader.peer_gone("a");
+        downloader.peer_gone("b");
+        downloader.update_peer_height("b", 1);
+        assert_eq!(downloader.schedule(&missing(1), &peers, now), vec![(String::from("b"), String::from("block1"))]);
+    }
+}
@@ -0,0 +1,127 @@
+//! errors returned by the library
+
+use crate::blockchain::BlockError;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+use std::time::SystemTimeError;
+
+/// PokError tells callers what went wrong, so they can react without parsing messages
+#[derive(Debug)]
+pub enum PokError {
+    //the sled database failed
+    Storage(sled::Error),
+    //stored or received data could not be encoded or decoded
+    Serialization(String),
+    //the local file system failed
+    Io(io::Error),
+    //the local clock is before the unix epoch
+    Clock(SystemTimeError),
+    Config(String),
+    //a block, transaction, undo data or agent is missing
+    NotFound(String),
+    //the state of the chain database forbids the operation
+    Chain(String),
+    InvalidBlock(BlockError),
+    InvalidTransaction(String),
+    InsufficientFunds { balance: i32, amount: i32 },
+    //the address is not held by the agent
+    UnknownAddress(String),
+    //the address can not be decoded
+    InvalidAddress(String),
+    //the address is watched by the agent without its secret key
+    WatchOnly(String),
+    InvalidArgument(String),
+    //the passphrase does not open the keystore of the agent
+    WrongPassphrase,
+    //the agent has to be unlocked to use its secret keys
+    Locked,
+    //the node has an agent an import would overwrite
+    AgentExists,
+    //a backup file is damaged or not a backup of an agent
+    InvalidBackup(String),
+    //talking to a peer failed
+    Network(io::Error),
+    //a peer sent a message we do not understand
+    Protocol(String),
+}
+
+impl fmt::Display for PokError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PokError::Storage(err) => write!(f, "storage error: {}", err),
+            PokError::Serialization(msg) => write!(f, "serialization error: {}", msg),
+            PokError::Io(err) => write!(f, "io error: {}", err),
+            PokError::Clock(err) => write!(f, "clock error: {}", err),
+            PokError::Config(msg) => write!(f, "configuration error: {}", msg),
+            PokError::NotFound(msg) => write!(f, "{} is not found", msg),
+            PokError::Chain(msg) => write!(f, "{}", msg),
+            PokError::InvalidBlock(err) => write!(f, "invalid block: {}", err),
+            PokError::InvalidTransaction(msg) => write!(f, "invalid transaction: {}", msg),
+            PokError::InsufficientFunds { balance, amount } => {
+                write!(f, "Not Enough balance: current balance {}, needs {}", balance, amount)
+            }
+            PokError::UnknownAddress(address) => write!(f, "address {} is not held by the agent", address),
+            PokError::InvalidAddress(address) => write!(f, "{} is not a valid address", address),
+            PokError::WatchOnly(address) => write!(f, "address {} is watch-only, its secret key is not held", address),
+            PokError::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            PokError::WrongPassphrase => write!(f, "wrong passphrase"),
+            PokError::Locked => write!(f, "agent is locked, its keys need the passphrase."),
+            PokError::AgentExists => write!(
+                f,
+                "node already has an agent.\nuse --merge to add the keys of the backup to it or --replace to replace it."
+            ),
+            PokError::InvalidBackup(msg) => write!(f, "invalid backup: {}", msg),
+            PokError::Network(err) => write!(f, "network error: {}", err),
+            PokError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PokError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PokError::Storage(err) => Some(err),
+            PokError::Io(err) | PokError::Network(err) => Some(err),
+            PokError::Clock(err) => Some(err),
+            PokError::InvalidBlock(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<sled::Error> for PokError {
+    fn from(err: sled::Error) -> PokError {
+        PokError::Storage(err)
+    }
+}
+
+impl From<bincode::Error> for PokError {
+    fn from(err: bincode::Error) -> PokError {
+        PokError::Serialization(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for PokError {
+    fn from(err: FromUtf8Error) -> PokError {
+        PokError::Serialization(err.to_string())
+    }
+}
+
+impl From<io::Error> for PokError {
+    fn from(err: io::Error) -> PokError {
+        PokError::Io(err)
+    }
+}
+
+impl From<SystemTimeError> for PokError {
+    fn from(err: SystemTimeError) -> PokError {
+        PokError::Clock(err)
+    }
+}
+
+impl From<BlockError> for PokError {
+    fn from(err: BlockError) -> PokError {
+        PokError::InvalidBlock(err)
+    }
+}
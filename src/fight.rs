@@ -1,119 +1,134 @@
-/***
- * 
- * TODO:  how do i combine fight and verification into one process?
- * only by this way we can have users a reason to do verification (for winning fights/coins).
- * maybe let the "random seed" which is necessarily used in a fight can be only produced by the verification process?
- * so that before having a fight users must go complete one verification in the first place.
- * (turn the "random seed" into a sort of *ticket* for the fight)
-*/
-//use rand::Rng;
-//use serde::{Serialize, Deserialize};
-//use crate::agent::*;
-
-/*
-
-pub fn versus(fighter1: &mut Build,fighter2:&mut Build, random_seed:Option<Vec<i32>>) -> FightResult{
-    
-    match random_seed {
-        None => {
-            let mut random_seed : Vec<i32> = vec![];
-
-            let mut rng = rand::thread_rng();
-            while fighter1.current_health() > 0 && fighter2.current_health() > 0 {
-                //-5 ~ 5 inclusively
-                let randomness:i32 = rng.gen_range(-5..=5);
-                random_seed.push(randomness);
-                //decide the first-mover
-                if fighter1.current_action() > fighter2.current_action() {
-                    fighter2.take_damage(fighter1.produce_damage(randomness));
-                    fighter1.take_damage(fighter2.produce_damage(randomness));
-                } else {
-                    fighter1.take_damage(fighter2.produce_damage(randomness));
-                    fighter2.take_damage(fighter1.produce_damage(randomness));
-                }
-                fighter1.regenerate();
-                fighter2.regenerate();
-
-                fighter1.report_point();
-                fighter2.report_point();
-            }
-            return FightResult::make(random_seed);
-        },
-        Some(random_seed) => {
-            for randomness in &random_seed{
-                if fighter1.current_action() > fighter2.current_action() {
-                    fighter2.take_damage(fighter1.produce_damage(*randomness));
-                    fighter1.take_damage(fighter2.produce_damage(*randomness));
-            } else {
-                    fighter1.take_damage(fighter2.produce_damage(*randomness));
-                    fighter2.take_damage(fighter1.produce_damage(*randomness));
-            }
-            fighter1.regenerate();
-            fighter2.regenerate();
-
-            fighter1.report_point();
-            fighter2.report_point();
-            }
-            return FightResult::make(random_seed);
-        },
-    }
+//! duels between two builds
+//!
+//! every duel produces a FightResult carrying everything needed to replay it,
+//! so a node receiving a block can check the fights instead of trusting the kills.
+
+use crate::agent::*;
+use rand::Rng;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+/// side that survived a duel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Winner {
+    Fighter,
+    Opponent,
 }
 
-/**
- * 
- * FightResult ought to store a hash which is converted from maybe the entire serialized data of the fight to verify.
- * by doing that, "VerifyFight()" can compare two hashes to check if the local version of the fight's hash differs from the verified one.
- * 
- **/
+/// damage dealt by each side during one round
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Round {
+    pub fighter_damage: i32,
+    pub opponent_damage: i32,
+}
 
- #[derive(Serialize, Deserialize)]
+/// transcript of a duel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FightResult {
-    winner: String,
+    //randomness drawn for each round, -5 ~ 5 inclusively
+    pub random_seed: Vec<i32>,
+    //builds as they were before the duel
+    pub fighter: Build,
+    pub opponent: Build,
+    pub rounds: Vec<Round>,
+    pub winner: Winner,
 }
 
 impl FightResult {
-    pub fn make(random_seed:Vec<i32>) -> FightResult {
-        println!("random sequence:{:#?}",random_seed);
-        FightResult {
-            winner:String::from("unimplemented"),
-        }
-
+    /// true if the fighter killed its opponent
+    pub fn is_opponent_killed(&self) -> bool {
+        self.winner == Winner::Fighter
     }
 }
 
-fn VerifyFight(random_seed: Vec<i32>,fighter1:&mut Build,fighter2:&mut Build, original_result:&FightResult) -> bool{
-    //TODO
-    true
+/// versus() runs a duel, drawing the randomness of each round from rng.
+pub fn versus(fighter: &Build, opponent: &Build, rng: &mut Pcg64) -> FightResult {
+    duel(fighter, opponent, std::iter::repeat_with(|| rng.gen_range(-5..=5)))
+}
+
+/// replays the duel from its random seed and returns true if it ends up with the same transcript.
+pub fn verify_fight(original_result: &FightResult) -> bool {
+    let replayed = duel(
+        &original_result.fighter,
+        &original_result.opponent,
+        original_result.random_seed.iter().copied(),
+    );
+    replayed == *original_result
 }
 
+fn duel<I: Iterator<Item = i32>>(fighter: &Build, opponent: &Build, mut randomness: I) -> FightResult {
+    let mut myself = fighter.clone();
+    let mut enemy = opponent.clone();
+    let mut random_seed = Vec::new();
+    let mut rounds = Vec::new();
+
+    while myself.get_health() > 0 && enemy.get_health() > 0 {
+        let r = match randomness.next() {
+            Some(r) => r,
+            None => break,
+        };
+        random_seed.push(r);
+        let fighter_damage;
+        let opponent_damage;
+        //decide the first-mover
+        if myself.current_action() > enemy.current_action() {
+            fighter_damage = myself.produce_damage(r);
+            enemy.take_damage(fighter_damage);
+            opponent_damage = enemy.produce_damage(r);
+            myself.take_damage(opponent_damage);
+        } else {
+            opponent_damage = enemy.produce_damage(r);
+            myself.take_damage(opponent_damage);
+            fighter_damage = myself.produce_damage(r);
+            enemy.take_damage(fighter_damage);
+        }
+        rounds.push(Round {
+            fighter_damage,
+            opponent_damage,
+        });
+
+        myself.report_health();
+        enemy.report_health();
+    }
+
+    let winner = if myself.check_death() > 0 {
+        Winner::Fighter
+    } else {
+        Winner::Opponent
+    };
+    FightResult {
+        random_seed,
+        fighter: fighter.clone(),
+        opponent: opponent.clone(),
+        rounds,
+        winner,
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::crypto::generator_from_sha256;
 
     #[test]
     fn test_fight() {
-        let mut warrior = Warrior::create("Axe the Warrior".to_owned(),100,10,3,2,18);
-
-        let mut mage = Mage::create(String::from("Collin the Mage"),55,2,2,50,5);
-    
-        let r = vec![
-            1,
-            -3,
-            -2,
-            -4,
-            -2,
-            4,
-            4,
-            5,
-            -5,
-            -3,
-            2,
-            -3,
-        ];
-        
-        let result = versus( &mut warrior, &mut mage, Some(r));
-    
+        let warrior = Build::new("Axe the Warrior".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+        let mage = Build::new("Collin the Mage".to_owned(), "Mage".to_owned(), "Wand".to_owned());
+
+        let mut rng = generator_from_sha256(&b"test_fight"[..]).unwrap();
+        let result = versus(&warrior, &mage, &mut rng);
+        assert_eq!(result.random_seed.len(), result.rounds.len());
+        assert!(verify_fight(&result));
+
+        let mut forged = result.clone();
+        forged.winner = match result.winner {
+            Winner::Fighter => Winner::Opponent,
+            Winner::Opponent => Winner::Fighter,
+        };
+        assert!(!verify_fight(&forged));
+
+        let mut forged = result;
+        forged.rounds[0].fighter_damage += 1;
+        assert!(!verify_fight(&forged));
     }
 }
-*/
\ No newline at end of file
@@ -0,0 +1,97 @@
+//! hierarchical deterministic keys
+//!
+//! addresses of an agent are derived from the seed of its mnemonic the SLIP-10 way for ed25519,
+//! where every step of a derivation path is hardened.
+
+use ::crypto::hmac::Hmac;
+use ::crypto::mac::Mac;
+use ::crypto::sha2::Sha512;
+use ::crypto::util::secure_memset;
+
+/// indexes at or above HARDENED are hardened, the only kind ed25519 derivation knows
+pub const HARDENED: u32 = 0x8000_0000;
+/// purpose and coin type leading the derivation path of every address, m/44'/1337'
+pub const PURPOSE: u32 = 44;
+pub const COIN_TYPE: u32 = 1337;
+
+const MASTER_KEY: &[u8] = b"ed25519 seed";
+
+/// ExtendedKey is a secret key along with the chain code deriving its children
+pub struct ExtendedKey {
+    pub secret_key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    /// master key of seed
+    pub fn master(seed: &[u8]) -> ExtendedKey {
+        ExtendedKey::from_hmac(MASTER_KEY, &[seed])
+    }
+
+    /// hardened child number index, index is hardened if it is not already
+    pub fn child(&self, index: u32) -> ExtendedKey {
+        let index = (index | HARDENED).to_be_bytes();
+        ExtendedKey::from_hmac(&self.chain_code, &[&[0], &self.secret_key, &index])
+    }
+
+    /// follows path down from the master key of seed
+    pub fn derive(seed: &[u8], path: &[u32]) -> ExtendedKey {
+        let mut key = ExtendedKey::master(seed);
+        for index in path {
+            key = key.child(*index);
+        }
+        key
+    }
+
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> ExtendedKey {
+        let mut hmac = Hmac::new(Sha512::new(), key);
+        for chunk in data {
+            hmac.input(chunk);
+        }
+        let mut output = [0; 64];
+        hmac.raw_result(&mut output);
+        let mut extended = ExtendedKey {
+            secret_key: [0; 32],
+            chain_code: [0; 32],
+        };
+        extended.secret_key.copy_from_slice(&output[..32]);
+        extended.chain_code.copy_from_slice(&output[32..]);
+        secure_memset(&mut output, 0);
+        extended
+    }
+}
+
+impl Drop for ExtendedKey {
+    fn drop(&mut self) {
+        secure_memset(&mut self.secret_key, 0);
+        secure_memset(&mut self.chain_code, 0);
+    }
+}
+
+/// derivation path of address number index of an agent, m/44'/1337'/0'/0'/index'
+pub fn address_path(index: u32) -> [u32; 5] {
+    [PURPOSE, COIN_TYPE, 0, 0, index]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_slip10_vector() {
+        //test vector 1 for ed25519 of SLIP-0010
+        let seed: Vec<u8> = (0..16).collect();
+        let master = ExtendedKey::master(&seed);
+        assert_eq!(hex(&master.secret_key), "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7");
+        assert_eq!(hex(&master.chain_code), "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb");
+
+        let child = ExtendedKey::derive(&seed, &[0]);
+        assert_eq!(hex(&child.secret_key), "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3");
+        assert_eq!(hex(&child.chain_code), "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69");
+        assert_eq!(ExtendedKey::derive(&seed, &[HARDENED]).secret_key, child.secret_key);
+    }
+}
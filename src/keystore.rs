@@ -0,0 +1,171 @@
+//! encryption of the secrets an agent keeps on disk
+//!
+//! a passphrase is stretched with scrypt into a key that seals secrets with AES-256-GCM.
+
+use crate::PokError;
+use crate::Result;
+use ::crypto::aead::{AeadDecryptor, AeadEncryptor};
+use ::crypto::aes::KeySize;
+use ::crypto::aes_gcm::AesGcm;
+use ::crypto::scrypt::{scrypt, ScryptParams};
+use ::crypto::util::secure_memset;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+//sealed by every keystore so a passphrase can be checked before any key is there
+const CHECK_PLAINTEXT: &[u8] = b"proof-of-kill keystore";
+
+/// cost of deriving a key from a passphrase, 2^log_n rounds of scrypt
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams { log_n: 15, r: 8, p: 1 }
+    }
+}
+
+/// Sealed is a secret encrypted and authenticated under a key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sealed {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+/// key derived from a passphrase, wiped from memory when dropped
+#[derive(Clone)]
+pub struct SecretKey([u8; KEY_LEN]);
+
+impl SecretKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<SecretKey> {
+        if bytes.len() != KEY_LEN {
+            return Err(PokError::Serialization(String::from("keystore key has a wrong length")));
+        }
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(bytes);
+        Ok(SecretKey(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// encrypts plaintext, aad is authenticated along with it and has to be given back to open it
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Sealed {
+        let mut nonce = vec![0; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let mut ciphertext = vec![0; plaintext.len()];
+        let mut tag = vec![0; TAG_LEN];
+        AesGcm::new(KeySize::KeySize256, &self.0, &nonce, aad).encrypt(plaintext, &mut ciphertext, &mut tag);
+        Sealed { nonce, ciphertext, tag }
+    }
+
+    /// decrypts sealed, None if it was sealed under another key or tampered with
+    pub fn open(&self, sealed: &Sealed, aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.nonce.len() != NONCE_LEN || sealed.tag.len() != TAG_LEN {
+            return None;
+        }
+        let mut plaintext = vec![0; sealed.ciphertext.len()];
+        if AesGcm::new(KeySize::KeySize256, &self.0, &sealed.nonce, aad).decrypt(
+            &sealed.ciphertext,
+            &mut plaintext,
+            &sealed.tag,
+        ) {
+            Some(plaintext)
+        } else {
+            None
+        }
+    }
+}
+
+//the key never shows up in logs
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        secure_memset(&mut self.0, 0);
+    }
+}
+
+/// Keystore holds what it takes to derive the key of a passphrase and to check it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Keystore {
+    params: KdfParams,
+    salt: Vec<u8>,
+    check: Sealed,
+}
+
+impl Keystore {
+    /// creates a keystore for passphrase, returning it with the derived key
+    pub fn new(passphrase: &str, params: KdfParams) -> (Keystore, SecretKey) {
+        let mut salt = vec![0; SALT_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        let key = derive_key(passphrase, &salt, &params);
+        let check = key.seal(CHECK_PLAINTEXT, &salt);
+        (Keystore { params, salt, check }, key)
+    }
+
+    /// derives the key of passphrase, an error tells the passphrase is wrong
+    pub fn unlock(&self, passphrase: &str) -> Result<SecretKey> {
+        let key = derive_key(passphrase, &self.salt, &self.params);
+        self.verify(&key)?;
+        Ok(key)
+    }
+
+    /// checks that key was derived from the passphrase of this keystore
+    pub fn verify(&self, key: &SecretKey) -> Result<()> {
+        match key.open(&self.check, &self.salt) {
+            Some(plaintext) if plaintext == CHECK_PLAINTEXT => Ok(()),
+            _ => Err(PokError::WrongPassphrase),
+        }
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: &KdfParams) -> SecretKey {
+    let mut key = [0; KEY_LEN];
+    scrypt(
+        passphrase.as_bytes(),
+        salt,
+        &ScryptParams::new(params.log_n, params.r, params.p),
+        &mut key,
+    );
+    SecretKey(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keystore() {
+        let params = KdfParams { log_n: 4, r: 8, p: 1 };
+        let (keystore, key) = Keystore::new("open sesame", params);
+        assert_eq!(keystore.unlock("open sesame").unwrap().as_bytes(), key.as_bytes());
+        assert!(matches!(keystore.unlock("open barley"), Err(PokError::WrongPassphrase)));
+
+        let sealed = key.seal(b"secret", b"address");
+        assert_ne!(sealed.ciphertext, b"secret".to_vec());
+        assert_eq!(key.open(&sealed, b"address"), Some(b"secret".to_vec()));
+        assert_eq!(key.open(&sealed, b"another address"), None);
+        let (_, other) = Keystore::new("open sesame", params);
+        assert_eq!(other.open(&sealed, b"address"), None);
+
+        let mut tampered = sealed;
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(key.open(&tampered, b"address"), None);
+    }
+}
@@ -0,0 +1,48 @@
+//! a demonstration of PoK(Proof-of-Kill) consensus model
+//!
+//! the library holds everything a node needs: the blockchain and its UTXO set,
+//! agents holding keys and builds, transactions and the p2p server.
+//! the `proof-of-kill` binary is a command line front end over it.
+
+pub mod addrbook;
+pub mod agent;
+pub mod block;
+pub mod blockchain;
+pub mod config;
+pub mod download;
+mod crypto;
+pub mod error;
+pub mod fight;
+pub mod hd;
+pub mod keystore;
+pub mod mempool;
+pub mod miner;
+pub mod protocol;
+pub mod server;
+pub mod transaction;
+pub mod utxoset;
+
+//for use of 'info!()'
+#[macro_use]
+extern crate log;
+
+pub use crate::error::PokError;
+
+pub type Result<T> = std::result::Result<T, PokError>;
+
+/// opens the sled database at path.
+///
+/// sled lets go of the file lock of a dropped database from a background thread,
+/// so opening it again right away is retried for a moment.
+pub(crate) fn open_db<P: AsRef<std::path::Path>>(path: P) -> Result<sled::Db> {
+    let mut attempts = 0;
+    loop {
+        match sled::open(path.as_ref()) {
+            Err(sled::Error::Io(_)) if attempts < 50 => {
+                attempts += 1;
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            db => return Ok(db?),
+        }
+    }
+}
@@ -1,22 +1,12 @@
-mod block;
-mod blockchain;
-mod agent;
 mod cli;
-mod crypto;
-mod fight;
-
-mod server;
-mod transaction;
-mod utxoset;
 
 //for use of 'info!()'
 #[macro_use]
 extern crate log;
 
-pub type Result<T> = std::result::Result<T, failure::Error>;
-
 use crate::cli::Cli;
 use env_logger::Env;
+use proof_of_kill::Result;
 
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("warning")).init();
@@ -0,0 +1,474 @@
+//! pool of the transactions waiting for a block
+//!
+//! a transaction enters the pool only if it spends outputs of the UTXO set, or of
+//! pooled transactions, that no other pooled transaction spends already.
+//! the pool is capped in bytes and evicts the transactions paying the lowest fee rate first,
+//! a transaction waiting longer than its expiry is dropped with everything spending it.
+
+use super::*;
+use crate::block::Block;
+use crate::transaction::*;
+use crate::utxoset::UTXOSet;
+use crate::PokError;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// bytes of serialized transactions the pool holds at most
+pub const MAX_MEMPOOL_BYTES: usize = 4_000_000;
+/// time a transaction may wait for a block before it is dropped
+pub const MEMPOOL_EXPIRY: Duration = Duration::from_secs(72 * 60 * 60);
+
+//an output, by the id of its transaction and its index
+type OutPoint = (String, i32);
+
+#[derive(Debug, Clone)]
+struct Entry {
+    tx: Transaction,
+    //serialized size
+    size: usize,
+    //inputs minus outputs
+    fee: i32,
+    added: Instant,
+    //order of admission, a transaction always comes after the pooled ones it spends
+    sequence: u64,
+    //pooled transactions this one spends outputs of
+    parents: HashSet<String>,
+}
+
+impl Entry {
+    //compares the fees paid per byte
+    fn cmp_fee_rate(&self, other: &Entry) -> Ordering {
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+/// Mempool holds validated transactions that are not in the chain yet
+#[derive(Debug)]
+pub struct Mempool {
+    entries: HashMap<String, Entry>,
+    //outpoint to the pooled transaction spending it
+    spent: HashMap<OutPoint, String>,
+    bytes: usize,
+    next_sequence: u64,
+    max_bytes: usize,
+    expiry: Duration,
+}
+
+impl Default for Mempool {
+    fn default() -> Mempool {
+        Mempool::with_limits(MAX_MEMPOOL_BYTES, MEMPOOL_EXPIRY)
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    pub fn with_limits(max_bytes: usize, expiry: Duration) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            bytes: 0,
+            next_sequence: 0,
+            max_bytes,
+            expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// serialized size of the pooled transactions
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.entries.get(txid).map(|entry| &entry.tx)
+    }
+
+    /// returns the fee a pooled transaction pays
+    pub fn get_fee(&self, txid: &str) -> Option<i32> {
+        self.entries.get(txid).map(|entry| entry.fee)
+    }
+
+    /// pooled transactions, each after the pooled ones it spends
+    pub fn transactions(&self) -> Vec<Transaction> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries.into_iter().map(|entry| entry.tx.clone()).collect()
+    }
+
+    /// block_template() picks the transactions of the next block, highest fee rate first,
+    /// as long as they fit in max_bytes.
+    ///
+    /// only transactions spending confirmed outputs are picked,
+    /// those spending pooled ones go into the blocks after their parents.
+    pub fn block_template(&self, max_bytes: usize) -> Vec<Transaction> {
+        let mut entries: Vec<&Entry> = self.entries.values().filter(|entry| entry.parents.is_empty()).collect();
+        entries.sort_by(|a, b| b.cmp_fee_rate(a).then(a.sequence.cmp(&b.sequence)));
+        let mut size = 0;
+        let mut txs = Vec::new();
+        for entry in entries {
+            if size + entry.size <= max_bytes {
+                size += entry.size;
+                txs.push(entry.tx.clone());
+            }
+        }
+        txs
+    }
+
+    /// add() validates tx against the UTXO set and the pool and keeps it.
+    ///
+    /// a transaction already pooled is ignored. if the pool outgrows its cap the transactions
+    /// paying the lowest fee rate are evicted, an error tells if tx was one of them.
+    pub fn add(&mut self, tx: Transaction, utxo: &UTXOSet, now: Instant) -> Result<()> {
+        if self.contains(&tx.id) {
+            return Ok(());
+        }
+        let txid = tx.id.clone();
+        self.admit(tx, utxo, now)?;
+        self.evict();
+        if !self.contains(&txid) {
+            return Err(PokError::InvalidTransaction(format!("{} evicted, the mempool is full", txid)));
+        }
+        Ok(())
+    }
+
+    fn admit(&mut self, tx: Transaction, utxo: &UTXOSet, added: Instant) -> Result<()> {
+        let (parents, fee) = self.check(&tx, utxo)?;
+        let size = tx.size()?;
+        if size > self.max_bytes {
+            return Err(PokError::InvalidTransaction(format!("{} is larger than the mempool", tx.id)));
+        }
+        for vin in &tx.vin {
+            self.spent.insert((vin.txid.clone(), vin.vout), tx.id.clone());
+        }
+        self.bytes += size;
+        self.entries.insert(
+            tx.id.clone(),
+            Entry {
+                tx,
+                size,
+                fee,
+                added,
+                sequence: self.next_sequence,
+                parents,
+            },
+        );
+        self.next_sequence += 1;
+        Ok(())
+    }
+
+    /// check() returns the pooled transactions tx spends and the fee it pays,
+    /// an error if tx is malformed, spends an output that is not available or does not verify
+    fn check(&self, tx: &Transaction, utxo: &UTXOSet) -> Result<(HashSet<String>, i32)> {
+        if tx.is_coinbase() {
+            return Err(PokError::InvalidTransaction(format!("coinbase {} outside of a block", tx.id)));
+        }
+        if tx.vin.is_empty() || tx.vout.is_empty() {
+            return Err(PokError::InvalidTransaction(format!("{} has no inputs or no outputs", tx.id)));
+        }
+
+        let mut parents = HashSet::new();
+        let mut prev_txs = HashMap::new();
+        let mut outpoints = HashSet::new();
+        //pooled transactions go into the block on top of the tip at the earliest
+        let spend_height = utxo.blockchain.get_best_height()?.wrapping_add(1);
+        for vin in &tx.vin {
+            let outpoint = (vin.txid.clone(), vin.vout);
+            if !outpoints.insert(outpoint.clone()) {
+                return Err(PokError::InvalidTransaction(format!(
+                    "{} spends {}:{} twice",
+                    tx.id, vin.txid, vin.vout
+                )));
+            }
+            if let Some(spender) = self.spent.get(&outpoint) {
+                return Err(PokError::InvalidTransaction(format!(
+                    "{} double spends {}:{}, already spent by {}",
+                    tx.id, vin.txid, vin.vout, spender
+                )));
+            }
+            match self.entries.get(&vin.txid) {
+                Some(parent) => {
+                    parents.insert(vin.txid.clone());
+                    prev_txs.insert(vin.txid.clone(), parent.tx.clone());
+                }
+                None => {
+                    let outs = match utxo.get_outputs(&vin.txid)? {
+                        Some(outs) if outs.outputs.contains_key(&vin.vout) => outs,
+                        _ => {
+                            return Err(PokError::InvalidTransaction(format!(
+                                "{} spends {}:{} which is not unspent",
+                                tx.id, vin.txid, vin.vout
+                            )))
+                        }
+                    };
+                    if !outs.is_mature(spend_height) {
+                        return Err(PokError::InvalidTransaction(format!(
+                            "{} spends the coinbase {} before it matured",
+                            tx.id, vin.txid
+                        )));
+                    }
+                    if !prev_txs.contains_key(&vin.txid) {
+                        prev_txs.insert(vin.txid.clone(), utxo.blockchain.find_transacton(&vin.txid)?);
+                    }
+                }
+            }
+        }
+
+        let fee = tx.fee(&prev_txs)?;
+        if !tx.verify(prev_txs)? {
+            return Err(PokError::InvalidTransaction(format!("{} does not verify", tx.id)));
+        }
+        Ok((parents, fee))
+    }
+
+    /// remove_block() takes the transactions of a connected block out of the pool,
+    /// along with the pooled ones spending the same outputs and their descendants.
+    /// returns the ids of the transactions dropped as conflicts.
+    pub fn remove_block(&mut self, block: &Block) -> Vec<String> {
+        for tx in block.get_transaction() {
+            self.remove(&tx.id);
+        }
+        let mut conflicts = Vec::new();
+        for tx in block.get_transaction().iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in &tx.vin {
+                if let Some(spender) = self.spent.get(&(vin.txid.clone(), vin.vout)).cloned() {
+                    info!("drop {} from the mempool, it conflicts with {}", spender, tx.id);
+                    conflicts.extend(self.remove_with_descendants(&spender));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// expire() drops the transactions older than the expiry and their descendants,
+    /// returns their ids
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| entry.added + self.expiry <= now)
+            .map(|entry| entry.tx.id.clone())
+            .collect();
+        let mut removed = Vec::new();
+        for txid in expired {
+            if self.contains(&txid) {
+                info!("transaction {} expired from the mempool", txid);
+                removed.extend(self.remove_with_descendants(&txid));
+            }
+        }
+        removed
+    }
+
+    /// readmit() validates the pool again after a reorganization, together with the
+    /// transactions of the disconnected blocks that did not make it into the new chain.
+    /// transactions that are no longer valid are dropped.
+    pub fn readmit(&mut self, orphaned: Vec<Transaction>, utxo: &UTXOSet, now: Instant) {
+        let mut pooled: Vec<Entry> = self.entries.drain().map(|(_, entry)| entry).collect();
+        pooled.sort_by_key(|entry| entry.sequence);
+        self.spent.clear();
+        self.bytes = 0;
+
+        //orphaned transactions were confirmed before the pooled ones and go first,
+        //they come from the tip backwards so one may spend another coming later
+        let mut pending: Vec<(Transaction, Instant)> = orphaned.into_iter().map(|tx| (tx, now)).collect();
+        pending.extend(pooled.into_iter().map(|entry| (entry.tx, entry.added)));
+        loop {
+            let waiting = pending.len();
+            pending.retain(|(tx, added)| !self.contains(&tx.id) && self.admit(tx.clone(), utxo, *added).is_err());
+            if pending.is_empty() || pending.len() == waiting {
+                break;
+            }
+        }
+        for (tx, _) in pending {
+            debug!("drop {} from the mempool after a reorganization", tx.id);
+        }
+        self.evict();
+    }
+
+    //evicts the transactions paying the lowest fee rate, the oldest first among equals,
+    //with their descendants until the pool fits its cap
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let cheapest = match self
+                .entries
+                .values()
+                .min_by(|a, b| a.cmp_fee_rate(b).then(a.sequence.cmp(&b.sequence)))
+            {
+                Some(entry) => entry.tx.id.clone(),
+                None => break,
+            };
+            info!("evict {} from the full mempool", cheapest);
+            self.remove_with_descendants(&cheapest);
+        }
+    }
+
+    //removes txid and the pooled transactions spending it, directly or not, returns their ids
+    fn remove_with_descendants(&mut self, txid: &str) -> Vec<String> {
+        let mut removed = Vec::new();
+        let mut queue = vec![txid.to_owned()];
+        while let Some(txid) = queue.pop() {
+            if let Some(entry) = self.remove(&txid) {
+                queue.extend(self.children(&entry.tx));
+                removed.push(txid);
+            }
+        }
+        removed
+    }
+
+    //pooled transactions spending outputs of tx
+    fn children(&self, tx: &Transaction) -> Vec<String> {
+        (0..tx.vout.len() as i32)
+            .filter_map(|vout| self.spent.get(&(tx.id.clone(), vout)).cloned())
+            .collect()
+    }
+
+    //removes txid alone, the transactions spending it now spend a confirmed output
+    fn remove(&mut self, txid: &str) -> Option<Entry> {
+        let entry = self.entries.remove(txid)?;
+        for vin in &entry.tx.vin {
+            self.spent.remove(&(vin.txid.clone(), vin.vout));
+        }
+        for child in self.children(&entry.tx) {
+            if let Some(child) = self.entries.get_mut(&child) {
+                child.parents.remove(txid);
+            }
+        }
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::agent::*;
+    use crate::block::MAX_BLOCK_TX_BYTES;
+    use crate::blockchain::Blockchain;
+    use crate::config::NodeConfig;
+    use crate::miner::MinerContext;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    //spends output vout of parent, signed by keypair
+    fn spend(parent: &Transaction, vout: i32, keypair: &Keypair, to: &str, value: i32, build: &Build) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: parent.id.clone(),
+                vout,
+                signature: Vec::new(),
+                pub_key: keypair.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(value, to.to_owned()).unwrap()],
+            sender_build: build.clone(),
+        };
+        tx.id = tx.hash().unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(parent.id.clone(), parent.clone());
+        tx.sign(&keypair.secret_key, prev_txs).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_mempool() {
+        let mut config = NodeConfig::new("test");
+        config.data_dir = std::env::temp_dir().join("pok_test_mempool");
+        config.keystore_log_n = 4;
+        std::fs::remove_dir_all(&config.data_dir).ok();
+
+        let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+        let mut agent = Agent::new(build.clone(), "passphrase", &config).unwrap();
+        let addr1 = agent.generate_address().unwrap();
+        let addr2 = agent.generate_address().unwrap();
+        let k1 = agent.get_keypair_by_address(&addr1).unwrap().clone();
+        let k2 = agent.get_keypair_by_address(&addr2).unwrap().clone();
+        let mut miner = MinerContext::new("tester".to_owned(), build.clone(), addr1.clone(), StdRng::seed_from_u64(3));
+        let mut utxo = UTXOSet {
+            blockchain: Blockchain::init(&mut miner, &config).unwrap(),
+        };
+        utxo.reindex().unwrap();
+        let now = Instant::now();
+
+        //the genesis coinbase cannot be spent before it matured
+        let genesis = utxo.blockchain.iter().next().unwrap();
+        let coinbase = genesis.get_transaction()[0].clone();
+        let early = spend(&coinbase, 0, &k1, &addr2, 10, &build);
+        assert!(matches!(Mempool::new().add(early, &utxo, now), Err(PokError::InvalidTransaction(_))));
+        let mut filler = MinerContext::new("filler".to_owned(), build.clone(), Keypair::new().address(), StdRng::seed_from_u64(4));
+        for _ in 1..COINBASE_MATURITY {
+            let block = utxo.blockchain.mine_block(&mut filler, Vec::new()).unwrap();
+            utxo.update(&block).unwrap();
+        }
+
+        //both spend the genesis coinbase
+        let tx_a = Transaction::send(&k1, &addr2, 4, DEFAULT_FEE_RATE, &utxo, build.clone()).unwrap();
+        let tx_b = Transaction::send(&k1, &addr2, 5, DEFAULT_FEE_RATE, &utxo, build.clone()).unwrap();
+        let mut mempool = Mempool::new();
+        mempool.add(tx_a.clone(), &utxo, now).unwrap();
+        mempool.add(tx_a.clone(), &utxo, now).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(matches!(mempool.add(tx_b.clone(), &utxo, now), Err(PokError::InvalidTransaction(_))));
+
+        //a child of a pooled transaction waits for its parent to be mined
+        let inflated = spend(&tx_a, 0, &k2, &addr1, 5, &build);
+        assert!(matches!(mempool.add(inflated, &utxo, now), Err(PokError::InvalidTransaction(_))));
+        let stolen = spend(&tx_a, 1, &k2, &addr2, 6, &build);
+        assert!(mempool.add(stolen, &utxo, now).is_err());
+        let child = spend(&tx_a, 0, &k2, &addr1, 4, &build);
+        mempool.add(child.clone(), &utxo, now).unwrap();
+        assert_eq!(mempool.block_template(MAX_BLOCK_TX_BYTES), vec![tx_a.clone()]);
+        assert_eq!(mempool.get_fee(&child.id), Some(0));
+        assert_eq!(mempool.transactions(), vec![tx_a.clone(), child.clone()]);
+
+        //a block spending the same output drops the conflict and its descendants
+        let block = utxo.blockchain.mine_block(&mut miner, vec![tx_b.clone()]).unwrap();
+        utxo.update(&block).unwrap();
+        let mut conflicts = mempool.remove_block(&block);
+        conflicts.sort();
+        let mut expected = vec![tx_a.id.clone(), child.id.clone()];
+        expected.sort();
+        assert_eq!(conflicts, expected);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+
+        //the best paying transactions go first, the worst paying one is evicted from a full pool
+        let tx_c = Transaction::send(&k1, &addr2, 1, 5, &utxo, build.clone()).unwrap();
+        let tx_d = Transaction::send(&k2, &addr1, 1, 0, &utxo, build.clone()).unwrap();
+        let mut mempool = Mempool::new();
+        mempool.add(tx_d.clone(), &utxo, now).unwrap();
+        mempool.add(tx_c.clone(), &utxo, now).unwrap();
+        assert_eq!(mempool.block_template(MAX_BLOCK_TX_BYTES), vec![tx_c.clone(), tx_d.clone()]);
+        assert_eq!(mempool.block_template(tx_c.size().unwrap()), vec![tx_c.clone()]);
+
+        let max_bytes = tx_c.size().unwrap() + tx_d.size().unwrap() - 1;
+        let mut full = Mempool::with_limits(max_bytes, Duration::from_secs(60));
+        full.add(tx_c.clone(), &utxo, now).unwrap();
+        assert!(matches!(full.add(tx_d.clone(), &utxo, now), Err(PokError::InvalidTransaction(_))));
+        assert!(full.contains(&tx_c.id));
+        assert!(full.expire(now + Duration::from_secs(59)).is_empty());
+        assert_eq!(full.expire(now + Duration::from_secs(60)), vec![tx_c.id.clone()]);
+
+        //only the transactions included in a block leave the pool
+        let block = utxo.blockchain.mine_block(&mut miner, vec![tx_c.clone()]).unwrap();
+        utxo.update(&block).unwrap();
+        assert!(mempool.remove_block(&block).is_empty());
+        assert_eq!(mempool.transactions(), vec![tx_d]);
+
+        drop(utxo);
+        std::fs::remove_dir_all(&config.data_dir).ok();
+    }
+}
@@ -0,0 +1,46 @@
+//! context blocks are mined in
+//!
+//! everything block building needs about the miner lives here, so blocks can be
+//! built for any agent without reading it from the disk or the environment.
+
+use super::*;
+use crate::agent::*;
+use crate::transaction::Transaction;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// MinerContext is the champion fighting for new blocks and where their reward goes
+pub struct MinerContext {
+    pub agent_id: String,
+    pub build: Build,
+    //address the coinbase of mined blocks pays to
+    pub payout_address: String,
+    //source of the coinbase randomness
+    pub rng: StdRng,
+}
+
+impl MinerContext {
+    pub fn new(agent_id: String, build: Build, payout_address: String, rng: StdRng) -> MinerContext {
+        MinerContext {
+            agent_id,
+            build,
+            payout_address,
+            rng,
+        }
+    }
+
+    /// from_agent() mines for an agent, the RNG is seeded by the OS
+    pub fn from_agent(agent: &Agent, payout_address: &str) -> MinerContext {
+        MinerContext::new(
+            agent.get_id().to_owned(),
+            agent.get_build().clone(),
+            payout_address.to_owned(),
+            StdRng::from_entropy(),
+        )
+    }
+
+    /// coinbase() creates the transaction paying the block reward to payout_address
+    pub fn coinbase(&mut self, data: String, reward: i32) -> Result<Transaction> {
+        Transaction::new_coinbase_with_rng(self.payout_address.clone(), data, reward, &mut self.rng)
+    }
+}
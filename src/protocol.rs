@@ -0,0 +1,518 @@
+//! wire protocol spoken between nodes
+//!
+//! every message travels in a frame:
+//! magic (4 bytes) | command (12 bytes, NUL padded) | payload length (4 bytes, LE) | checksum (4 bytes) | payload
+//!
+//! the checksum is the start of the double SHA256 of the payload, so a truncated or
+//! corrupted frame is rejected instead of being decoded as something else.
+//!
+//! the magic tells networks apart, nodes of different networks can't read each other.
+//! a connection opens with a version/verack handshake, see `Versionmsg`.
+
+use super::*;
+use crate::block::{Block, BlockHeader};
+use crate::transaction::Transaction;
+use crate::PokError;
+use ::crypto::digest::Digest;
+use ::crypto::sha2::Sha256;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// version of the protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 2;
+/// peers speaking an older protocol are disconnected
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+/// the peer serves blocks of the full chain
+pub const SERVICE_NETWORK: u64 = 1;
+/// the peer understands compact block relay
+pub const SERVICE_COMPACT_BLOCKS: u64 = 1 << 1;
+pub const COMMAND_LEN: usize = 12;
+/// frames announcing a bigger payload are refused before anything is read
+pub const MAX_PAYLOAD_LEN: usize = 32 * 1024 * 1024;
+/// headers carried by one headers message at most
+pub const MAX_HEADERS: usize = 2000;
+const CHECKSUM_LEN: usize = 4;
+const MAGIC_LEN: usize = 4;
+const HEADER_LEN: usize = MAGIC_LEN + COMMAND_LEN + 4 + CHECKSUM_LEN;
+
+/// Network a node belongs to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    //local networks for development
+    Regtest,
+}
+
+impl Network {
+    /// bytes opening every frame sent on the network
+    pub fn magic(self) -> [u8; MAGIC_LEN] {
+        match self {
+            Network::Mainnet => [0xf0, 0x4b, 0x11, 0x1d],
+            Network::Testnet => [0xf0, 0x4b, 0x7e, 0x57],
+            Network::Regtest => [0xf0, 0x4b, 0x4e, 0x67],
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = PokError;
+
+    fn from_str(s: &str) -> Result<Network> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(PokError::Config(format!("unknown network {}", s))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message {
+    Addr(Vec<String>),
+    Version(Versionmsg),
+    //acknowledges the version of the peer
+    Verack,
+    Tx(Txmsg),
+    GetData(GetDatamsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
+    Inv(Invmsg),
+    Block(Blockmsg),
+    //requests of the node owner, only accepted from the host the node runs on
+    Unlock(Unlockmsg),
+    Lock,
+    //answers Unlock and Lock
+    Session(Sessionmsg),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Blockmsg {
+    pub block: Block,
+}
+
+/// GetHeadersmsg asks for the headers of the main chain of the peer following our chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GetHeadersmsg {
+    //block locator of our best chain, see `Blockchain::locator`
+    pub locator: Vec<String>,
+    //hash of the last header wanted, empty for as many as fit in a message
+    pub stop: String,
+}
+
+/// Headersmsg answers GetHeadersmsg with up to MAX_HEADERS headers, parents first
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Headersmsg {
+    pub headers: Vec<BlockHeader>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GetDatamsg {
+    pub kind: String,
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Invmsg {
+    pub kind: String,
+    pub items: Vec<String>,
+}
+
+/// Unlockmsg asks the node to unlock the keys of its agent for timeout_secs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Unlockmsg {
+    pub passphrase: String,
+    pub timeout_secs: u64,
+}
+
+/// Sessionmsg tells whether the agent of the node is unlocked after an Unlock or Lock
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sessionmsg {
+    //seconds the agent stays unlocked, 0 while it is locked
+    pub unlocked_secs: u64,
+    //why the request failed, empty if it did not
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Txmsg {
+    pub transaction: Transaction,
+}
+
+/// Versionmsg opens every connection.
+///
+/// each side sends its version first and answers the version of the other side
+/// with a verack. a peer on another network or speaking a protocol older than
+/// MIN_PROTOCOL_VERSION is disconnected, so is a peer sending anything before its version.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Versionmsg {
+    pub version: u32,
+    pub network: Network,
+    //SERVICE_* flags the sender offers
+    pub services: u64,
+    //software of the sender, e.g. /proof-of-kill:0.1.0/
+    pub user_agent: String,
+    //address the sender listens on
+    pub addr_from: String,
+    //current height of the blockchain
+    pub best_height: u128,
+    //total kills included by current blockchain
+    pub kills: u128,
+}
+
+impl Versionmsg {
+    /// checks the peer can talk to a node of network
+    pub fn check_compatible(&self, network: Network) -> Result<()> {
+        if self.network != network {
+            return Err(PokError::Protocol(format!("peer is on {}, not {}", self.network, network)));
+        }
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(PokError::Protocol(format!(
+                "peer speaks protocol {}, at least {} is required",
+                self.version, MIN_PROTOCOL_VERSION
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// user agent advertised by this node
+pub fn user_agent() -> String {
+    format!("/proof-of-kill:{}/", env!("CARGO_PKG_VERSION"))
+}
+
+impl Message {
+    /// command naming the message in its frame
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Addr(_) => "addr",
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Tx(_) => "tx",
+            Message::GetData(_) => "getdata",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::Inv(_) => "inv",
+            Message::Block(_) => "block",
+            Message::Unlock(_) => "unlock",
+            Message::Lock => "lock",
+            Message::Session(_) => "session",
+        }
+    }
+
+    fn payload(&self) -> Result<Vec<u8>> {
+        let payload = match self {
+            Message::Addr(data) => serialize(data)?,
+            Message::Version(data) => serialize(data)?,
+            Message::Verack => Vec::new(),
+            Message::Tx(data) => serialize(data)?,
+            Message::GetData(data) => serialize(data)?,
+            Message::GetHeaders(data) => serialize(data)?,
+            Message::Headers(data) => serialize(data)?,
+            Message::Inv(data) => serialize(data)?,
+            Message::Block(data) => serialize(data)?,
+            Message::Unlock(data) => serialize(data)?,
+            Message::Lock => Vec::new(),
+            Message::Session(data) => serialize(data)?,
+        };
+        Ok(payload)
+    }
+
+    /// decodes the payload of a frame carrying command
+    pub fn decode(command: &str, payload: &[u8]) -> Result<Message> {
+        let message = match command {
+            "addr" => Message::Addr(deserialize(payload)?),
+            "version" => Message::Version(deserialize(payload)?),
+            "verack" => Message::Verack,
+            "tx" => Message::Tx(deserialize(payload)?),
+            "getdata" => Message::GetData(deserialize(payload)?),
+            "getheaders" => Message::GetHeaders(deserialize(payload)?),
+            "headers" => Message::Headers(deserialize(payload)?),
+            "inv" => Message::Inv(deserialize(payload)?),
+            "block" => Message::Block(deserialize(payload)?),
+            "unlock" => Message::Unlock(deserialize(payload)?),
+            "lock" => Message::Lock,
+            "session" => Message::Session(deserialize(payload)?),
+            _ => return Err(PokError::Protocol(format!("unknown command {}", command))),
+        };
+        Ok(message)
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut first = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result(&mut first);
+
+    let mut second = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(&first);
+    hasher.result(&mut second);
+
+    let mut sum = [0; CHECKSUM_LEN];
+    sum.copy_from_slice(&second[..CHECKSUM_LEN]);
+    sum
+}
+
+/// writes one frame of network holding payload under command
+pub fn write_frame<W: Write>(writer: &mut W, network: Network, command: &str, payload: &[u8]) -> Result<()> {
+    if command.len() > COMMAND_LEN {
+        return Err(PokError::Protocol(format!("command {} is too long", command)));
+    }
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(PokError::Protocol(format!("payload of {} bytes is too large", payload.len())));
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&network.magic());
+    let mut command_bytes = [0; COMMAND_LEN];
+    command_bytes[..command.len()].copy_from_slice(command.as_bytes());
+    frame.extend_from_slice(&command_bytes);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&checksum(payload));
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame).map_err(PokError::Network)?;
+    writer.flush().map_err(PokError::Network)
+}
+
+/// reads one frame of network, returns its command and payload
+pub fn read_frame<R: Read>(reader: &mut R, network: Network) -> Result<(String, Vec<u8>)> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).map_err(PokError::Network)?;
+
+    let (magic, rest) = header.split_at(MAGIC_LEN);
+    let (command_bytes, rest) = rest.split_at(COMMAND_LEN);
+    let (len_bytes, expected_checksum) = rest.split_at(4);
+    if magic != network.magic() {
+        return Err(PokError::Protocol(format!("frame does not start with the magic of {}", network)));
+    }
+    let command_end = command_bytes.iter().position(|b| *b == 0).unwrap_or(COMMAND_LEN);
+    if command_bytes[command_end..].iter().any(|b| *b != 0) {
+        return Err(PokError::Protocol(String::from("command is not NUL padded")));
+    }
+    let command = String::from_utf8(command_bytes[..command_end].to_vec())
+        .map_err(|_| PokError::Protocol(String::from("command is not valid UTF-8")))?;
+
+    let mut len = [0; 4];
+    len.copy_from_slice(len_bytes);
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(PokError::Protocol(format!("payload of {} bytes is too large", len)));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).map_err(PokError::Network)?;
+    if checksum(&payload) != expected_checksum {
+        return Err(PokError::Protocol(format!("checksum of {} frame does not match", command)));
+    }
+    Ok((command, payload))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, network: Network, message: &Message) -> Result<()> {
+    write_frame(writer, network, message.command(), &message.payload()?)
+}
+
+pub fn read_message<R: Read>(reader: &mut R, network: Network) -> Result<Message> {
+    let (command, payload) = read_frame(reader, network)?;
+    Message::decode(&command, &payload)
+}
+
+/// Handshake is how far a connection got through the version/verack exchange
+#[derive(Debug, Clone, Default)]
+pub struct Handshake {
+    pub version_sent: bool,
+    //version of the peer, once received and accepted
+    pub peer_version: Option<Versionmsg>,
+    pub verack_received: bool,
+    //SERVICE_* flags offered by both sides
+    pub features: u64,
+}
+
+impl Handshake {
+    /// both sides sent their version and acknowledged the other one
+    pub fn is_complete(&self) -> bool {
+        self.version_sent && self.peer_version.is_some() && self.verack_received
+    }
+}
+
+/// Connection is a long-lived link to a peer, clones share the same socket.
+///
+/// one thread reads the socket through `reader()`, any thread can `send` on it.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    addr: String,
+    network: Network,
+    stream: Arc<Mutex<TcpStream>>,
+    handshake: Arc<Mutex<Handshake>>,
+}
+
+impl Connection {
+    /// dials addr
+    pub fn connect(addr: &str, network: Network) -> Result<Connection> {
+        let stream = TcpStream::connect(addr).map_err(PokError::Network)?;
+        Ok(Connection::new(addr.to_owned(), network, stream))
+    }
+
+    /// wraps a stream accepted by a listener
+    pub fn accept(stream: TcpStream, network: Network) -> Result<Connection> {
+        let addr = stream.peer_addr().map_err(PokError::Network)?.to_string();
+        Ok(Connection::new(addr, network, stream))
+    }
+
+    fn new(addr: String, network: Network, stream: TcpStream) -> Connection {
+        Connection {
+            addr,
+            network,
+            stream: Arc::new(Mutex::new(stream)),
+            handshake: Arc::new(Mutex::new(Handshake::default())),
+        }
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// state of the handshake, shared by every clone of the connection
+    pub fn handshake(&self) -> MutexGuard<'_, Handshake> {
+        self.handshake.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// returns true if both sides offered the SERVICE_* feature
+    pub fn supports(&self, feature: u64) -> bool {
+        self.handshake().features & feature != 0
+    }
+
+    /// address the connection was dialed to or accepted from
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// returns true if both connections share the same socket
+    pub fn same(&self, other: &Connection) -> bool {
+        Arc::ptr_eq(&self.stream, &other.stream)
+    }
+
+    pub fn send(&self, message: &Message) -> Result<()> {
+        debug!("send {} to {}", message.command(), self.addr);
+        let mut stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        write_message(&mut *stream, self.network, message)
+    }
+
+    /// a handle reading the socket without blocking senders
+    pub fn reader(&self) -> Result<TcpStream> {
+        let stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        stream.try_clone().map_err(PokError::Network)
+    }
+
+    /// returns true if the other end of the socket runs on this host
+    pub fn is_local(&self) -> bool {
+        let stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match (stream.peer_addr(), stream.local_addr()) {
+            (Ok(peer), Ok(local)) => peer.ip().is_loopback() || peer.ip() == local.ip(),
+            _ => false,
+        }
+    }
+
+    /// address of the host at the other end of the socket
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        let stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        stream.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    pub fn close(&self) {
+        let stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        stream.shutdown(Shutdown::Both).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame() {
+        let inv = Message::Inv(Invmsg {
+            kind: String::from("block"),
+            items: vec![String::from("00ab"), String::from("00cd")],
+        });
+        let version = Message::Version(Versionmsg {
+            version: PROTOCOL_VERSION,
+            network: Network::Mainnet,
+            services: SERVICE_NETWORK,
+            user_agent: user_agent(),
+            addr_from: String::from("localhost:3000"),
+            best_height: 4,
+            kills: 120,
+        });
+        let mainnet = Network::Mainnet;
+        let mut data = Vec::new();
+        write_message(&mut data, mainnet, &inv).unwrap();
+        write_message(&mut data, mainnet, &version).unwrap();
+        write_message(&mut data, mainnet, &Message::Verack).unwrap();
+
+        //frames written back to back are read one at a time
+        let mut reader = Cursor::new(data.clone());
+        assert_eq!(read_message(&mut reader, mainnet).unwrap(), inv);
+        assert_eq!(read_message(&mut reader, mainnet).unwrap(), version);
+        assert_eq!(read_message(&mut reader, mainnet).unwrap(), Message::Verack);
+        assert!(matches!(read_message(&mut reader, mainnet), Err(PokError::Network(_))));
+
+        //a truncated frame is never decoded
+        let data = data[..data.len() - HEADER_LEN].to_vec();
+        let mut reader = Cursor::new(data[..data.len() - 1].to_vec());
+        read_message(&mut reader, mainnet).unwrap();
+        assert!(matches!(read_message(&mut reader, mainnet), Err(PokError::Network(_))));
+
+        let mut corrupted = data.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        let mut reader = Cursor::new(corrupted);
+        read_message(&mut reader, mainnet).unwrap();
+        assert!(matches!(read_message(&mut reader, mainnet), Err(PokError::Protocol(_))));
+
+        //frames of another network are refused
+        assert!(matches!(read_message(&mut Cursor::new(data), Network::Testnet), Err(PokError::Protocol(_))));
+
+        let mut unknown = Vec::new();
+        write_frame(&mut unknown, mainnet, "ping", &[]).unwrap();
+        assert!(matches!(read_message(&mut Cursor::new(unknown), mainnet), Err(PokError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_version_compatibility() {
+        let mut version = Versionmsg {
+            version: PROTOCOL_VERSION,
+            network: Network::Testnet,
+            services: SERVICE_NETWORK,
+            user_agent: user_agent(),
+            addr_from: String::from("localhost:3000"),
+            best_height: 0,
+            kills: 0,
+        };
+        version.check_compatible(Network::Testnet).unwrap();
+        assert!(version.check_compatible(Network::Regtest).is_err());
+
+        version.version = MIN_PROTOCOL_VERSION - 1;
+        assert!(version.check_compatible(Network::Testnet).is_err());
+        assert_eq!("regtest".parse::<Network>().unwrap(), Network::Regtest);
+    }
+}
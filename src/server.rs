@@ -1,603 +1,1152 @@
 //! server of Blockchain
 
 use super::*;
+use crate::addrbook::{is_routable, AddrBook};
+use crate::agent::Agent;
 use crate::block::*;
+use crate::blockchain::*;
+use crate::config::NodeConfig;
+use crate::download::Downloader;
+use crate::mempool::Mempool;
+use crate::miner::MinerContext;
+use crate::protocol::*;
 use crate::transaction::*;
 use crate::utxoset::*;
-use bincode::{deserialize, serialize};
-use failure::format_err;
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use crate::PokError;
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 use std::sync::*;
 use std::thread;
-use std::time::Duration;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum Message {
-    Addr(Vec<String>),
-    Version(Versionmsg),
-    Tx(Txmsg),
-    GetData(GetDatamsg),
-    GetBlock(GetBlocksmsg),
-    Inv(Invmsg),
-    Block(Blockmsg),
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Blockmsg {
-    from_ip: String,
-    block: Block,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetBlocksmsg {
-    from_ip: String,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetDatamsg {
-    from_ip: String,
-    kind: String,
-    id: String,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Invmsg {
-    from_ip: String,
-    kind: String,
-    items: Vec<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Txmsg {
-    from_ip: String,
-    transaction: Transaction,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-struct Versionmsg {
-    from_ip: String,
-    version: i32,
-    //current height of the blockchain
-    best_height: u128,
-    //total kills included by current blockchain
-    kills: u128,
-}
+use std::time::{Duration, Instant, SystemTime};
 
+#[derive(Clone)]
 pub struct Server {
     node_ip: String,
-    mining_address: String,
+    network: Network,
+    //peers dialed on start along with the address book
+    seed_peers: Vec<String>,
+    book: AddrBook,
+    //None unless the node mines, locked apart from inner so blocks are mined without holding it
+    miner: Option<Arc<Mutex<MinerContext>>>,
+    //None if the node has no agent, its keys are unlocked by Unlock requests and locked once the session runs out
+    agent: Option<Arc<Mutex<Agent>>>,
     inner: Arc<Mutex<ServerInner>>,
 }
 
 struct ServerInner {
-    known_node: HashSet<String>,
+    //open connections, keyed by the address they were dialed to or accepted from,
+    //and by the listening address a peer announced in its version
+    peers: HashMap<String, Connection>,
     utxo: UTXOSet,
-    blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    //block bodies requested from peers and blocks waiting for their parent
+    downloads: Downloader,
+    mempool: Mempool,
 }
 
-pub const CENTRAL_NODE: &str = "localhost:3333";
-const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+//SERVICE_* flags offered by this node
+const SERVICES: u64 = SERVICE_NETWORK;
+/// connections dialed on start
+pub const MAX_OUTBOUND: usize = 8;
+/// Addr messages carrying more addresses are a protocol error
+pub const MAX_ADDR_ITEMS: usize = 1000;
+//how often block requests and pooled transactions are checked for their deadline
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
 
 impl Server {
-    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
-        let mut known_node = HashSet::new();
-        known_node.insert(String::from(CENTRAL_NODE));
+    /// new() creates a server listening on the address of config,
+    /// knowing its seed peers and the address book of its data directory
+    pub fn new(config: &NodeConfig, miner: Option<MinerContext>, agent: Option<Agent>, utxo: UTXOSet) -> Result<Server> {
         Ok(Server {
-            node_ip: String::from("localhost:") + port,
-            mining_address: miner_address.to_string(),
+            node_ip: config.listen_addr.clone(),
+            network: config.network,
+            seed_peers: config.seed_peers.clone(),
+            book: AddrBook::open(&config.peers_path())?,
+            miner: miner.map(|miner| Arc::new(Mutex::new(miner))),
+            agent: agent.map(|agent| Arc::new(Mutex::new(agent))),
             inner: Arc::new(Mutex::new(ServerInner {
-                known_node: known_node,
+                peers: HashMap::new(),
                 utxo,
-                blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                downloads: Downloader::new(),
+                mempool: Mempool::new(),
             })),
         })
     }
 
     pub fn start(&self) -> Result<()> {
-        let server1 = Server {
-            node_ip: self.node_ip.clone(),
-            mining_address: self.mining_address.clone(),
-            inner: Arc::clone(&self.inner),
-        };
-        info!(
-            "Start server at {}, collect coins by address: {}",
-            &self.node_ip, &self.mining_address
-        );
+        let server1 = self.clone();
+        match self.lock_miner() {
+            Some(miner) => info!(
+                "Start server at {}, collect coins by address: {}",
+                &self.node_ip, &miner.payout_address
+            ),
+            None => info!("Start server at {}", &self.node_ip),
+        }
 
         thread::spawn(move || {
             thread::sleep(Duration::from_millis(1000));
+            //the handshake tells whether the peers have a better chain
+            server1.dial_peers();
             //if server1 has not any existing blockchain yet
             if server1.get_best_height()? == u128::MAX {
-                //server1.request_blocks()
-                //request blocks from localhost:3333
-                server1.request_blocks()
-            } else {
-                server1.send_version(CENTRAL_NODE)
+                server1.request_blocks()?;
             }
+            Ok::<(), PokError>(())
+        });
+
+        let server2 = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(HOUSEKEEPING_INTERVAL);
+            server2.housekeeping();
         });
 
-        let listener = TcpListener::bind(&self.node_ip).unwrap();
+        let listener = TcpListener::bind(&self.node_ip).map_err(PokError::Network)?;
         info!("Server listen...");
 
         for stream in listener.incoming() {
-            let stream = stream?;
-            let server1 = Server {
-                node_ip: self.node_ip.clone(),
-                mining_address: self.mining_address.clone(),
-                inner: Arc::clone(&self.inner),
-            };
-            thread::spawn(move || server1.handle_connection(stream));
+            self.accept(stream);
         }
 
         Ok(())
     }
 
+    /// serves an incoming connection, one failing before it is served is dropped
+    /// so the node keeps listening
+    fn accept(&self, stream: io::Result<TcpStream>) {
+        let conn = match stream
+            .map_err(PokError::Network)
+            .and_then(|stream| Connection::accept(stream, self.network))
+        {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                return;
+            }
+        };
+        info!("Accept connection from {}", conn.addr());
+        if let Err(e) = self.serve(conn.clone()) {
+            warn!("failed to serve {}: {}", conn.addr(), e);
+            conn.close();
+        }
+    }
+
     /* ------------------- helper functions for Server ----------------------------------*/
 
-    fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_node.remove(addr);
+    //a thread panicking while holding the lock leaves the state usable, so poisoning is ignored
+    fn lock(&self) -> MutexGuard<'_, ServerInner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 
-    fn add_nodes(&self, addr: &str) {
-        self.inner
-            .lock()
-            .unwrap()
-            .known_node
-            .insert(String::from(addr));
+    /// checks block requests, pooled transactions and the agent session for their deadline
+    fn housekeeping(&self) {
+        if let Err(e) = self.check_downloads() {
+            warn!("failed to check block downloads: {}", e);
+        }
+        self.lock().mempool.expire(Instant::now());
+        if let Some(mut agent) = self.lock_agent() {
+            if agent.lock_if_expired() {
+                info!("agent session expired, its keys are locked");
+            }
+        }
     }
 
-    fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_node.clone()
+    //the agent is never locked while inner is held
+    fn lock_agent(&self) -> Option<MutexGuard<'_, Agent>> {
+        self.agent
+            .as_ref()
+            .map(|agent| agent.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
     }
 
-    fn is_node_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_node.get(addr).is_some()
+    //the miner is locked before inner whenever both are held
+    fn lock_miner(&self) -> Option<MutexGuard<'_, MinerContext>> {
+        self.miner
+            .as_ref()
+            .map(|miner| miner.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&hashs);
+    /// adds the node listening on addr to the address book, returns true if it is new
+    fn add_node(&self, addr: &str) -> Result<bool> {
+        if addr == self.node_ip {
+            return Ok(false);
+        }
+        self.book.add(addr)
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    fn get_known_nodes(&self) -> Result<Vec<String>> {
+        self.book.addresses()
     }
 
-    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
+    /// peers that completed the handshake, once each
+    fn get_peers(&self) -> Vec<Connection> {
+        let mut peers: Vec<Connection> = Vec::new();
+        for conn in self.lock().peers.values() {
+            if conn.handshake().peer_version.is_some() && !peers.iter().any(|peer| peer.same(conn)) {
+                peers.push(conn.clone());
+            }
         }
+        peers
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
-    }
-
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    fn get_mempool_tx(&self, txid: &str) -> Option<Transaction> {
+        self.lock().mempool.get(txid).cloned()
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    /// validates tx against the UTXO set and the mempool before pooling it
+    fn add_to_mempool(&self, tx: Transaction) -> Result<()> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        inner.mempool.add(tx, &inner.utxo, Instant::now())
     }
 
     fn get_best_height(&self) -> Result<u128> {
-        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+        self.lock().utxo.blockchain.get_best_height()
     }
 
     fn get_kills(&self) -> u128 {
-        self.inner.lock().unwrap().utxo.blockchain.get_kills()
+        self.lock().utxo.blockchain.get_kills()
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
+    fn has_block(&self, block_hash: &str) -> Result<bool> {
+        self.lock().utxo.blockchain.has_block(block_hash)
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
-        self.inner
-            .lock()
-            .unwrap()
+    fn accept_header(&self, header: &BlockHeader) -> Result<String> {
+        self.lock().utxo.blockchain.accept_header(header)
+    }
+
+    fn headers_after(&self, locator: &[String], stop: &str) -> Result<Vec<BlockHeader>> {
+        self.lock()
             .utxo
             .blockchain
-            .get_block(block_hash)
+            .headers_after(locator, stop, MAX_HEADERS)
+    }
+
+    fn is_header_known(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.lock().utxo.blockchain.get_block_index(block_hash)?.is_some())
+    }
+
+    fn reset_best_header(&self) {
+        self.lock().utxo.blockchain.reset_best_header()
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
-        self.inner
-            .lock()
-            .unwrap()
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
+        self.lock()
             .utxo
             .blockchain
-            .verify_transacton(tx)
+            .get_block(block_hash)
     }
 
-    fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    fn add_block(&self, block: Block) -> Result<ChainUpdate> {
+        self.lock().utxo.blockchain.add_block(block)
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    fn is_miner(&self) -> bool {
+        self.miner.is_some()
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    /// mine_block() mines txs on top of the tip and connects the block.
+    ///
+    /// the server is locked to build the template and to connect the block, not while mining,
+    /// a block whose parent is no longer the tip by then does not change the chain.
+    fn mine_block(&self, txs: Vec<Transaction>) -> Result<(Block, ChainUpdate)> {
+        let mut miner = match self.lock_miner() {
+            Some(miner) => miner,
+            None => return Err(PokError::Config(String::from("this node does not mine"))),
+        };
+        let template = self.lock().utxo.blockchain.block_template(&mut miner, txs)?;
+        info!("mine a new block");
+        let block = template.mine(&mut miner)?;
+        drop(miner);
+
+        let update = self.add_block(block.clone())?;
+        match &update {
+            ChainUpdate::Extended => self.utxo_update(&block)?,
+            ChainUpdate::Reorganized { .. } => self.utxo_reorganize(&update)?,
+            ChainUpdate::Known | ChainUpdate::SideChain => {
+                info!("mined block {} is stale, the tip moved on", block.get_hash())
+            }
+        }
+        Ok((block, update))
+    }
+
+    /// applies a block extending the tip to the UTXO set,
+    /// the mempool keeps what the block did not include
+    fn utxo_update(&self, block: &Block) -> Result<()> {
+        let mut inner = self.lock();
+        inner.utxo.update(block)?;
+        inner.mempool.remove_block(block);
+        Ok(())
+    }
+
+    /// switches the UTXO set to another fork, the mempool is validated again
+    /// with the transactions orphaned by the disconnected blocks
+    fn utxo_reorganize(&self, update: &ChainUpdate) -> Result<()> {
+        if let ChainUpdate::Reorganized { disconnected, connected } = update {
+            let mut inner = self.lock();
+            let inner = &mut *inner;
+            inner.utxo.reorganize(disconnected, connected)?;
+            inner.mempool.readmit(update.orphaned_transactions(), &inner.utxo, Instant::now());
+        }
+        Ok(())
     }
 
     /* -----------------------------------------------------*/
 
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_ip {
-            return Ok(());
+    /// registers conn and reads messages from it on a new thread,
+    /// until the peer closes the connection or breaks the protocol
+    fn serve(&self, conn: Connection) -> Result<()> {
+        let mut reader = conn.reader()?;
+        self.lock().peers.insert(conn.addr().to_owned(), conn.clone());
+        let server1 = self.clone();
+        thread::spawn(move || {
+            loop {
+                let msg = match read_message(&mut reader, conn.network()) {
+                    Ok(msg) => msg,
+                    Err(PokError::Network(e)) => {
+                        info!("connection to {} closed: {}", conn.addr(), e);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("disconnect {}: {}", conn.addr(), e);
+                        break;
+                    }
+                };
+                match server1.handle_message(&conn, msg) {
+                    Ok(()) => {}
+                    Err(PokError::Protocol(e)) => {
+                        warn!("disconnect {}: {}", conn.addr(), e);
+                        break;
+                    }
+                    Err(e) => warn!("failed to handle message from {}: {}", conn.addr(), e),
+                }
+            }
+            conn.close();
+            {
+                let mut inner = server1.lock();
+                inner.peers.retain(|_, peer| !peer.same(&conn));
+                inner.downloads.peer_gone(conn.addr());
+            }
+            //blocks the peer was asked for go to the others
+            if let Err(e) = server1.request_missing_blocks() {
+                warn!("failed to request blocks: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// returns the open connection to addr, dialing it if there is none.
+    ///
+    /// a new connection opens with our version, messages sent right after it
+    /// are read by the peer once it accepted the version.
+    fn connect(&self, addr: &str) -> Result<Connection> {
+        if let Some(conn) = self.lock().peers.get(addr) {
+            return Ok(conn.clone());
         }
-        let mut stream = match TcpStream::connect(addr) {
-            Ok(s) => s,
-            Err(_) => {
-                self.remove_node(addr);
-                return Ok(());
+        let conn = Connection::connect(addr, self.network)?;
+        self.serve(conn.clone())?;
+        self.send_version(&conn)?;
+        Ok(conn)
+    }
+
+    /// returns the connection to the node listening on addr, counting a failure in the address book if it can not be reached
+    fn reach(&self, addr: &str) -> Option<Connection> {
+        if addr == self.node_ip {
+            return None;
+        }
+        match self.connect(addr) {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                info!("can't reach node {}: {}", addr, e);
+                if let Err(e) = self.book.mark_failed(addr) {
+                    warn!("failed to update the address book: {}", e);
+                }
+                None
             }
-        };
+        }
+    }
 
-        stream.write(data)?;
+    /// addresses worth dialing: the seed peers, then the address book
+    fn candidates(&self) -> Result<Vec<String>> {
+        let mut candidates = self.seed_peers.clone();
+        for addr in self.get_known_nodes()? {
+            if !candidates.contains(&addr) {
+                candidates.push(addr);
+            }
+        }
+        Ok(candidates)
+    }
 
-        info!("data send successfully");
-        Ok(())
+    /// dials candidates until MAX_OUTBOUND of them answered
+    fn dial_peers(&self) {
+        let candidates = match self.candidates() {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                warn!("failed to read the address book: {}", e);
+                return;
+            }
+        };
+        let mut dialed = 0;
+        for addr in candidates {
+            if dialed == MAX_OUTBOUND {
+                break;
+            }
+            if self.reach(&addr).is_some() {
+                dialed += 1;
+            }
+        }
+        info!("dialed {} peers", dialed);
     }
 
-    fn request_blocks(&self) -> Result<()> {
-        for node in self.get_known_nodes() {
-            self.request_get_blocks(&node)?
+    /// sends msg to every peer that completed the handshake, except the one it came from
+    fn broadcast(&self, msg: &Message, except: Option<&Connection>) {
+        for peer in self.get_peers() {
+            if except.is_some_and(|conn| conn.same(&peer)) {
+                continue;
+            }
+            if let Err(e) = peer.send(msg) {
+                warn!("failed to send {} to {}: {}", msg.command(), peer.addr(), e);
+            }
         }
+    }
+
+    fn request_blocks(&self) -> Result<()> {
+        let best_header = self.lock().utxo.blockchain.best_header.clone();
+        self.broadcast(&self.get_headers_message(&best_header)?, None);
         Ok(())
     }
 
-    fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
-        info!("send block data to: {} block hash: {}", addr, b.get_hash());
-        let data = Blockmsg {
-            from_ip: self.node_ip.clone(),
-            block: b.clone(),
-        };
-        let data = serialize(&(cmd_to_byte("block"), data))?;
-        self.send_data(addr, &data)
+    /// asks for the headers following the chain ending at block_hash
+    fn get_headers_message(&self, block_hash: &str) -> Result<Message> {
+        let locator = self.lock().utxo.blockchain.locator(block_hash)?;
+        info!("send get headers message, locator of {} hashes", locator.len());
+        Ok(Message::GetHeaders(GetHeadersmsg {
+            locator,
+            stop: String::new(),
+        }))
     }
 
-    fn send_addr(&self, addr: &str) -> Result<()> {
-        info!("send address info to: {}", addr);
-        let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_byte("addr"), nodes))?;
-        self.send_data(addr, &data)
+    /// request_missing_blocks() asks the peers for the bodies between tip and the best header
+    fn request_missing_blocks(&self) -> Result<()> {
+        let missing = self.lock().utxo.blockchain.missing_blocks()?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+        //no peer delivers the next body, the headers leading there can't be trusted
+        if self.lock().downloads.is_given_up(&missing[0].0) {
+            warn!("block {} can't be downloaded, forget the headers ahead of tip", missing[0].0);
+            self.reset_best_header();
+            return Ok(());
+        }
+        let peers = self.get_peers();
+        let addrs: Vec<String> = peers.iter().map(|peer| peer.addr().to_owned()).collect();
+        let requests = self.lock().downloads.schedule(&missing, &addrs, Instant::now());
+        for (addr, block_hash) in requests {
+            if let Some(peer) = peers.iter().find(|peer| peer.addr() == addr) {
+                //a failed send shows up as an expired request
+                if let Err(e) = peer.send(&Server::get_data_message("block", &block_hash)) {
+                    warn!("failed to request block {} from {}: {}", block_hash, addr, e);
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
-        info!(
-            "send inv message to: {} kind: {} data: {:?}",
-            addr, kind, items
-        );
-        let data = Invmsg {
-            from_ip: self.node_ip.clone(),
-            kind: kind.to_string(),
-            items,
-        };
-        let data = serialize(&(cmd_to_byte("inv"), data))?;
-        self.send_data(addr, &data)
+    /// expires late block requests, drops stalled peers and hands their blocks to the others
+    fn check_downloads(&self) -> Result<()> {
+        let stalled = self.lock().downloads.expire(Instant::now());
+        for addr in stalled {
+            warn!("disconnect {}: stalled block download", addr);
+            for peer in self.get_peers() {
+                if peer.addr() == addr {
+                    peer.close();
+                }
+            }
+            self.lock().downloads.peer_gone(&addr);
+        }
+        self.request_missing_blocks()
     }
 
-    //addr = localhost:3333 by default
-    fn request_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to: {}", addr);
-        let data = GetBlocksmsg {
-            from_ip: self.node_ip.clone(),
-        };
-        let data = serialize(&(cmd_to_byte("getblocks"), data))?;
-        self.send_data(addr, &data)
+    fn inv_message(kind: &str, items: Vec<String>) -> Message {
+        info!("send inv message kind: {} data: {:?}", kind, items);
+        Message::Inv(Invmsg {
+            kind: kind.to_string(),
+            items,
+        })
     }
 
-    fn request_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
-        info!(
-            "send get data message to: {} kind: {} id: {}",
-            addr, kind, id
-        );
-        let data = GetDatamsg {
-            from_ip: self.node_ip.clone(),
+    fn get_data_message(kind: &str, id: &str) -> Message {
+        info!("send get data message kind: {} id: {}", kind, id);
+        Message::GetData(GetDatamsg {
             kind: kind.to_string(),
             id: id.to_string(),
-        };
-        let data = serialize(&(cmd_to_byte("getdata"), data))?;
-        self.send_data(addr, &data)
+        })
     }
 
+    /// send_tx() hands tx over to the node listening on to_addr, which may be the local one
     pub fn send_tx(&self, to_addr: &str, tx: &Transaction) -> Result<()> {
         info!("send tx to: {} txid: {}", to_addr, &tx.id);
-        let data = Txmsg {
-            from_ip: self.node_ip.clone(),
-            transaction: tx.clone(),
-        };
-        let data = serialize(&(cmd_to_byte("tx"), data))?;
-        self.send_data(to_addr, &data)
+        self.connect(to_addr)?.send(&Message::Tx(Txmsg { transaction: tx.clone() }))
+    }
+
+    /// submit_tx() hands tx over to every seed peer and known node that can be reached,
+    /// they relay it to the rest of the network
+    pub fn submit_tx(&self, tx: &Transaction) -> Result<()> {
+        let mut sent = 0;
+        for addr in self.candidates()? {
+            match self.send_tx(&addr, tx) {
+                Ok(()) => sent += 1,
+                Err(e) => info!("can't reach node {}: {}", addr, e),
+            }
+        }
+        if sent == 0 {
+            return Err(PokError::Network(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no peer could be reached",
+            )));
+        }
+        Ok(())
     }
 
-    fn send_version(&self, to_ip: &str) -> Result<()> {
-        info!("send version info to: {}", to_ip);
-        let data = Versionmsg {
-            from_ip: self.node_ip.clone(),
+    fn version_message(&self) -> Result<Message> {
+        Ok(Message::Version(Versionmsg {
+            version: PROTOCOL_VERSION,
+            network: self.network,
+            services: SERVICES,
+            user_agent: user_agent(),
+            addr_from: self.node_ip.clone(),
             best_height: self.get_best_height()?,
-            version: VERSION,
             kills: self.get_kills(),
+        }))
+    }
+
+    fn send_version(&self, conn: &Connection) -> Result<()> {
+        //marked before sending, the answer may be read before send returns
+        conn.handshake().version_sent = true;
+        conn.send(&self.version_message()?)
+    }
+
+    /// a Protocol error returned here disconnects the peer
+    fn handle_message(&self, conn: &Connection, msg: Message) -> Result<()> {
+        //requests of the node owner skip the handshake, they only come from this host
+        if matches!(msg, Message::Unlock(_) | Message::Lock) {
+            if !conn.is_local() {
+                return Err(PokError::Protocol(format!("{} is only accepted from this host", msg.command())));
+            }
+        } else if conn.handshake().peer_version.is_none() && !matches!(msg, Message::Version(_)) {
+            return Err(PokError::Protocol(format!("{} received before version", msg.command())));
+        }
+        match msg {
+            Message::Addr(data) => self.handle_addr(conn, data),
+            Message::Block(data) => self.handle_block(conn, data),
+            Message::Inv(data) => self.handle_inv(conn, data),
+            Message::GetHeaders(data) => self.handle_get_headers(conn, data),
+            Message::Headers(data) => self.handle_headers(conn, data),
+            Message::GetData(data) => self.handle_get_data(conn, data),
+            Message::Tx(data) => self.handle_tx(conn, data),
+            Message::Version(data) => self.handle_version(conn, data),
+            Message::Verack => self.handle_verack(conn),
+            Message::Unlock(data) => self.handle_unlock(conn, data),
+            Message::Lock => self.handle_lock(conn),
+            Message::Session(_) => Err(PokError::Protocol(String::from("unexpected session"))),
+        }
+    }
+
+    /// handle_unlock() unlocks the keys of the agent for the requested time and answers with the session
+    fn handle_unlock(&self, conn: &Connection, msg: Unlockmsg) -> Result<()> {
+        info!("receive unlock msg from {}", conn.addr());
+        let unlocked = match self.lock_agent() {
+            Some(mut agent) => agent.unlock(&msg.passphrase, Duration::from_secs(msg.timeout_secs)),
+            None => Err(PokError::NotFound(String::from("agent of this node"))),
+        };
+        conn.send(&self.session_message(unlocked))
+    }
+
+    /// handle_lock() locks the keys of the agent and answers with the session
+    fn handle_lock(&self, conn: &Connection) -> Result<()> {
+        info!("receive lock msg from {}", conn.addr());
+        let locked = match self.lock_agent() {
+            Some(mut agent) => {
+                agent.lock();
+                Ok(())
+            }
+            None => Err(PokError::NotFound(String::from("agent of this node"))),
         };
-        let data = serialize( &(cmd_to_byte("version"), data) )?;
-        self.send_data(to_ip, &data)
+        conn.send(&self.session_message(locked))
+    }
+
+    /// describes the session of the agent once a request got result
+    fn session_message(&self, result: Result<()>) -> Message {
+        let unlocked_until = self.lock_agent().and_then(|agent| agent.unlocked_until());
+        Message::Session(Sessionmsg {
+            unlocked_secs: unlocked_until
+                .map(|until| until.duration_since(SystemTime::now()).unwrap_or_default().as_secs())
+                .unwrap_or(0),
+            error: result.err().map(|e| e.to_string()).unwrap_or_default(),
+        })
     }
 
-    /// handle_version defines forking strategy
-    fn handle_version(&self, msg: Versionmsg) -> Result<()> {
+    /// handle_version accepts the peer if it is compatible and defines forking strategy
+    fn handle_version(&self, conn: &Connection, msg: Versionmsg) -> Result<()> {
         info!("receive version msg: {:#?}", msg);
-        /* old version backup
-        let my_best_height = self.get_best_height()?;
-        if my_best_height < msg.best_height {
-            self.request_get_blocks(&msg.from_ip)?;
-        } else if my_best_height > msg.best_height {
-            self.send_version(&msg.from_ip)?;
+        msg.check_compatible(self.network)?;
+        let send_version = {
+            let mut handshake = conn.handshake();
+            if handshake.peer_version.is_some() {
+                return Err(PokError::Protocol(String::from("duplicate version")));
+            }
+            handshake.peer_version = Some(msg.clone());
+            handshake.features = msg.services & SERVICES;
+            !handshake.version_sent
+        };
+        info!("{} runs {} with protocol {}", conn.addr(), msg.user_agent, msg.version);
+
+        //the peer is reachable through this connection under the address it listens on,
+        //unless it announced one it can't be reached at
+        if self.may_listen_on(conn, &msg.addr_from) {
+            self.lock()
+                .peers
+                .entry(msg.addr_from.clone())
+                .or_insert_with(|| conn.clone());
+            self.add_node(&msg.addr_from)?;
+            self.book.mark_seen(&msg.addr_from)?;
+        } else {
+            warn!("{} announced address {}, it is not kept", conn.addr(), msg.addr_from);
         }
-        */
-        //always picking the most winning version
-        if self.get_kills() < msg.kills {
-            self.request_get_blocks(&msg.from_ip)?;
-        } else if self.get_kills() > msg.kills {
-            self.send_version(&msg.from_ip)?;
+        //u128::MAX stands for no blockchain at all
+        if msg.best_height != u128::MAX {
+            self.lock().downloads.update_peer_height(conn.addr(), msg.best_height);
         }
 
-        self.send_addr(&msg.from_ip)?;
+        if send_version {
+            self.send_version(conn)?;
+        }
+        conn.send(&Message::Verack)?;
 
-        if !self.is_node_known(&msg.from_ip) {
-            self.add_nodes(&msg.from_ip);
+        //always picking the most winning version,
+        //a peer with fewer kills asks us once it reads our version
+        if self.is_behind(&msg)? {
+            let best_header = self.lock().utxo.blockchain.best_header.clone();
+            conn.send(&self.get_headers_message(&best_header)?)?;
         }
+
+        info!("send address info to: {}", conn.addr());
+        let mut nodes = self.get_known_nodes()?;
+        nodes.truncate(MAX_ADDR_ITEMS);
+        conn.send(&Message::Addr(nodes))?;
         Ok(())
     }
 
-    fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
+    /// tells whether the peer on conn may listen on addr:
+    /// we dialed it there, or addr is routable or on the host the peer connects from
+    fn may_listen_on(&self, conn: &Connection, addr: &str) -> bool {
+        if addr == conn.addr() {
+            return true;
+        }
+        let resolved = match addr.to_socket_addrs() {
+            Ok(resolved) => resolved,
+            Err(_) => return false,
+        };
+        let peer_ip = conn.peer_ip();
+        resolved
+            .filter(|resolved| resolved.port() != 0)
+            .any(|resolved| is_routable(&resolved) || Some(resolved.ip()) == peer_ip)
+    }
+
+    /// returns true if the peer announced a heavier chain, height breaking ties like in fork choice
+    fn is_behind(&self, msg: &Versionmsg) -> Result<bool> {
+        //u128::MAX stands for no blockchain at all
+        if msg.best_height == u128::MAX {
+            return Ok(false);
+        }
+        Ok(match self.get_best_height()? {
+            u128::MAX => true,
+            height => (self.get_kills(), height) < (msg.kills, msg.best_height),
+        })
+    }
+
+    fn handle_verack(&self, conn: &Connection) -> Result<()> {
+        let mut handshake = conn.handshake();
+        if !handshake.version_sent || handshake.verack_received {
+            return Err(PokError::Protocol(String::from("unexpected verack")));
+        }
+        handshake.verack_received = true;
+        info!("handshake with {} complete", conn.addr());
+        Ok(())
+    }
+
+    /// handle_addr() adds the addresses to the address book and gossips the new ones
+    fn handle_addr(&self, conn: &Connection, msg: Vec<String>) -> Result<()> {
         info!("receive address msg: {:#?}", msg);
+        if msg.len() > MAX_ADDR_ITEMS {
+            return Err(PokError::Protocol(format!("addr carries {} addresses", msg.len())));
+        }
+        let mut new_nodes = Vec::new();
         for node in msg {
-            self.add_nodes(&node);
+            //nodes are held to the rule of the address a peer announces for itself
+            if !self.may_listen_on(conn, &node) {
+                debug!("drop address {} from {}", node, conn.addr());
+                continue;
+            }
+            if self.add_node(&node)? {
+                new_nodes.push(node);
+            }
         }
-        /*
-        //self.request_blocks()?;
-        for node in self.get_known_nodes() {
-            self.request_get_blocks(&node)?
+        //addresses known already are not relayed again, so gossip dies out
+        if !new_nodes.is_empty() {
+            self.broadcast(&Message::Addr(new_nodes), Some(conn));
         }
         Ok(())
-        */
-        Ok(())
     }
 
-    fn handle_block(&self, msg: Blockmsg) -> Result<()> {
+    fn handle_block(&self, conn: &Connection, msg: Blockmsg) -> Result<()> {
         info!(
             "receive block msg: {}, {}",
-            msg.from_ip,
+            conn.addr(),
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
-
-        let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.request_get_data(&msg.from_ip, "block", block_hash)?;
-            in_transit.remove(0);
-            self.replace_in_transit(in_transit);
+        let block = msg.block;
+        let block_hash = block.get_hash();
+
+        //bodies downloaded from several peers arrive out of order,
+        //a block waits in the orphan pool for the body of its parent.
+        //the request is only marked delivered once the block is pooled or connected,
+        //so that it is not scheduled again in between
+        let prev_hash = block.get_prev_hash();
+        if !prev_hash.is_empty() && !self.has_block(&prev_hash)? {
+            let parent_known = self.is_header_known(&prev_hash)?;
+            {
+                let mut inner = self.lock();
+                inner.downloads.add_orphan(block);
+                inner.downloads.received(&block_hash);
+            }
+            if !parent_known {
+                //a block of a chain we have no headers of, the headers tell what is missing
+                let best_header = self.lock().utxo.blockchain.best_header.clone();
+                conn.send(&self.get_headers_message(&best_header)?)?;
+            }
         } else {
-            self.utxo_reindex()?;
+            let connected = self.connect_blocks(conn, block);
+            self.lock().downloads.received(&block_hash);
+            connected?;
         }
+        self.request_missing_blocks()
+    }
 
+    /// adds block to the chain, then the orphans it was the missing parent of
+    fn connect_blocks(&self, conn: &Connection, block: Block) -> Result<()> {
+        let mut queue = vec![block];
+        while let Some(block) = queue.pop() {
+            let block_hash = block.get_hash();
+            let children = self.lock().downloads.take_children(&block_hash);
+            let update = match self.add_block(block.clone()) {
+                Ok(update) => update,
+                Err(e) => {
+                    warn!("reject block {} from {}: {}", block_hash, conn.addr(), e);
+                    if !children.is_empty() {
+                        warn!("drop {} blocks built on {}", children.len(), block_hash);
+                    }
+                    //the headers leading there can't be trusted anymore
+                    self.reset_best_header();
+                    continue;
+                }
+            };
+            match &update {
+                ChainUpdate::Extended => self.utxo_update(&block)?,
+                ChainUpdate::Reorganized { .. } => self.utxo_reorganize(&update)?,
+                ChainUpdate::Known | ChainUpdate::SideChain => {}
+            }
+            //a block moving our tip is announced to the other peers
+            if matches!(update, ChainUpdate::Extended | ChainUpdate::Reorganized { .. }) {
+                self.broadcast(&Server::inv_message("block", vec![block_hash.clone()]), Some(conn));
+            }
+            queue.extend(children);
+        }
         Ok(())
     }
 
-    fn handle_inv(&self, msg: Invmsg) -> Result<()> {
+    fn handle_inv(&self, conn: &Connection, msg: Invmsg) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.request_get_data(&msg.from_ip, "block", block_hash)?;
-
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
+            //announced blocks are fetched headers first, the headers tell what is missing
+            let mut unknown = false;
+            for block_hash in &msg.items {
+                if !self.is_header_known(block_hash)? {
+                    unknown = true;
                 }
             }
-            self.replace_in_transit(new_in_transit);
+            if unknown {
+                let best_header = self.lock().utxo.blockchain.best_header.clone();
+                conn.send(&self.get_headers_message(&best_header)?)?;
+            }
         } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.request_get_data(&msg.from_ip, "tx", txid)?
-                    }
-                }
-                None => self.request_get_data(&msg.from_ip, "tx", txid)?,
+            let txid = match msg.items.first() {
+                Some(txid) => txid,
+                None => return Err(PokError::Protocol(String::from("tx inv carries no item"))),
+            };
+            if self.get_mempool_tx(txid).is_none() {
+                conn.send(&Server::get_data_message("tx", txid))?
             }
         }
         Ok(())
     }
 
-    fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
-        info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
-        self.send_inv(&msg.from_ip, "block", block_hashs)?;
-        Ok(())
+    fn handle_get_headers(&self, conn: &Connection, msg: GetHeadersmsg) -> Result<()> {
+        info!("receive get headers msg from {}, locator of {} hashes", conn.addr(), msg.locator.len());
+        let headers = self.headers_after(&msg.locator, &msg.stop)?;
+        conn.send(&Message::Headers(Headersmsg { headers }))
     }
 
-    fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
+    /// handle_headers() validates the headers and the kills they add up to
+    /// before any body is downloaded
+    fn handle_headers(&self, conn: &Connection, msg: Headersmsg) -> Result<()> {
+        info!("receive {} headers from {}", msg.headers.len(), conn.addr());
+        if msg.headers.len() > MAX_HEADERS {
+            return Err(PokError::Protocol(format!("headers carries {} headers", msg.headers.len())));
+        }
+        let mut last = None;
+        for header in &msg.headers {
+            last = Some(self.accept_header(header)?);
+        }
+        if let Some(header) = msg.headers.last() {
+            let mut inner = self.lock();
+            inner.downloads.update_peer_height(conn.addr(), header.height);
+            //new headers give blocks given up on another chance
+            inner.downloads.clear_attempts();
+        }
+        //a full message means the peer has more to send
+        if let (Some(last), MAX_HEADERS) = (last, msg.headers.len()) {
+            conn.send(&self.get_headers_message(&last)?)?;
+        }
+        self.request_missing_blocks()
+    }
+
+    fn handle_get_data(&self, conn: &Connection, msg: GetDatamsg) -> Result<()> {
         info!("receive get data msg: {:#?}", msg);
         if msg.kind == "block" {
             let block = self.get_block(&msg.id)?;
-            self.send_block(&msg.from_ip, &block)?;
+            info!("send block data to: {} block hash: {}", conn.addr(), block.get_hash());
+            conn.send(&Message::Block(Blockmsg { block }))?;
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.from_ip, &tx)?;
+            match self.get_mempool_tx(&msg.id) {
+                Some(tx) => conn.send(&Message::Tx(Txmsg { transaction: tx }))?,
+                None => return Err(PokError::NotFound(format!("transaction {} in the mempool", msg.id))),
+            }
         }
         Ok(())
     }
 
-    fn handle_tx(&self, msg: Txmsg) -> Result<()> {
-        info!("receive tx msg: {} {}", msg.from_ip, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
-
-        let known_nodes = self.get_known_nodes();
-        if self.node_ip == CENTRAL_NODE {
-            //known_nodes only contains localhost:3333
-            for node in known_nodes {
-                //i am not CENTRAL_NODE && incomming tx is not from CENTRAL_NODE
-                if node != self.node_ip && node != msg.from_ip {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
-                }
-            }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
-
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
-
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
-
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
-
-                    for node in self.get_known_nodes() {
-                        if node != self.node_ip {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
-
-                    if mempool.len() == 0 {
-                        break;
-                    }
-                }
-                self.clear_mempool();
-            }
+    /// handle_tx() keeps a valid transaction in the mempool and relays it,
+    /// a miner also mines it
+    fn handle_tx(&self, conn: &Connection, msg: Txmsg) -> Result<()> {
+        info!("receive tx msg: {} {}", conn.addr(), &msg.transaction.id);
+        let tx = msg.transaction;
+        if self.get_mempool_tx(&tx.id).is_some() {
+            return Ok(());
         }
+        let txid = tx.id.clone();
+        self.add_to_mempool(tx)?;
+        //the transaction is not sent back to where it came from
+        self.broadcast(&Server::inv_message("tx", vec![txid]), Some(conn));
 
+        if self.is_miner() {
+            self.mine_mempool()?;
+        }
         Ok(())
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
-
-        let cmd = byte_to_cmd(&buffer)?;
+    /// mine_mempool() mines the pooled transactions, the best paying first,
+    /// those spending unconfirmed outputs go into the blocks after their parents
+    fn mine_mempool(&self) -> Result<()> {
+        loop {
+            let txs = self.lock().mempool.block_template(MAX_BLOCK_TX_BYTES);
+            debug!("Current mempool: {:#?}", &txs);
+            if txs.is_empty() {
+                return Ok(());
+            }
 
-        match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
-            Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?,
+            let (new_block, update) = self.mine_block(txs)?;
+            if matches!(update, ChainUpdate::Extended | ChainUpdate::Reorganized { .. }) {
+                self.broadcast(&Server::inv_message("block", vec![new_block.get_hash()]), None);
+            }
         }
-
-        Ok(())
     }
 }
 
-fn cmd_to_byte(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
-    }
-    data
-}
-
-fn byte_to_cmd(byte: &[u8]) -> Result<Message> {
-    let cmd_byte = &byte[..CMD_LEN];
-    let data = &byte[CMD_LEN..];
-
-    let mut cmd = Vec::new();
-    for b in cmd_byte {
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
-    }
-    info!("cmd: {}", String::from_utf8(cmd.clone())?);
-
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
-        Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = deserialize(data)?;
-        Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
-        let data: Invmsg = deserialize(data)?;
-        Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlocksmsg = deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
-        let data: GetDatamsg = deserialize(data)?;
-        Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
-        let data: Txmsg = deserialize(data)?;
-        Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
-        let data: Versionmsg = deserialize(data)?;
-        Ok(Message::Version(data))
-    } else {
-        Err(format_err!("Unknown command in the server"))
+/// request_session() sends an Unlock or Lock request to the node listening on addr
+/// and returns its answer, a request the node refused is an error
+pub fn request_session(addr: &str, network: Network, request: &Message) -> Result<Sessionmsg> {
+    let conn = Connection::connect(addr, network)?;
+    let mut reader = conn.reader()?;
+    conn.send(request)?;
+    let answer = read_message(&mut reader, network);
+    conn.close();
+    match answer? {
+        Message::Session(session) if session.error.is_empty() => Ok(session),
+        Message::Session(session) => Err(PokError::InvalidArgument(format!("the node refused: {}", session.error))),
+        other => Err(PokError::Protocol(format!("expected a session, got {}", other.command()))),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::blockchain::*;
     use crate::agent::*;
 
-
     #[test]
-    fn test_cmd() {
+    fn test_requests_answered_on_same_connection() {
         let build:Build = Build::new (
             "Tim".to_owned(),
             "Warrior".to_owned(),
             "Axe".to_owned(),
         );
-        let mut agent = Agent::new(build,"test").unwrap();
-        let wa1 = agent.generate_address();
-        let bc = Blockchain::init(wa1,"test").unwrap();
+        let mut config = NodeConfig::new("7878");
+        config.data_dir = std::env::temp_dir().join("pok_test_server");
+        config.keystore_log_n = 4;
+        config.seed_peers = Vec::new();
+        std::fs::remove_dir_all(&config.data_dir).ok();
+        let mut agent = Agent::new(build, "passphrase", &config).unwrap();
+        let wa1 = agent.generate_address().unwrap();
+        let mut miner = MinerContext::from_agent(&agent, &wa1);
+        let bc = Blockchain::init(&mut miner,&config).unwrap();
+        let genesis_hash = bc.tip.clone();
         let utxo_set = UTXOSet { blockchain: bc };
-        let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
+        let server = Server::new(&config, Some(miner), None, utxo_set).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dial = || {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            server.serve(Connection::accept(stream, Network::Mainnet).unwrap()).unwrap();
+            client
+        };
+        let mainnet = Network::Mainnet;
+        let version = Versionmsg {
+            version: PROTOCOL_VERSION,
+            network: mainnet,
+            services: SERVICE_NETWORK | SERVICE_COMPACT_BLOCKS,
+            user_agent: String::from("/test/"),
+            addr_from: String::from("localhost:7879"),
+            best_height: 0,
+            kills: server.get_kills(),
+        };
 
-        let vmsg = Versionmsg {
-            from_ip: server.node_ip.clone(),
-            best_height: server.get_best_height().unwrap(),
-            version: VERSION,
-            kills:server.get_kills(),
+        //peers skipping the handshake or speaking an old protocol are disconnected
+        let mut client = dial();
+        let get_headers = Message::GetHeaders(GetHeadersmsg {
+            locator: Vec::new(),
+            stop: String::new(),
+        });
+        write_message(&mut client, mainnet, &get_headers).unwrap();
+        assert!(matches!(read_message(&mut client, mainnet), Err(PokError::Network(_))));
+
+        let mut client = dial();
+        let old = Versionmsg {
+            version: MIN_PROTOCOL_VERSION - 1,
+            ..version.clone()
         };
-        let data = serialize(&(cmd_to_byte("version"), vmsg.clone())).unwrap();
-        if let Message::Version(v) = byte_to_cmd(&data).unwrap() {
-            assert_eq!(v, vmsg);
-        } else {
-            panic!("wrong!");
+        write_message(&mut client, mainnet, &Message::Version(old)).unwrap();
+        assert!(matches!(read_message(&mut client, mainnet), Err(PokError::Network(_))));
+        assert!(!server.book.contains("localhost:7879").unwrap());
+
+        //the version makes the peer known under the address it listens on
+        let mut client = dial();
+        write_message(&mut client, mainnet, &Message::Version(version.clone())).unwrap();
+        match read_message(&mut client, mainnet).unwrap() {
+            Message::Version(msg) => {
+                assert_eq!(msg.network, mainnet);
+                assert_eq!(msg.services, SERVICES);
+            }
+            other => panic!("expected a version, got {:?}", other),
+        }
+        assert_eq!(read_message(&mut client, mainnet).unwrap(), Message::Verack);
+        match read_message(&mut client, mainnet).unwrap() {
+            Message::Addr(nodes) => assert_eq!(nodes, vec![String::from("localhost:7879")]),
+            other => panic!("expected addresses, got {:?}", other),
         }
+        assert!(server.book.contains("localhost:7879").unwrap());
+        let conn = server.lock().peers.get("localhost:7879").cloned().unwrap();
+        assert!(conn.supports(SERVICE_NETWORK));
+        assert!(!conn.supports(SERVICE_COMPACT_BLOCKS));
+        write_message(&mut client, mainnet, &Message::Verack).unwrap();
+
+        //an empty locator shares nothing with the chain, headers start at genesis
+        write_message(&mut client, mainnet, &get_headers).unwrap();
+        match read_message(&mut client, mainnet).unwrap() {
+            Message::Headers(msg) => {
+                assert_eq!(msg.headers.len(), 1);
+                assert_eq!(msg.headers[0].hash().unwrap(), genesis_hash);
+            }
+            other => panic!("expected headers, got {:?}", other),
+        }
+
+        let get_data = Message::GetData(GetDatamsg {
+            kind: String::from("block"),
+            id: genesis_hash.clone(),
+        });
+        write_message(&mut client, mainnet, &get_data).unwrap();
+        match read_message(&mut client, mainnet).unwrap() {
+            Message::Block(msg) => assert_eq!(msg.block.get_hash(), genesis_hash),
+            other => panic!("expected a block, got {:?}", other),
+        }
+        assert!(conn.handshake().is_complete());
+
+        //addresses are kept, our own one is not
+        let addr = Message::Addr(vec![String::from("localhost:7880"), String::from("localhost:7878")]);
+        write_message(&mut client, mainnet, &addr).unwrap();
+        write_message(&mut client, mainnet, &get_headers).unwrap();
+        read_message(&mut client, mainnet).unwrap();
+        assert_eq!(server.get_known_nodes().unwrap(), vec!["localhost:7879", "localhost:7880"]);
+        let junk = Message::Addr(vec![
+            String::from("10.0.0.9:7883"),
+            String::from("not an address"),
+            String::from("1.2.3.4:0"),
+        ]);
+        write_message(&mut client, mainnet, &junk).unwrap();
+        write_message(&mut client, mainnet, &get_headers).unwrap();
+        read_message(&mut client, mainnet).unwrap();
+        assert_eq!(server.get_known_nodes().unwrap(), vec!["localhost:7879", "localhost:7880"]);
+
+        //a peer is only known under a routable address or one on its own host
+        for (addr_from, kept) in [("10.0.0.7:7881", false), ("1.2.3.4:7882", true)] {
+            let mut client = dial();
+            let announced = Versionmsg {
+                addr_from: String::from(addr_from),
+                ..version.clone()
+            };
+            write_message(&mut client, mainnet, &Message::Version(announced)).unwrap();
+            read_message(&mut client, mainnet).unwrap();
+            assert_eq!(read_message(&mut client, mainnet).unwrap(), Message::Verack);
+            read_message(&mut client, mainnet).unwrap();
+            assert_eq!(server.book.contains(addr_from).unwrap(), kept);
+            assert_eq!(server.lock().peers.contains_key(addr_from), kept);
+        }
+
+        //a flood of addresses disconnects the peer and none of it is kept
+        let flood: Vec<String> = (0..=MAX_ADDR_ITEMS).map(|i| format!("1.2.{}.{}:7884", i / 256, i % 256)).collect();
+        write_message(&mut client, mainnet, &Message::Addr(flood)).unwrap();
+        assert!(read_message(&mut client, mainnet).is_err());
+        assert!(!server.book.contains("1.2.0.0:7884").unwrap());
+
+        drop(client);
+        drop(server);
+        std::fs::remove_dir_all(&config.data_dir).ok();
+    }
+    #[test]
+    fn test_mine_block() {
+        let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+        let mut config = NodeConfig::new("7878");
+        config.data_dir = std::env::temp_dir().join("pok_test_server_mine");
+        config.seed_peers = Vec::new();
+        std::fs::remove_dir_all(&config.data_dir).ok();
+        let mut miner = MinerContext::new(
+            String::from("tester"),
+            build.clone(),
+            Keypair::new().address(),
+            rand::SeedableRng::seed_from_u64(7),
+        );
+        let bc = Blockchain::init(&mut miner, &config).unwrap();
+        let utxo_set = UTXOSet { blockchain: bc };
+        utxo_set.reindex().unwrap();
+        let server = Server::new(&config, Some(miner), None, utxo_set).unwrap();
+
+        let (block, update) = server.mine_block(Vec::new()).unwrap();
+        assert!(matches!(update, ChainUpdate::Extended));
+        assert_eq!(server.get_best_height().unwrap(), 1);
+        assert_eq!(server.lock().utxo.blockchain.tip, block.get_hash());
+        assert_eq!(server.lock().utxo.total_value().unwrap(), (subsidy(0) + subsidy(1)) as i64);
+
+        //a template the tip moved past while it was mined competes with the tip, it does not extend it
+        let template = {
+            let mut miner = server.lock_miner().unwrap();
+            server.lock().utxo.blockchain.block_template(&mut miner, Vec::new()).unwrap()
+        };
+        server.mine_block(Vec::new()).unwrap();
+        let stale = template.mine(&mut server.lock_miner().unwrap()).unwrap();
+        let update = server.add_block(stale).unwrap();
+        assert!(matches!(update, ChainUpdate::SideChain | ChainUpdate::Reorganized { .. }));
+        assert_eq!(server.get_best_height().unwrap(), 2);
+
+        drop(server);
+        std::fs::remove_dir_all(&config.data_dir).ok();
+    }
+
+    #[test]
+    fn test_accept_keeps_serving() {
+        let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+        let mut config = NodeConfig::new("7878");
+        config.data_dir = std::env::temp_dir().join("pok_test_server_accept");
+        config.seed_peers = Vec::new();
+        std::fs::remove_dir_all(&config.data_dir).ok();
+        let mut miner = MinerContext::new(
+            String::from("tester"),
+            build,
+            Keypair::new().address(),
+            rand::SeedableRng::seed_from_u64(8),
+        );
+        let bc = Blockchain::init(&mut miner, &config).unwrap();
+        let server = Server::new(&config, None, None, UTXOSet { blockchain: bc }).unwrap();
+
+        //a failed accept is dropped, the next connection is still served
+        server.accept(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "aborted")));
+        assert!(server.lock().peers.is_empty());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        server.accept(listener.accept().map(|(stream, _)| stream));
+        let addr = client.local_addr().unwrap().to_string();
+        assert!(server.lock().peers.contains_key(&addr));
+
+        drop(client);
+        drop(server);
+        std::fs::remove_dir_all(&config.data_dir).ok();
+    }
+
+    #[test]
+    fn test_agent_session() {
+        let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+        let mut config = NodeConfig::new("7878");
+        config.data_dir = std::env::temp_dir().join("pok_test_server_session");
+        config.keystore_log_n = 4;
+        config.seed_peers = Vec::new();
+        std::fs::remove_dir_all(&config.data_dir).ok();
+        let mut agent = Agent::new(build, "passphrase", &config).unwrap();
+        let address = agent.generate_address().unwrap();
+        agent.lock();
+        let mut miner = MinerContext::from_agent(&agent, &address);
+        let bc = Blockchain::init(&mut miner, &config).unwrap();
+        let server = Server::new(&config, None, Some(agent), UTXOSet { blockchain: bc }).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server1 = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                server1.accept(stream);
+            }
+        });
+        let unlock = |passphrase: &str, timeout_secs| {
+            let request = Message::Unlock(Unlockmsg {
+                passphrase: passphrase.to_owned(),
+                timeout_secs,
+            });
+            request_session(&addr, Network::Mainnet, &request)
+        };
+
+        //the node holds the session, requests from this host need no handshake
+        assert!(matches!(unlock("wrong", 60), Err(PokError::InvalidArgument(_))));
+        assert!(server.lock_agent().unwrap().unlocked_until().is_none());
+        let session = unlock("passphrase", 60).unwrap();
+        assert!((59..=60).contains(&session.unlocked_secs));
+        assert!(server.lock_agent().unwrap().mnemonic().is_ok());
+        let session = request_session(&addr, Network::Mainnet, &Message::Lock).unwrap();
+        assert_eq!(session.unlocked_secs, 0);
+        assert!(matches!(server.lock_agent().unwrap().mnemonic(), Err(PokError::Locked)));
+
+        //an expired session is locked by the housekeeping
+        unlock("passphrase", 0).unwrap();
+        server.housekeeping();
+        assert!(!server.lock_agent().unwrap().lock_if_expired());
+
+        drop(server);
+        std::fs::remove_dir_all(&config.data_dir).ok();
     }
 }
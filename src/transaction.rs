@@ -4,20 +4,63 @@ use super::*;
 use crate::utxoset::*;
 use crate::agent::*;
 use bincode::serialize;
-use bitcoincash_addr::Address;
 use ::crypto::digest::Digest;
 use ::crypto::ed25519;
 use ::crypto::sha2::Sha256;
-use failure::format_err;
+use crate::PokError;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use rand::RngCore;
 
 
-const SUBSIDY: i32 = 10;
+/// coins paid by the coinbase of the first blocks, on top of the fees
+pub const INITIAL_SUBSIDY: i32 = 10;
+/// blocks after which the subsidy is halved
+pub const HALVING_INTERVAL: u128 = 210_000;
+/// coins that will ever be created, the sum of the subsidy schedule
+pub const MAX_SUPPLY: i64 = scheduled_supply(u128::MAX);
+
+/// returns the subsidy of the block at height, halved every HALVING_INTERVAL blocks
+pub const fn subsidy(height: u128) -> i32 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= 31 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings as u32
+    }
+}
+
+/// returns the coins created by the subsidies of the blocks from genesis up to height
+pub const fn scheduled_supply(height: u128) -> i64 {
+    let mut supply = 0;
+    let mut era_start = 0;
+    //the subsidy reaches 0 after a few halvings, later blocks create nothing
+    while era_start <= height && subsidy(era_start) > 0 {
+        let blocks = if height - era_start >= HALVING_INTERVAL {
+            HALVING_INTERVAL
+        } else {
+            height - era_start + 1
+        };
+        supply += blocks as i64 * subsidy(era_start) as i64;
+        era_start += HALVING_INTERVAL;
+    }
+    supply
+}
+/// blocks a coinbase output waits before it can be spent,
+/// so a reorganization can't take back rewards that were already spent
+pub const COINBASE_MATURITY: u128 = 10;
+/// fee rate used when none is given, in coins per 1000 bytes
+pub const DEFAULT_FEE_RATE: i32 = 1;
+
+/// returns the fee a transaction of size bytes pays at fee_rate coins per 1000 bytes, rounded up
+pub fn fee_for_size(size: usize, fee_rate: i32) -> i32 {
+    let fee = (size as i64 * i64::from(fee_rate.max(0)) + 999) / 1000;
+    fee.min(i64::from(i32::MAX)) as i32
+}
 
 /// TXInput represents a transaction input
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXInput {
     pub txid: String,
     pub vout: i32,
@@ -26,20 +69,40 @@ pub struct TXInput {
 }
 
 /// TXOutput represents a transaction output
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutput {
     pub value: i32,
     pub pub_key_hash: Vec<u8>,
 }
 
-// TXOutputs collects TXOutput
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// TXOutputs collects the unspent TXOutput of a transaction, keyed by their index in vout
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: BTreeMap<i32, TXOutput>,
+    //height of the block holding the transaction
+    pub height: u128,
+    //outputs of a coinbase mature for COINBASE_MATURITY blocks
+    pub coinbase: bool,
+}
+
+impl TXOutputs {
+    /// returns empty outputs of tx, mined at height
+    pub fn of(tx: &Transaction, height: u128) -> TXOutputs {
+        TXOutputs {
+            outputs: BTreeMap::new(),
+            height,
+            coinbase: tx.is_coinbase(),
+        }
+    }
+
+    /// returns true if the outputs can be spent by a block at spend_height
+    pub fn is_mature(&self, spend_height: u128) -> bool {
+        !self.coinbase || spend_height >= self.height + COINBASE_MATURITY
+    }
 }
 
 /// Transaction represents a Bitcoin transaction
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
@@ -50,25 +113,57 @@ pub struct Transaction {
 impl Transaction {
     /// NewUTXOTransaction creates a new transaction
     /// to is address
-    pub fn send(keypair: &Keypair, to_address: &str, amount: i32, utxo: &UTXOSet, sender_build:Build) -> Result<Transaction> {
+    ///
+    /// the fee, paid on top of amount, is whatever the inputs hold beyond the outputs.
+    /// it is raised until it covers fee_rate coins per 1000 bytes of the signed transaction.
+    pub fn send(
+        keypair: &Keypair,
+        to_address: &str,
+        amount: i32,
+        fee_rate: i32,
+        utxo: &UTXOSet,
+        sender_build: Build,
+    ) -> Result<Transaction> {
         info!(
             "new Transaction from: {} to: {}",
             keypair.address(),
             to_address
         );
+        let mut fee = 0;
+        loop {
+            let tx = Transaction::send_with_fee(keypair, to_address, amount, fee, utxo, sender_build.clone())?;
+            let required = fee_for_size(tx.size()?, fee_rate);
+            if required <= fee {
+                return Ok(tx);
+            }
+            fee = required;
+        }
+    }
+
+    fn send_with_fee(
+        keypair: &Keypair,
+        to_address: &str,
+        amount: i32,
+        fee: i32,
+        utxo: &UTXOSet,
+        sender_build: Build,
+    ) -> Result<Transaction> {
         let mut vin = Vec::new();
 
         let mut pub_key_hash = keypair.public_key.clone();
         hash_public_key(&mut pub_key_hash);
 
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        let total = amount.checked_add(fee).ok_or_else(|| {
+            PokError::InvalidArgument(format!("{} plus a fee of {} is not an amount", amount, fee))
+        })?;
+        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, total)?;
 
-        if acc_v.0 < amount {
+        if acc_v.0 < total {
             error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
+            return Err(PokError::InsufficientFunds {
+                balance: acc_v.0,
+                amount: total,
+            });
         }
 
         for tx in acc_v.1 {
@@ -84,8 +179,8 @@ impl Transaction {
         }
 
         let mut vout = vec![TXOutput::new(amount, to_address.to_string())?];
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, keypair.address())?)
+        if acc_v.0 > total {
+            vout.push(TXOutput::new(acc_v.0 - total, keypair.address())?)
         }
 
         let mut tx = Transaction {
@@ -100,13 +195,13 @@ impl Transaction {
         Ok(tx)
     }
 
-    /// NewCoinbaseTX creates a new coinbase transaction
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    /// creates a new coinbase transaction paying value, drawing its random bytes from rng
+    pub fn new_coinbase_with_rng<R: RngCore>(to: String, mut data: String, value: i32, rng: &mut R) -> Result<Transaction> {
         info!("new coinbase Transaction to: {}", to);
+        //random bytes keep coinbase ids unique even when the same data pays the same address twice
         let mut key: [u8; 32] = [0; 32];
+        rng.fill_bytes(&mut key);
         if data.is_empty() {
-            let mut rand = rand::rngs::OsRng;
-            rand.fill_bytes(&mut key);
             data = format!("Reward to '{}'", to);
         }
         let mut pub_key = Vec::from(data.as_bytes());
@@ -122,7 +217,7 @@ impl Transaction {
                 signature: Vec::new(),
                 pub_key,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(value, to)?],
             sender_build:coinbase_build,
         };
         tx.id = tx.hash()?;
@@ -141,24 +236,34 @@ impl Transaction {
         }
 
         for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
+            match prev_txs.get(&vin.txid) {
+                Some(prev_tx) if !prev_tx.id.is_empty() => {}
+                _ => return Err(PokError::InvalidTransaction(format!("previous transaction {} is not correct", vin.txid))),
             }
         }
 
         let mut tx_copy = self.trim_copy();
 
         for in_id in 0..self.vin.len() {
-            let prev_tx = prev_txs.get(&self.vin[in_id].txid).unwrap();
+            let prev_tx = &prev_txs[&self.vin[in_id].txid];
+            let prev_out = prev_tx.output(self.vin[in_id].vout)?;
+            //malformed keys and signatures would make the ed25519 functions panic
+            if self.vin[in_id].pub_key.len() != 32 || self.vin[in_id].signature.len() != 64 {
+                return Ok(false);
+            }
+            //the key signing the input has to be the one the spent output is locked to
+            let mut pub_key_hash = self.vin[in_id].pub_key.clone();
+            hash_public_key(&mut pub_key_hash);
+            if !prev_out.is_locked_with_key(&pub_key_hash) {
+                return Ok(false);
+            }
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = prev_out.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
 
             if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
+                tx_copy.id.as_bytes(),
                 &self.vin[in_id].pub_key,
                 &self.vin[in_id].signature,
             ) {
@@ -180,19 +285,18 @@ impl Transaction {
         }
 
         for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
+            match prev_txs.get(&vin.txid) {
+                Some(prev_tx) if !prev_tx.id.is_empty() => {}
+                _ => return Err(PokError::InvalidTransaction(format!("previous transaction {} is not correct", vin.txid))),
             }
         }
 
         let mut tx_copy = self.trim_copy();
 
         for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
+            let prev_tx = &prev_txs[&tx_copy.vin[in_id].txid];
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = prev_tx.output(tx_copy.vin[in_id].vout)?.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
             let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
@@ -202,6 +306,49 @@ impl Transaction {
         Ok(())
     }
 
+    /// fee() returns what the inputs hold beyond the outputs,
+    /// the outputs spent are looked up in prev_txs.
+    ///
+    /// an error tells the outputs are worth more than the inputs or one of them is worth nothing.
+    pub fn fee(&self, prev_txs: &HashMap<String, Transaction>) -> Result<i32> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+        let mut input_value: i64 = 0;
+        for vin in &self.vin {
+            let prev_tx = match prev_txs.get(&vin.txid) {
+                Some(prev_tx) => prev_tx,
+                None => return Err(PokError::InvalidTransaction(format!("previous transaction {} is not correct", vin.txid))),
+            };
+            input_value += i64::from(prev_tx.output(vin.vout)?.value);
+        }
+        if self.vout.iter().any(|out| out.value <= 0) {
+            return Err(PokError::InvalidTransaction(format!("{} has an output without value", self.id)));
+        }
+        let output_value: i64 = self.vout.iter().map(|out| i64::from(out.value)).sum();
+        if output_value > input_value {
+            return Err(PokError::InvalidTransaction(format!(
+                "{} spends {} but its inputs hold {}",
+                self.id, output_value, input_value
+            )));
+        }
+        i32::try_from(input_value - output_value)
+            .map_err(|_| PokError::InvalidTransaction(format!("{} pays a fee out of range", self.id)))
+    }
+
+    /// size of the serialized transaction in bytes, fee rates are measured against it
+    pub fn size(&self) -> Result<usize> {
+        Ok(serialize(self)?.len())
+    }
+
+    /// returns the output at index vout, an error if the transaction has no such output
+    pub fn output(&self, vout: i32) -> Result<&TXOutput> {
+        usize::try_from(vout)
+            .ok()
+            .and_then(|index| self.vout.get(index))
+            .ok_or_else(|| PokError::InvalidTransaction(format!("output {}:{} does not exist", self.id, vout)))
+    }
+
     /// Hash returns the hash of the Transaction
     pub fn hash(&self) -> Result<String> {
         let mut copy = self.clone();
@@ -220,7 +367,7 @@ impl Transaction {
         for v in &self.vin {
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
+                vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
             })
@@ -249,7 +396,7 @@ impl TXOutput {
     }
     /// Lock signs the output
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = address_to_pub_key_hash(address)?;
         debug!("lock: {}", address);
         self.pub_key_hash = pub_key_hash;
         Ok(())
@@ -276,17 +423,36 @@ mod test {
             "Warrior".to_owned(),
             "Axe".to_owned(),
         );
-        let mut agent = Agent::new(build,"test").unwrap();
-        let addr1 = agent.generate_address();
+        let mut config = crate::config::NodeConfig::new("test");
+        config.data_dir = std::env::temp_dir().join("pok_test_transaction");
+        config.keystore_log_n = 4;
+        std::fs::remove_dir_all(&config.data_dir).ok();
+        let mut agent = Agent::new(build,"passphrase",&config).unwrap();
+        let addr1 = agent.generate_address().unwrap();
         let k1 = agent.get_keypair_by_address(&addr1).unwrap().clone();
         agent.save().unwrap();
         drop(agent);
 
         let data = String::from("test");
-        let tx = Transaction::new_coinbase(addr1, data).unwrap();
+        let tx = Transaction::new_coinbase_with_rng(addr1, data, INITIAL_SUBSIDY, &mut rand::rngs::OsRng).unwrap();
         assert!(tx.is_coinbase());
 
         let signature = ed25519::signature(tx.id.as_bytes(), &k1.secret_key);
         assert!(ed25519::verify(tx.id.as_bytes(), &k1.public_key, &signature));
     }
+
+    #[test]
+    fn test_subsidy_schedule() {
+        assert_eq!(subsidy(0), INITIAL_SUBSIDY);
+        assert_eq!(subsidy(HALVING_INTERVAL - 1), INITIAL_SUBSIDY);
+        assert_eq!(subsidy(HALVING_INTERVAL), INITIAL_SUBSIDY / 2);
+        assert_eq!(subsidy(3 * HALVING_INTERVAL), 1);
+        assert_eq!(subsidy(4 * HALVING_INTERVAL), 0);
+        assert_eq!(subsidy(u128::MAX), 0);
+
+        assert_eq!(scheduled_supply(0), i64::from(INITIAL_SUBSIDY));
+        assert_eq!(scheduled_supply(HALVING_INTERVAL), (HALVING_INTERVAL as i64) * 10 + 5);
+        assert_eq!(MAX_SUPPLY, (HALVING_INTERVAL as i64) * (10 + 5 + 2 + 1));
+        assert_eq!(scheduled_supply(4 * HALVING_INTERVAL), MAX_SUPPLY);
+    }
 }
@@ -5,8 +5,34 @@ use crate::block::*;
 use crate::blockchain::*;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
-use sled;
+use crate::PokError;
 use std::collections::HashMap;
+use std::path::PathBuf;
+
+//tree of the UTXO database holding the layout version of its entries
+const META_TREE: &str = "meta";
+/// layout of the stored TXOutputs, bumped whenever it changes
+pub const UTXO_VERSION: u32 = 2;
+
+/// Balance splits the unspent value of a key into what the next block can spend
+/// and the coinbase rewards that have not matured yet
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Balance {
+    pub spendable: i32,
+    pub immature: i32,
+}
+
+/// Supply compares the coins created by the main chain to the subsidy schedule
+#[derive(Debug, Clone, PartialEq)]
+pub struct Supply {
+    pub height: u128,
+    //coins created by the blocks of the main chain
+    pub emitted: i64,
+    //coins the subsidy schedule created up to height
+    pub scheduled: i64,
+    //value held by the UTXO set, equal to emitted while the set is in sync
+    pub unspent: i64,
+}
 
 /// UTXOSet represents UTXO set
 pub struct UTXOSet {
@@ -14,7 +40,39 @@ pub struct UTXOSet {
 }
 
 impl UTXOSet {
-    /// FindUnspentTransactions returns a list of transactions containing unspent outputs
+    /// the UTXO set lives in the data directory of its blockchain
+    fn db_path(&self) -> PathBuf {
+        self.blockchain.data_dir.join("utxo")
+    }
+
+    /// opens the UTXO database, a set written with another layout is rebuilt from the blockchain first
+    fn open_db(&self) -> Result<sled::Db> {
+        let db = open_db(self.db_path())?;
+        let meta = db.open_tree(META_TREE)?;
+        match meta.get("version")? {
+            Some(version) if deserialize::<u32>(&version)? == UTXO_VERSION => Ok(db),
+            //a new set starts with the current layout
+            None if db.is_empty() => {
+                meta.insert("version", serialize(&UTXO_VERSION)?)?;
+                Ok(db)
+            }
+            _ => {
+                info!("UTXO set was written with another layout, rebuilding it");
+                drop(meta);
+                drop(db);
+                self.reindex()?;
+                open_db(self.db_path())
+            }
+        }
+    }
+
+    /// height of the block that would spend outputs now, on top of the tip
+    fn spend_height(&self) -> Result<u128> {
+        Ok(self.blockchain.get_best_height()?.wrapping_add(1))
+    }
+
+    /// FindUnspentTransactions returns a list of transactions containing unspent outputs,
+    /// coinbase outputs that have not matured are left out
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
@@ -22,25 +80,21 @@ impl UTXOSet {
     ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
         let mut accumulated = 0;
+        let spend_height = self.spend_height()?;
 
-        let node_id =  std::env::var("NODE_ID").unwrap();
-        let utxo_path = "data_".to_owned() + &node_id + "/utxo";
-
-        let db = sled::open(&utxo_path)?;
+        let db = self.open_db()?;
         for kv in db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+            let outs: TXOutputs = deserialize(&v)?;
+            if !outs.is_mature(spend_height) {
+                continue;
+            }
+
+            for (out_idx, out) in &outs.outputs {
+                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
+                    accumulated += out.value;
+                    unspent_outputs.entry(txid.clone()).or_default().push(*out_idx);
                 }
             }
         }
@@ -49,22 +103,17 @@ impl UTXOSet {
     }
 
     /// FindUTXO finds UTXO for a public key hash
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let node_id =  std::env::var("NODE_ID").unwrap();
-        let utxo_path = "data_".to_owned() + &node_id + "/utxo";
-        
-        let db = sled::open(&utxo_path)?;
+    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
+        let db = self.open_db()?;
 
         for kv in db.iter() {
             let (_, v) = kv?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
 
-            for out in outs.outputs {
+            for out in outs.outputs.into_values() {
                 if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+                    utxos.push(out)
                 }
             }
         }
@@ -72,13 +121,72 @@ impl UTXOSet {
         Ok(utxos)
     }
 
+    /// get_balance() sums the unspent outputs of a public key hash,
+    /// telling apart the coinbase outputs still maturing
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> Result<Balance> {
+        let mut balance = Balance::default();
+        let spend_height = self.spend_height()?;
+        let db = self.open_db()?;
+
+        for kv in db.iter() {
+            let (_, v) = kv?;
+            let outs: TXOutputs = deserialize(&v)?;
+            let value: i32 = outs
+                .outputs
+                .values()
+                .filter(|out| out.is_locked_with_key(pub_key_hash))
+                .map(|out| out.value)
+                .sum();
+            if outs.is_mature(spend_height) {
+                balance.spendable += value;
+            } else {
+                balance.immature += value;
+            }
+        }
+
+        Ok(balance)
+    }
+
+    /// returns the unspent outputs of transaction txid, with the height and kind of their transaction
+    pub fn get_outputs(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        let db = self.open_db()?;
+        match db.get(txid)? {
+            Some(outs) => Ok(Some(deserialize(&outs)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// returns the value of all unspent outputs
+    pub fn total_value(&self) -> Result<i64> {
+        let mut total = 0;
+        let db = self.open_db()?;
+        for kv in db.iter() {
+            let (_, v) = kv?;
+            let outs: TXOutputs = deserialize(&v)?;
+            total += outs.outputs.values().map(|out| i64::from(out.value)).sum::<i64>();
+        }
+        Ok(total)
+    }
+
+    /// supply() sums what the main chain emitted and what the schedule allows up to its tip
+    pub fn supply(&self) -> Result<Supply> {
+        let height = self.blockchain.get_best_height()?;
+        if height == u128::MAX {
+            return Err(PokError::Chain(String::from("blockchain has no blocks")));
+        }
+        Ok(Supply {
+            height,
+            emitted: self.blockchain.emitted_supply()?,
+            scheduled: scheduled_supply(height),
+            unspent: self.total_value()?,
+        })
+    }
+
     /// CountTransactions returns the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
 
-        let node_id =  std::env::var("NODE_ID").unwrap();
-        let utxo_path = "data_".to_owned() + &node_id + "/utxo";
-        let db = sled::open(utxo_path)?;
+        let db = self.open_db()?;
         for kv in db.iter() {
             kv?;
             counter += 1;
@@ -88,10 +196,12 @@ impl UTXOSet {
 
     /// Reindex rebuilds the UTXO set
     pub fn reindex(&self) -> Result<()> {
-        let node_id =  std::env::var("NODE_ID").unwrap();
-        let utxo_path = "data_".to_owned() + &node_id + "/utxo";
-        std::fs::remove_dir_all(&utxo_path).ok();
-        let db = sled::open(&utxo_path)?;
+        //a set left in place would be opened again with its old layout
+        match std::fs::remove_dir_all(self.db_path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let db = self.open_db()?;
 
         let utxos = self.blockchain.find_utxo();
 
@@ -104,42 +214,143 @@ impl UTXOSet {
 
     /// Update updates the UTXO set with transactions from the Block
     ///
-    /// The Block is considered to be the tip of a blockchain
+    /// The Block is considered to be the tip of a blockchain,
+    /// spending an output the set does not hold is an error and leaves the set untouched
     pub fn update(&self, block: &Block) -> Result<()> {
-        let node_id =  std::env::var("NODE_ID").unwrap();
-        let utxo_path = "data_".to_owned() + &node_id + "/utxo";
-        let db = sled::open(utxo_path)?;
+        let db = self.open_db()?;
+        let mut changes = Changes::new(&db);
 
         for tx in block.get_transaction() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
+                    let missing = || PokError::NotFound(format!("output {}:{} in the UTXO set", vin.txid, vin.vout));
+                    let mut outs = changes.get(&vin.txid)?.ok_or_else(missing)?;
+                    outs.outputs.remove(&vin.vout).ok_or_else(missing)?;
 
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
+                    if outs.outputs.is_empty() {
+                        changes.remove(&vin.txid);
                     } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
+                        changes.insert(&vin.txid, outs);
                     }
                 }
             }
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            let mut new_outputs = TXOutputs::of(tx, block.get_height());
+            for (out_idx, out) in tx.vout.iter().enumerate() {
+                new_outputs.outputs.insert(out_idx as i32, out.clone());
+            }
+
+            changes.insert(&tx.id, new_outputs);
+        }
+        changes.apply()
+    }
+
+    /// Revert takes the Block off the UTXO set, undoing a previous `update`
+    ///
+    /// The Block is considered to be the tip of a blockchain
+    pub fn revert(&self, block: &Block) -> Result<()> {
+        let db = self.open_db()?;
+        let mut changes = Changes::new(&db);
+        let undo = self.blockchain.get_block_undo(&block.get_hash())?;
+
+        //walk backwards so outputs created and spent inside the block cancel out
+        for (tx, spent) in block.get_transaction().iter().zip(undo.spent.iter()).rev() {
+            changes.remove(&tx.id);
+
+            for spent_output in spent {
+                let mut outs = match changes.get(&spent_output.txid)? {
+                    Some(outs) => outs,
+                    None => self.created_outputs(block, &spent_output.txid)?,
+                };
+                outs.outputs.insert(spent_output.vout, spent_output.output.clone());
+                changes.insert(&spent_output.txid, outs);
             }
+        }
+        changes.apply()
+    }
+
+    /// returns empty outputs of the transaction txid, found in block or in the chain leading to it,
+    /// so outputs that were all spent get their height and kind back on revert
+    fn created_outputs(&self, block: &Block, txid: &str) -> Result<TXOutputs> {
+        if let Some(tx) = block.get_transaction().iter().find(|tx| tx.id == txid) {
+            return Ok(TXOutputs::of(tx, block.get_height()));
+        }
+        let (tx, height) = self.blockchain.locate_transaction(&block.get_prev_hash(), txid)?;
+        Ok(TXOutputs::of(&tx, height))
+    }
+
+    /// Reorganize switches the UTXO set to another fork,
+    /// disconnected blocks go from the old tip backwards, connected ones from the fork point forwards
+    pub fn reorganize(&self, disconnected: &[Block], connected: &[Block]) -> Result<()> {
+        for block in disconnected {
+            self.revert(block)?;
+        }
+        for block in connected {
+            self.update(block)?;
+        }
+        Ok(())
+    }
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+    /// Rollback reverts the last `count` blocks and disconnects them from the tip,
+    /// returns the disconnected blocks. the tip only moves once its block is reverted
+    pub fn rollback(&mut self, count: usize) -> Result<Vec<Block>> {
+        let mut disconnected = Vec::new();
+        for _ in 0..count {
+            let block = self.blockchain.get_block(&self.blockchain.tip)?;
+            if block.get_prev_hash().is_empty() {
+                return Err(PokError::Chain(String::from("genesis block can not be disconnected")));
+            }
+            self.revert(&block)?;
+            disconnected.push(self.blockchain.disconnect_tip()?);
+        }
+        Ok(disconnected)
+    }
+}
+
+/// Changes stages what a block adds to and removes from the UTXO set,
+/// so the database gets all of it or none
+struct Changes<'a> {
+    db: &'a sled::Db,
+    //None for transactions whose outputs leave the set
+    staged: HashMap<String, Option<TXOutputs>>,
+}
+
+impl<'a> Changes<'a> {
+    fn new(db: &'a sled::Db) -> Changes<'a> {
+        Changes {
+            db,
+            staged: HashMap::new(),
+        }
+    }
+
+    /// the outputs of txid, looking at staged changes first
+    fn get(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        if let Some(outs) = self.staged.get(txid) {
+            return Ok(outs.clone());
+        }
+        match self.db.get(txid)? {
+            Some(outs) => Ok(Some(deserialize(&outs)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, txid: &str, outs: TXOutputs) {
+        self.staged.insert(txid.to_owned(), Some(outs));
+    }
+
+    fn remove(&mut self, txid: &str) {
+        self.staged.insert(txid.to_owned(), None);
+    }
+
+    fn apply(self) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (txid, outs) in self.staged {
+            match outs {
+                Some(outs) => batch.insert(txid.as_bytes(), serialize(&outs)?),
+                None => batch.remove(txid.as_bytes()),
+            }
         }
+        self.db.apply_batch(batch)?;
         Ok(())
     }
 }
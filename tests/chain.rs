@@ -0,0 +1,667 @@
+//! drives a node through the public API of the library
+
+use proof_of_kill::agent::*;
+use proof_of_kill::block::{Block, BlockHeader};
+use proof_of_kill::blockchain::*;
+use proof_of_kill::config::NodeConfig;
+use proof_of_kill::miner::MinerContext;
+use proof_of_kill::protocol::MAX_HEADERS;
+use proof_of_kill::transaction::*;
+use proof_of_kill::utxoset::*;
+use proof_of_kill::PokError;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+fn balance(utxo_set: &UTXOSet, address: &str) -> Balance {
+    let pub_key_hash = bitcoincash_addr::Address::decode(address).unwrap().body;
+    utxo_set.get_balance(&pub_key_hash).unwrap()
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()));
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+/// a chain whose genesis reward, paid to the first address of the agent, can be spent by the next block
+fn mature_chain(name: &str) -> (NodeConfig, Agent, MinerContext, UTXOSet) {
+    let mut config = NodeConfig::new("test");
+    config.data_dir = std::env::temp_dir().join(name);
+    config.keystore_log_n = 4;
+    std::fs::remove_dir_all(&config.data_dir).ok();
+
+    let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+    let mut agent = Agent::new(build, "passphrase", &config).unwrap();
+    let address = agent.generate_address().unwrap();
+    agent.generate_address().unwrap();
+    let mut miner = MinerContext::new(
+        agent.get_id().to_owned(),
+        agent.get_build().clone(),
+        address,
+        StdRng::seed_from_u64(21),
+    );
+    let bc = Blockchain::init(&mut miner, &config).unwrap();
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.reindex().unwrap();
+    let mut filler = MinerContext::new(
+        String::from("filler"),
+        agent.get_build().clone(),
+        Keypair::new().address(),
+        StdRng::seed_from_u64(22),
+    );
+    for _ in 1..COINBASE_MATURITY {
+        let block = utxo_set.blockchain.mine_block(&mut filler, Vec::new()).unwrap();
+        utxo_set.update(&block).unwrap();
+    }
+    (config, agent, miner, utxo_set)
+}
+
+/// signs a transaction moving output vout of prev_tx, whole, to address
+fn spend(keypair: &Keypair, prev_tx: &Transaction, vout: i32, address: &str, build: &Build) -> Transaction {
+    let mut tx = Transaction {
+        id: String::new(),
+        vin: vec![TXInput {
+            txid: prev_tx.id.clone(),
+            vout,
+            signature: Vec::new(),
+            pub_key: keypair.public_key.clone(),
+        }],
+        vout: vec![TXOutput::new(prev_tx.vout[vout as usize].value, address.to_owned()).unwrap()],
+        sender_build: build.clone(),
+    };
+    tx.id = tx.hash().unwrap();
+    let mut prev_txs = HashMap::new();
+    prev_txs.insert(prev_tx.id.clone(), prev_tx.clone());
+    tx.sign(&keypair.secret_key, prev_txs).unwrap();
+    tx
+}
+
+/// builds a block paying no fees on top of parent
+fn block_on(bc: &Blockchain, miner: &mut MinerContext, parent: &str, mut transactions: Vec<Transaction>) -> Block {
+    let height = bc.get_header(parent).unwrap().height + 1;
+    let bits = bc.next_bits(parent).unwrap();
+    transactions.push(miner.coinbase(String::new(), subsidy(height)).unwrap());
+    Block::new_block(miner, transactions, parent.to_owned(), height, bits).unwrap()
+}
+
+#[test]
+fn test_double_spend_across_blocks() {
+    let (config, agent, mut miner, mut utxo_set) = mature_chain("pok_test_double_spend");
+    let addr1 = &miner.payout_address.clone();
+    let addresses = agent.get_all_addresses();
+    let addr2 = addresses.iter().find(|address| *address != addr1).unwrap();
+    let key1 = agent.get_keypair_by_address(addr1).unwrap();
+    let key2 = agent.get_keypair_by_address(addr2).unwrap();
+    let build = agent.get_build().clone();
+    let genesis = utxo_set.blockchain.iter().last().unwrap();
+    let reward = genesis.get_transaction()[0].clone();
+
+    //an output created earlier in the same block can be spent by it, not one created later
+    let tx_a = spend(key1, &reward, 0, addr2, &build);
+    let tx_b = spend(key2, &tx_a, 0, addr1, &build);
+    let backwards = block_on(&utxo_set.blockchain, &mut miner, &utxo_set.blockchain.tip, vec![tx_b.clone(), tx_a.clone()]);
+    match utxo_set.blockchain.add_block(backwards) {
+        Err(PokError::InvalidBlock(BlockError::MissingOutput(txid, 0))) => assert_eq!(txid, tx_a.id),
+        other => panic!("expected a missing output, got {:?}", other),
+    }
+    let block = block_on(&utxo_set.blockchain, &mut miner, &utxo_set.blockchain.tip, vec![tx_a, tx_b]);
+    assert!(matches!(utxo_set.blockchain.add_block(block.clone()), Ok(ChainUpdate::Extended)));
+    utxo_set.update(&block).unwrap();
+    assert_eq!(
+        balance(&utxo_set, addr1),
+        Balance { spendable: INITIAL_SUBSIDY, immature: subsidy(block.get_height()) }
+    );
+    assert_eq!(balance(&utxo_set, addr2), Balance::default());
+
+    //spending the genesis reward again in a later block is refused
+    let respend = spend(key1, &reward, 0, addr2, &build);
+    let tip = utxo_set.blockchain.tip.clone();
+    let block = block_on(&utxo_set.blockchain, &mut miner, &utxo_set.blockchain.tip, vec![respend]);
+    match utxo_set.blockchain.add_block(block) {
+        Err(PokError::InvalidBlock(BlockError::MissingOutput(txid, 0))) => assert_eq!(txid, reward.id),
+        other => panic!("expected a missing output, got {:?}", other),
+    }
+    assert_eq!(utxo_set.blockchain.tip, tip);
+
+    drop(utxo_set);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+}
+
+#[test]
+fn test_utxo_missing_output() {
+    let (config, agent, mut miner, mut utxo_set) = mature_chain("pok_test_utxo_missing");
+    let addr1 = &miner.payout_address.clone();
+    let key1 = agent.get_keypair_by_address(addr1).unwrap();
+    let genesis = utxo_set.blockchain.iter().last().unwrap();
+    let reward = genesis.get_transaction()[0].clone();
+
+    //a block spending an output the set does not hold changes nothing
+    let tx_a = spend(key1, &reward, 0, &Keypair::new().address(), agent.get_build());
+    let mut tx_b = tx_a.clone();
+    tx_b.vin[0].vout = 5;
+    tx_b.id = tx_b.hash().unwrap();
+    let block = block_on(&utxo_set.blockchain, &mut miner, &utxo_set.blockchain.tip, vec![tx_a, tx_b]);
+    match utxo_set.update(&block) {
+        Err(PokError::NotFound(what)) => assert!(what.contains(&format!("{}:5", reward.id))),
+        other => panic!("expected a missing output, got {:?}", other),
+    }
+    let total = utxo_set.total_value().unwrap();
+    assert_eq!(total, INITIAL_SUBSIDY as i64 * COINBASE_MATURITY as i64);
+    assert_eq!(balance(&utxo_set, addr1), Balance { spendable: INITIAL_SUBSIDY, immature: 0 });
+
+    //rolling back past the genesis block stops with the genesis block in place
+    let height = utxo_set.blockchain.get_best_height().unwrap();
+    assert!(matches!(utxo_set.rollback(height as usize + 1), Err(PokError::Chain(_))));
+    assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 0);
+    assert_eq!(utxo_set.total_value().unwrap(), INITIAL_SUBSIDY as i64);
+
+    drop(utxo_set);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+}
+
+#[test]
+fn test_reorganize_with_transactions() {
+    let (config, agent, mut miner, mut utxo_set) = mature_chain("pok_test_reorganize");
+    let addr1 = &miner.payout_address.clone();
+    let addresses = agent.get_all_addresses();
+    let addr2 = addresses.iter().find(|address| *address != addr1).unwrap();
+    let cold = Keypair::new().address();
+    let key1 = agent.get_keypair_by_address(addr1).unwrap();
+    let build = agent.get_build().clone();
+    let fork_point = utxo_set.blockchain.tip.clone();
+    let reward = utxo_set.blockchain.iter().last().unwrap().get_transaction()[0].clone();
+
+    //the main chain pays the genesis reward to addr2
+    let tx_main = spend(key1, &reward, 0, addr2, &build);
+    let main = block_on(&utxo_set.blockchain, &mut miner, &fork_point, vec![tx_main.clone()]);
+    assert!(matches!(utxo_set.blockchain.add_block(main.clone()), Ok(ChainUpdate::Extended)));
+    utxo_set.update(&main).unwrap();
+    assert_eq!(balance(&utxo_set, addr2).spendable, INITIAL_SUBSIDY);
+
+    //the fork pays it to addr1 then on to cold, spending outputs only the fork holds
+    let tx_fork = spend(key1, &reward, 0, addr1, &build);
+    let fork1 = block_on(&utxo_set.blockchain, &mut miner, &fork_point, vec![tx_fork.clone()]);
+    let mut fork_tip = fork1.get_hash();
+    let mut update = utxo_set.blockchain.add_block(fork1).unwrap();
+    assert!(matches!(update, ChainUpdate::SideChain));
+
+    //an output only the main chain holds is missing on the fork
+    let key2 = agent.get_keypair_by_address(addr2).unwrap();
+    let foreign = spend(key2, &tx_main, 0, &cold, &build);
+    let bad = block_on(&utxo_set.blockchain, &mut miner, &fork_tip, vec![foreign]);
+    match utxo_set.blockchain.add_block(bad) {
+        Err(PokError::InvalidBlock(BlockError::MissingOutput(txid, 0))) => assert_eq!(txid, tx_main.id),
+        other => panic!("expected a missing output, got {:?}", other),
+    }
+
+    let onward = spend(key1, &tx_fork, 0, &cold, &build);
+    let mut transactions = vec![onward];
+    for _ in 0..20 {
+        if let ChainUpdate::Reorganized { .. } = update {
+            break;
+        }
+        let block = block_on(&utxo_set.blockchain, &mut miner, &fork_tip, std::mem::take(&mut transactions));
+        fork_tip = block.get_hash();
+        update = utxo_set.blockchain.add_block(block).unwrap();
+    }
+    assert!(transactions.is_empty());
+    match &update {
+        ChainUpdate::Reorganized { disconnected, connected } => {
+            assert_eq!(disconnected.len(), 1);
+            assert_eq!(disconnected[0].get_hash(), main.get_hash());
+            utxo_set.reorganize(disconnected, connected).unwrap();
+        }
+        other => panic!("expected a reorganization, got {:?}", other),
+    }
+    assert_eq!(utxo_set.blockchain.tip, fork_tip);
+    assert_eq!(update.orphaned_transactions(), vec![tx_main]);
+
+    assert_eq!(balance(&utxo_set, addr2), Balance::default());
+    assert_eq!(balance(&utxo_set, &cold), Balance { spendable: INITIAL_SUBSIDY, immature: 0 });
+    assert_eq!(balance(&utxo_set, addr1).spendable, 0);
+    //the UTXO set matches one rebuilt from the new main chain
+    let unspent = utxo_set.total_value().unwrap();
+    utxo_set.reindex().unwrap();
+    assert_eq!(utxo_set.total_value().unwrap(), unspent);
+    assert_eq!(utxo_set.supply().unwrap().emitted, unspent);
+
+    drop(utxo_set);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+}
+
+/// block layout of databases written before headers were versioned
+#[derive(Serialize)]
+struct LegacyBlock {
+    timestamp: u128,
+    transactions: Vec<Transaction>,
+    hash: String,
+    prev_block_hash: String,
+    height: u128,
+    chance: u32,
+    kills: u32,
+    agent_id: String,
+    agent_build: Build,
+}
+
+#[test]
+fn test_migrate() {
+    let mut config = NodeConfig::new("test");
+    config.data_dir = std::env::temp_dir().join("pok_test_migrate");
+    std::fs::remove_dir_all(&config.data_dir).ok();
+
+    //a legacy chain of three blocks paying one address
+    let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+    let address = Keypair::new().address();
+    let mut miner = MinerContext::new(String::from("tester"), build.clone(), address.clone(), StdRng::seed_from_u64(31));
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+    let db = sled::open(config.chain_path()).unwrap();
+    let mut prev_block_hash = String::new();
+    for height in 0..3 {
+        let block = LegacyBlock {
+            timestamp: now - 1000 * (3 - height),
+            transactions: vec![miner.coinbase(format!("legacy {}", height), subsidy(height)).unwrap()],
+            hash: format!("legacy{}", height),
+            prev_block_hash: prev_block_hash.clone(),
+            height,
+            chance: 1,
+            kills: 1,
+            agent_id: String::from("tester"),
+            agent_build: build.clone(),
+        };
+        db.insert(&block.hash, bincode::serialize(&block).unwrap()).unwrap();
+        prev_block_hash = block.hash;
+    }
+    db.insert("LAST", prev_block_hash.as_bytes()).unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    match Blockchain::load(&config) {
+        Err(PokError::Chain(message)) => assert!(message.contains("migrate")),
+        other => panic!("expected an old block format, got {:?}", other.map(|bc| bc.tip)),
+    }
+    assert_eq!(Blockchain::migrate(&config).unwrap(), 3);
+    assert!(config.data_dir.join("chain.legacy").exists());
+    assert!(!config.data_dir.join("chain.migrating").exists());
+
+    //the blocks are mined again, linked by their new hashes and indexed
+    let bc = Blockchain::load(&config).unwrap();
+    let blocks: Vec<Block> = bc.iter().collect();
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(bc.get_best_height().unwrap(), 2);
+    for (block, parent) in blocks.iter().zip(blocks.iter().skip(1)) {
+        assert_eq!(block.get_prev_hash(), parent.get_hash());
+    }
+    assert!(blocks.iter().all(|block| !block.get_hash().starts_with("legacy")));
+    let index = bc.get_block_index(&bc.tip).unwrap().unwrap();
+    assert_eq!(index.height, 2);
+    assert_eq!(bc.get_kills(), index.cumulative_kills);
+    assert_eq!(bc.get_kills(), blocks.iter().map(|block| u128::from(block.get_kills())).sum::<u128>());
+    assert!(bc.get_block_undo(&bc.tip).is_ok());
+
+    let utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.reindex().unwrap();
+    let paid = balance(&utxo_set, &address);
+    assert_eq!(paid.spendable + paid.immature, 3 * INITIAL_SUBSIDY);
+
+    //a UTXO set written with an older layout is rebuilt from the chain when it is opened
+    let blockchain = utxo_set.blockchain;
+    let utxo_path = config.data_dir.join("utxo");
+    std::fs::remove_dir_all(&utxo_path).unwrap();
+    let db = sled::open(&utxo_path).unwrap();
+    db.insert("legacy0", vec![1u8, 2, 3]).unwrap();
+    db.flush().unwrap();
+    drop(db);
+    let utxo_set = UTXOSet { blockchain };
+    assert_eq!(balance(&utxo_set, &address), paid);
+    drop(utxo_set);
+    assert_eq!(Blockchain::migrate(&config).unwrap(), 0);
+
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+}
+
+#[test]
+fn test_send_and_rollback() {
+    let mut config = NodeConfig::new("test");
+    config.data_dir = std::env::temp_dir().join("pok_test_integration");
+    config.keystore_log_n = 4;
+    std::fs::remove_dir_all(&config.data_dir).ok();
+
+    let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+    let mut agent = Agent::new(build, "passphrase", &config).unwrap();
+    let addr1 = agent.generate_address().unwrap();
+    let addr2 = agent.generate_address().unwrap();
+    agent.save().unwrap();
+
+    let mut miner = MinerContext::new(
+        agent.get_id().to_owned(),
+        agent.get_build().clone(),
+        addr1.clone(),
+        StdRng::seed_from_u64(11),
+    );
+    let bc = Blockchain::init(&mut miner, &config).unwrap();
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.reindex().unwrap();
+    assert_eq!(balance(&utxo_set, &addr1), Balance { spendable: 0, immature: INITIAL_SUBSIDY });
+
+    //the genesis reward has to mature before it is spent
+    let keypair = agent.get_keypair_by_address(&addr1).unwrap();
+    match Transaction::send(keypair, &addr2, 4, DEFAULT_FEE_RATE, &utxo_set, agent.get_build().clone()) {
+        Err(PokError::InsufficientFunds { balance, .. }) => assert_eq!(balance, 0),
+        other => panic!("expected insufficient funds, got {:?}", other.map(|tx| tx.id)),
+    }
+    let genesis = utxo_set.blockchain.get_block(&utxo_set.blockchain.tip).unwrap();
+    let reward = &genesis.get_transaction()[0];
+    let mut early = Transaction {
+        id: String::new(),
+        vin: vec![TXInput {
+            txid: reward.id.clone(),
+            vout: 0,
+            signature: Vec::new(),
+            pub_key: keypair.public_key.clone(),
+        }],
+        vout: vec![TXOutput::new(INITIAL_SUBSIDY, addr2.clone()).unwrap()],
+        sender_build: agent.get_build().clone(),
+    };
+    early.id = early.hash().unwrap();
+    utxo_set.blockchain.sign_transacton(&mut early, &keypair.secret_key).unwrap();
+    assert!(!utxo_set.blockchain.verify_transacton(&early).unwrap());
+
+    let mut filler = MinerContext::new(
+        String::from("filler"),
+        agent.get_build().clone(),
+        Keypair::new().address(),
+        StdRng::seed_from_u64(12),
+    );
+    for _ in 1..COINBASE_MATURITY {
+        let block = utxo_set.blockchain.mine_block(&mut filler, Vec::new()).unwrap();
+        utxo_set.update(&block).unwrap();
+    }
+    assert_eq!(balance(&utxo_set, &addr1), Balance { spendable: INITIAL_SUBSIDY, immature: 0 });
+    assert!(utxo_set.blockchain.verify_transacton(&early).unwrap());
+    let height = COINBASE_MATURITY;
+
+    match Transaction::send(keypair, &addr2, INITIAL_SUBSIDY + 1, DEFAULT_FEE_RATE, &utxo_set, agent.get_build().clone()) {
+        Err(PokError::InsufficientFunds { balance, amount }) => {
+            assert_eq!(balance, INITIAL_SUBSIDY);
+            assert_eq!(amount, INITIAL_SUBSIDY + 1);
+        }
+        other => panic!("expected insufficient funds, got {:?}", other.map(|tx| tx.id)),
+    }
+    match Transaction::send(keypair, "not an address", 1, DEFAULT_FEE_RATE, &utxo_set, agent.get_build().clone()) {
+        Err(PokError::InvalidAddress(address)) => assert_eq!(address, "not an address"),
+        other => panic!("expected an invalid address, got {:?}", other.map(|tx| tx.id)),
+    }
+    let tx = Transaction::send(keypair, &addr2, 4, DEFAULT_FEE_RATE, &utxo_set, agent.get_build().clone()).unwrap();
+    let fee = utxo_set.blockchain.transaction_fee(&tx).unwrap();
+    assert_eq!(fee, fee_for_size(tx.size().unwrap(), DEFAULT_FEE_RATE));
+    assert!(fee > 0);
+
+    //a coinbase leaving the fee out is refused
+    let tip = utxo_set.blockchain.tip.clone();
+    let bits = utxo_set.blockchain.next_bits(&tip).unwrap();
+    let coinbase = miner.coinbase(String::new(), subsidy(height)).unwrap();
+    let unpaid = Block::new_block(&miner, vec![tx.clone(), coinbase], tip, height, bits).unwrap();
+    match utxo_set.blockchain.add_block(unpaid) {
+        Err(PokError::InvalidBlock(BlockError::BadCoinbaseValue(value, expected))) => {
+            assert_eq!(value, i64::from(subsidy(height)));
+            assert_eq!(expected, i64::from(subsidy(height) + fee));
+        }
+        other => panic!("expected a bad coinbase value, got {:?}", other),
+    }
+
+    let block = utxo_set.blockchain.mine_block(&mut miner, vec![tx]).unwrap();
+    assert_eq!(block.get_transaction().last().unwrap().vout[0].value, subsidy(height) + fee);
+    utxo_set.update(&block).unwrap();
+    assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), height);
+    let paid = balance(&utxo_set, &addr1);
+    assert_eq!(paid.immature, subsidy(height) + fee);
+    assert_eq!(paid.spendable + paid.immature, 2 * INITIAL_SUBSIDY - 4);
+    assert_eq!(balance(&utxo_set, &addr2), Balance { spendable: 4, immature: 0 });
+
+    let disconnected = utxo_set.rollback(1).unwrap();
+    assert_eq!(disconnected[0].get_hash(), block.get_hash());
+    assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), height - 1);
+    assert_eq!(balance(&utxo_set, &addr1), Balance { spendable: INITIAL_SUBSIDY, immature: 0 });
+    assert_eq!(balance(&utxo_set, &addr2), Balance::default());
+
+    drop(utxo_set);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+}
+
+#[test]
+fn test_headers_first_sync() {
+    let mut config = NodeConfig::new("test");
+    config.data_dir = std::env::temp_dir().join("pok_test_sync_a");
+    let mut behind = NodeConfig::new("test");
+    behind.data_dir = std::env::temp_dir().join("pok_test_sync_b");
+    std::fs::remove_dir_all(&config.data_dir).ok();
+    std::fs::remove_dir_all(&behind.data_dir).ok();
+
+    let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+    let mut miner = MinerContext::new(
+        String::from("tester"),
+        build,
+        Keypair::new().address(),
+        StdRng::seed_from_u64(5),
+    );
+    //both nodes share the genesis block, then one of them mines past a retarget
+    let mut ahead = Blockchain::init(&mut miner, &config).unwrap();
+    copy_dir(&config.data_dir, &behind.data_dir);
+    for _ in 0..12 {
+        ahead.mine_block(&mut miner, Vec::new()).unwrap();
+    }
+    let mut bc = Blockchain::load(&behind).unwrap();
+    let genesis = bc.tip.clone();
+
+    let locator = ahead.locator(&ahead.tip).unwrap();
+    assert_eq!(locator.first(), Some(&ahead.tip));
+    assert_eq!(locator.last(), Some(&genesis));
+    assert!(locator.len() < 13);
+
+    let headers = ahead.headers_after(&bc.locator(&bc.tip).unwrap(), "", MAX_HEADERS).unwrap();
+    assert_eq!(headers.len(), 12);
+    assert_eq!(headers[0].prev_block_hash, genesis);
+
+    let mut forged = headers[0].clone();
+    forged.bits += 1;
+    assert!(bc.accept_header(&forged).is_err());
+
+    for header in &headers {
+        bc.accept_header(header).unwrap();
+    }
+    assert_eq!(bc.best_header, ahead.tip);
+    assert_eq!(bc.tip, genesis);
+    let missing = bc.missing_blocks().unwrap();
+    assert_eq!(missing.len(), 12);
+
+    //the locator of a node part way through only gets the rest
+    let rest = ahead.headers_after(&[missing[4].0.clone()], "", MAX_HEADERS).unwrap();
+    assert_eq!(rest.len(), 7);
+    assert_eq!(rest[0].height, 6);
+    let stopped = ahead.headers_after(&[missing[4].0.clone()], &missing[6].0, MAX_HEADERS).unwrap();
+    assert_eq!(stopped.len(), 2);
+
+    for (block_hash, _) in &missing {
+        bc.add_block(ahead.get_block(block_hash).unwrap()).unwrap();
+    }
+    assert_eq!(bc.tip, ahead.tip);
+    assert_eq!(bc.get_kills(), ahead.get_kills());
+    assert!(bc.missing_blocks().unwrap().is_empty());
+
+    drop(ahead);
+    drop(bc);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+    std::fs::remove_dir_all(&behind.data_dir).unwrap();
+}
+
+#[test]
+fn test_repair_kills() {
+    let mut config = NodeConfig::new("test");
+    config.data_dir = std::env::temp_dir().join("pok_test_repair_a");
+    let mut behind = NodeConfig::new("test");
+    behind.data_dir = std::env::temp_dir().join("pok_test_repair_b");
+    std::fs::remove_dir_all(&config.data_dir).ok();
+    std::fs::remove_dir_all(&behind.data_dir).ok();
+
+    let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+    let mut miner = MinerContext::new(
+        String::from("tester"),
+        build,
+        Keypair::new().address(),
+        StdRng::seed_from_u64(6),
+    );
+    let mut ahead = Blockchain::init(&mut miner, &config).unwrap();
+    copy_dir(&config.data_dir, &behind.data_dir);
+    for _ in 0..8 {
+        ahead.mine_block(&mut miner, Vec::new()).unwrap();
+    }
+
+    //the node behind has all headers but only the first bodies
+    let mut bc = Blockchain::load(&behind).unwrap();
+    let headers = ahead.headers_after(&bc.locator(&bc.tip).unwrap(), "", MAX_HEADERS).unwrap();
+    for header in &headers {
+        bc.accept_header(header).unwrap();
+    }
+    let missing = bc.missing_blocks().unwrap();
+    for (block_hash, _) in &missing[..3] {
+        bc.add_block(ahead.get_block(block_hash).unwrap()).unwrap();
+    }
+    let hashes: Vec<String> = missing.iter().map(|(block_hash, _)| block_hash.clone()).collect();
+    let indexes: Vec<Option<BlockIndex>> = hashes.iter().map(|h| bc.get_block_index(h).unwrap()).collect();
+    let kills = bc.get_kills();
+
+    //a lost tip entry and a stray key in the block tree
+    bc.db.open_tree("index").unwrap().remove(bc.tip.as_str()).unwrap();
+    bc.db.insert("stray", vec![1u8, 2, 3]).unwrap();
+    assert_eq!(bc.repair_kills().unwrap(), kills);
+    for (block_hash, index) in hashes.iter().zip(&indexes) {
+        assert_eq!(&bc.get_block_index(block_hash).unwrap(), index);
+    }
+    assert_eq!(bc.missing_blocks().unwrap(), missing[3..]);
+
+    //the header-only entries still let the download finish
+    for (block_hash, _) in &missing[3..] {
+        bc.add_block(ahead.get_block(block_hash).unwrap()).unwrap();
+    }
+    assert_eq!(bc.tip, ahead.tip);
+    assert_eq!(bc.get_kills(), ahead.get_kills());
+
+    drop(ahead);
+    drop(bc);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+    std::fs::remove_dir_all(&behind.data_dir).unwrap();
+}
+
+#[test]
+fn test_inflated_chance() {
+    let mut config = NodeConfig::new("test");
+    config.data_dir = std::env::temp_dir().join("pok_test_chance_a");
+    let mut behind = NodeConfig::new("test");
+    behind.data_dir = std::env::temp_dir().join("pok_test_chance_b");
+    std::fs::remove_dir_all(&config.data_dir).ok();
+    std::fs::remove_dir_all(&behind.data_dir).ok();
+
+    let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+    let mut miner = MinerContext::new(
+        String::from("tester"),
+        build,
+        Keypair::new().address(),
+        StdRng::seed_from_u64(9),
+    );
+    let mut ahead = Blockchain::init(&mut miner, &config).unwrap();
+    copy_dir(&config.data_dir, &behind.data_dir);
+    let block = ahead.mine_block(&mut miner, Vec::new()).unwrap();
+    let mut bc = Blockchain::load(&behind).unwrap();
+
+    //kills claimed from more fights than a block carries would waive the proof of work
+    let mut inflated = block.get_header().clone();
+    inflated.chance *= 1000;
+    inflated.kills = inflated.chance;
+    match bc.accept_header(&inflated) {
+        Err(PokError::InvalidBlock(BlockError::BadChance(chance))) => assert_eq!(chance, inflated.chance),
+        other => panic!("expected a bad chance, got {:?}", other),
+    }
+    let mut inflated = block.get_header().clone();
+    inflated.kills = inflated.chance + 1;
+    assert!(matches!(
+        bc.accept_header(&inflated),
+        Err(PokError::InvalidBlock(BlockError::TooManyKills(_, _)))
+    ));
+    assert_eq!(bc.best_header, bc.tip);
+
+    assert_eq!(bc.accept_header(block.get_header()).unwrap(), block.get_hash());
+    assert!(matches!(bc.add_block(block), Ok(ChainUpdate::Extended)));
+
+    drop(ahead);
+    drop(bc);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+    std::fs::remove_dir_all(&behind.data_dir).unwrap();
+}
+
+/// searches the nonce that lets header reach its target, returns its hash
+fn mine_header(header: &mut BlockHeader) -> String {
+    loop {
+        let hash = header.hash().unwrap();
+        if header.meets_target(&hash) {
+            return hash;
+        }
+        header.nonce += 1;
+    }
+}
+
+#[test]
+fn test_header_kills_are_claims() {
+    let mut config = NodeConfig::new("test");
+    config.data_dir = std::env::temp_dir().join("pok_test_claims");
+    std::fs::remove_dir_all(&config.data_dir).ok();
+
+    let build = Build::new("Tim".to_owned(), "Warrior".to_owned(), "Axe".to_owned());
+    let mut miner = MinerContext::new(
+        String::from("tester"),
+        build,
+        Keypair::new().address(),
+        StdRng::seed_from_u64(10),
+    );
+    let mut bc = Blockchain::init(&mut miner, &config).unwrap();
+    let block = bc.mine_block(&mut miner, Vec::new()).unwrap();
+    assert_eq!(bc.best_header, block.get_hash());
+
+    //a sibling header claiming every kill it may does not outrank the verified tip
+    let mut sibling = block.get_header().clone();
+    sibling.kills = sibling.chance;
+    assert!(sibling.kills > block.get_kills());
+    let sibling_hash = mine_header(&mut sibling);
+    bc.accept_header(&sibling).unwrap();
+    let sibling_index = bc.get_block_index(&sibling_hash).unwrap().unwrap();
+    assert!(sibling_index.is_heavier_than(&bc.get_block_index(&bc.tip).unwrap().unwrap()));
+    assert_eq!(bc.best_header, bc.tip);
+    assert!(bc.missing_blocks().unwrap().is_empty());
+
+    //a longer header chain is worth downloading
+    let mut child = sibling.clone();
+    child.prev_block_hash = sibling_hash.clone();
+    child.height += 1;
+    child.kills = 0;
+    child.bits = bc.next_bits(&sibling_hash).unwrap();
+    let child_hash = mine_header(&mut child);
+    bc.accept_header(&child).unwrap();
+    assert_eq!(bc.best_header, child_hash);
+    assert_eq!(bc.missing_blocks().unwrap().len(), 2);
+
+    //dropping it falls back to the tip
+    bc.reset_best_header();
+    assert_eq!(bc.best_header, bc.tip);
+
+    drop(bc);
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+}